@@ -0,0 +1,158 @@
+#!/usr/bin/env perl
+#
+# Writes the unicode tables in src/unicode/ from the unicode character database that comes with
+# perl, run from the root of the repository
+#
+#     perl scripts/unicode-tables.pl
+#
+# The tables follow whichever version of unicode the perl running this was built with
+
+use strict;
+use warnings;
+
+use Unicode::UCD qw(prop_invlist prop_invmap prop_values prop_value_aliases);
+
+my $version = Unicode::UCD::UnicodeVersion();
+
+sub rust_char {
+    my ($code_point) = @_;
+    return sprintf("'\\u{%X}'", $code_point);
+}
+
+# The characters with a property as inclusive ranges of chars, split around the surrogates as
+# they are not chars
+sub char_ranges {
+    my ($property) = @_;
+    my @invlist = prop_invlist($property);
+    die "unknown property $property" unless @invlist;
+    push(@invlist, 0x110000) if @invlist % 2;
+
+    my @ranges;
+    while (my ($start, $end) = splice(@invlist, 0, 2)) {
+        $end -= 1;
+        if ($start < 0xD800 && $end > 0xDFFF) {
+            push(@ranges, [$start, 0xD7FF], [0xE000, $end]);
+        } elsif ($start >= 0xD800 && $start <= 0xDFFF) {
+            push(@ranges, [0xE000, $end]) if $end > 0xDFFF;
+        } elsif ($end >= 0xD800 && $end <= 0xDFFF) {
+            push(@ranges, [$start, 0xD7FF]) if $start < 0xD800;
+        } else {
+            push(@ranges, [$start, $end]);
+        }
+    }
+
+    return map { "(" . rust_char($_->[0]) . ", " . rust_char($_->[1]) . ")" } @ranges;
+}
+
+# A constant holding the ranges of a property
+sub rust_ranges {
+    my ($const_name, $property) = @_;
+    my $ranges = join('', map { "    $_,\n" } char_ranges($property));
+    return "pub const $const_name: &[(char, char)] = &[\n$ranges];\n";
+}
+
+sub write_table {
+    my ($file_name, $contents) = @_;
+    open(my $file, '>', "src/unicode/$file_name") or die "can not write src/unicode/$file_name: $!";
+    print $file "// Generated by scripts/unicode-tables.pl from unicode $version, do not edit by hand\n\n";
+    print $file $contents;
+    close($file);
+
+    # Short tables are laid out differently by rustfmt, leave that to it
+    system('rustfmt', '--edition', '2018', "src/unicode/$file_name") == 0 or die "rustfmt failed on $file_name";
+}
+
+# Simple case folding maps every character to one other, characters that fold to the same one are
+# the same letter in different cases. Each character with other cases is listed with all of them
+sub case_folding {
+    my ($ranges, $map, $format, $default) = prop_invmap('Simple_Case_Folding');
+    die "unexpected format $format for Simple_Case_Folding" unless $format eq 'a';
+
+    my %orbits;
+    for my $i (0 .. $#$ranges - 1) {
+        next if ref($map->[$i]) || $map->[$i] == $default;
+        for my $code_point ($ranges->[$i] .. $ranges->[$i + 1] - 1) {
+            my $folded = $map->[$i] + ($code_point - $ranges->[$i]);
+            next if $folded == $code_point;
+            $orbits{$folded}{$folded} = 1;
+            $orbits{$folded}{$code_point} = 1;
+        }
+    }
+
+    my %others;
+    for my $orbit (values %orbits) {
+        my @members = sort { $a <=> $b } keys %$orbit;
+        for my $code_point (@members) {
+            $others{$code_point} = [grep { $_ != $code_point } @members];
+        }
+    }
+
+    my $contents = "/// Every character with another case, sorted, along with its other cases under simple case\n";
+    $contents .= "/// folding\n";
+    $contents .= "pub const CASE_FOLDING_SIMPLE: &[(char, &[char])] = &[\n";
+    for my $code_point (sort { $a <=> $b } keys %others) {
+        my $folds = join(', ', map { rust_char($_) } @{$others{$code_point}});
+        $contents .= "    (" . rust_char($code_point) . ", &[$folds]),\n";
+    }
+    $contents .= "];\n";
+
+    write_table('case_folding.rs', $contents);
+}
+
+# The unicode versions of \d, \s and \w as defined by unicode technical standard 18, perl's own
+# definitions of them follow it
+sub perl_classes {
+    my $contents = "/// \\d, characters with the general category Decimal_Number\n";
+    $contents .= rust_ranges('PERL_DIGIT', 'XPosixDigit') . "\n";
+    $contents .= "/// \\s, characters with the property White_Space\n";
+    $contents .= rust_ranges('PERL_SPACE', 'XPosixSpace') . "\n";
+    $contents .= "/// \\w, alphabetic characters, marks, decimal numbers, connector punctuation and join controls\n";
+    $contents .= rust_ranges('PERL_WORD', 'XPosixWord');
+
+    write_table('perl.rs', $contents);
+}
+
+# Names are matched loosely, ignoring case, spaces, underscores and hyphens
+sub loose_name {
+    my ($name) = @_;
+    $name =~ s/[ _-]//g;
+    return lc($name);
+}
+
+# A table of characters for every value of a property, such as each script, and a list of them
+# by every name each value goes by
+sub property_table {
+    my ($property, $file_name, $description) = @_;
+
+    my $contents = "";
+    my @by_name;
+    for my $value (prop_values($property)) {
+        my @aliases = prop_value_aliases($property, $value);
+        # Katakana_Or_Hiragana is only used by script extensions so has no characters here
+        next unless @aliases;
+        my ($short_name, $long_name, @other_names) = @aliases;
+        my $const_name = uc($long_name);
+
+        my @names = ($long_name, grep { $_ ne $long_name } $short_name, @other_names);
+        $contents .= "\n/// " . join(', ', @names) . "\n";
+        $contents .= rust_ranges($const_name, "$property=$value");
+
+        my %loose_names = map { loose_name($_) => 1 } @aliases;
+        push(@by_name, map { [$_, $const_name] } keys %loose_names);
+    }
+
+    my $by_name = "/// $description by every name they go by, sorted by name. Names are\n";
+    $by_name .= "/// matched loosely by ignoring case, spaces, underscores and hyphens\n";
+    $by_name .= "pub const BY_NAME: &[(&str, &[(char, char)])] = &[\n";
+    for my $name (sort { $a->[0] cmp $b->[0] } @by_name) {
+        $by_name .= "    (\"$name->[0]\", $name->[1]),\n";
+    }
+    $by_name .= "];\n";
+
+    write_table($file_name, $by_name . $contents);
+}
+
+case_folding();
+perl_classes();
+property_table('General_Category', 'general_category.rs', 'The characters in each general category');
+property_table('Script', 'script.rs', 'The characters in each script');
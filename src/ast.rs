@@ -0,0 +1,480 @@
+use crate::class::CharClass;
+use crate::error::Span;
+use crate::ndfa::Look;
+
+use std::fmt;
+
+/// A parsed regex along with where in the regex it came from. Flags are already applied, so
+/// under (?i) a letter is a class of its cases and under (?m) ^ is the start of a line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ast {
+    pub kind: AstKind,
+    pub span: Span,
+}
+
+/// Each kind of thing a regex can be made of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AstKind {
+    /// Matches the empty string, such as either side of a| or the inside of ()
+    Empty,
+    Char(char),
+    Class(CharClass),
+    /// Matches any character, \n only when matches_new_line is set
+    AnyChar {
+        matches_new_line: bool,
+    },
+    Assertion(Look),
+    Group {
+        /// The group's number and name, None for a non capturing group such as (?:a)
+        capture: Option<Capture>,
+        ast: Box<Ast>,
+    },
+    Repetition {
+        repetition: Repetition,
+        /// Whether it repeats as few times as it can, such as a*?
+        lazy: bool,
+        ast: Box<Ast>,
+    },
+    /// Each in turn, never holds fewer than two
+    Concat(Vec<Ast>),
+    /// Any one of them, the first that matches being preferred. Never holds fewer than two
+    Alternation(Vec<Ast>),
+}
+
+/// A capturing group, numbered by the order its ( appears in starting from 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capture {
+    pub index: u32,
+    pub name: Option<String>,
+}
+
+/// How many times a repetition repeats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repetition {
+    /// *
+    ZeroOrMore,
+    /// +
+    OneOrMore,
+    /// ?
+    ZeroOrOne,
+    /// {min}, {min,} or {min,max}, with no max for {min,}
+    Range { min: u32, max: Option<u32> },
+}
+
+/// Something that walks an ast with `Ast::walk`, each node is visited before and after all of
+/// the nodes inside it
+pub trait Visitor {
+    fn visit_pre(&mut self, _ast: &Ast) {}
+
+    fn visit_post(&mut self, _ast: &Ast) {}
+}
+
+impl Ast {
+    pub fn new(kind: AstKind, span: Span) -> Ast {
+        Ast { kind, span }
+    }
+
+    /// The nodes directly inside this one, in the order they appear in the regex
+    pub fn children(&self) -> &[Ast] {
+        match &self.kind {
+            AstKind::Group { ast, .. } | AstKind::Repetition { ast, .. } => {
+                std::slice::from_ref(ast)
+            }
+            AstKind::Concat(asts) | AstKind::Alternation(asts) => asts,
+            _ => &[],
+        }
+    }
+
+    /// Visits this node and every node inside it, depth first in the order they appear
+    pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+        visitor.visit_pre(self);
+        for child in self.children() {
+            child.walk(visitor);
+        }
+        visitor.visit_post(self);
+    }
+}
+
+/// Prints the ast back out as a regex that parses to the same thing under the default options.
+/// Only characters that would otherwise mean something are escaped, classes are printed as their
+/// sorted ranges, flags only wrap what they apply to, such as (?m:^), and non capturing groups
+/// are only printed where something needs grouping
+///
+/// What is printed is the ast as lowered by the parser rather than the regex as written. Flags
+/// and shorthands have already been applied so (?i)a prints as [Aa], \d as [0-9] and a property
+/// such as \pL as every one of its ranges. Two regexes that parse to the same thing print the
+/// same so the output can be compared, but it is often longer than the regex it came from
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            AstKind::Empty => Ok(()),
+            AstKind::Char(c) => write_char(f, *c, "\\.+*?()|[{^$"),
+            AstKind::Class(class) => write_class(f, class),
+            AstKind::AnyChar {
+                matches_new_line: false,
+            } => f.write_str("."),
+            AstKind::AnyChar {
+                matches_new_line: true,
+            } => f.write_str("(?s:.)"),
+            AstKind::Assertion(look) => f.write_str(match look {
+                Look::StartText => "^",
+                Look::EndText => "$",
+                Look::StartLine => "(?m:^)",
+                Look::EndLine => "(?m:$)",
+                Look::WordBoundary => "\\b",
+                Look::NotWordBoundary => "\\B",
+                Look::UnicodeWordBoundary => "(?u:\\b)",
+                Look::NotUnicodeWordBoundary => "(?u:\\B)",
+            }),
+            AstKind::Group { capture, ast } => match capture {
+                Some(Capture {
+                    name: Some(name), ..
+                }) => write!(f, "(?P<{}>{})", name, ast),
+                Some(_) => write!(f, "({})", ast),
+                None => write!(f, "{}", ast),
+            },
+            AstKind::Repetition {
+                repetition,
+                lazy,
+                ast,
+            } => {
+                /* A quantifier only repeats the single thing before it */
+                let needs_group = matches!(
+                    ungrouped(ast).kind,
+                    AstKind::Empty
+                        | AstKind::Repetition { .. }
+                        | AstKind::Concat(_)
+                        | AstKind::Alternation(_)
+                );
+                write_grouped(f, ast, needs_group)?;
+
+                match repetition {
+                    Repetition::ZeroOrMore => f.write_str("*")?,
+                    Repetition::OneOrMore => f.write_str("+")?,
+                    Repetition::ZeroOrOne => f.write_str("?")?,
+                    Repetition::Range { min, max: None } => write!(f, "{{{},}}", min)?,
+                    Repetition::Range {
+                        min,
+                        max: Some(max),
+                    } if min == max => write!(f, "{{{}}}", min)?,
+                    Repetition::Range {
+                        min,
+                        max: Some(max),
+                    } => write!(f, "{{{},{}}}", min, max)?,
+                }
+                if *lazy {
+                    f.write_str("?")?;
+                }
+                Ok(())
+            }
+            AstKind::Concat(asts) => {
+                /* An empty ast would print as nothing and drop out of the concatenation */
+                for ast in asts {
+                    let needs_group = matches!(
+                        ungrouped(ast).kind,
+                        AstKind::Empty | AstKind::Alternation(_)
+                    );
+                    write_grouped(f, ast, needs_group)?;
+                }
+                Ok(())
+            }
+            AstKind::Alternation(asts) => {
+                /* Alternatives are built from the left so any on the right need keeping together */
+                for (i, ast) in asts.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("|")?;
+                    }
+                    let needs_group =
+                        i > 0 && matches!(ungrouped(ast).kind, AstKind::Alternation(_));
+                    write_grouped(f, ast, needs_group)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The ast inside any non capturing groups around it
+fn ungrouped(ast: &Ast) -> &Ast {
+    match &ast.kind {
+        AstKind::Group { capture: None, ast } => ungrouped(ast),
+        _ => ast,
+    }
+}
+
+/// Prints the ast in a non capturing group if it needs one to stay together
+fn write_grouped(f: &mut fmt::Formatter, ast: &Ast, needs_group: bool) -> fmt::Result {
+    if needs_group {
+        write!(f, "(?:{})", ast)
+    } else {
+        write!(f, "{}", ast)
+    }
+}
+
+/// Prints a character, escaping it if it is one of the special characters. Characters that
+/// can not be seen are printed as escapes too
+fn write_char(f: &mut fmt::Formatter, c: char, special: &str) -> fmt::Result {
+    match c {
+        '\n' => f.write_str("\\n"),
+        '\t' => f.write_str("\\t"),
+        '\r' => f.write_str("\\r"),
+        _ if c.is_control() || (c.is_whitespace() && c != ' ') => {
+            write!(f, "\\x{{{:X}}}", c as u32)
+        }
+        _ if special.contains(c) => write!(f, "\\{}", c),
+        _ => write!(f, "{}", c),
+    }
+}
+
+/// Prints whichever of the class and its negation has fewer ranges, never an empty one as [] and
+/// [^] do not parse
+fn write_class(f: &mut fmt::Formatter, class: &CharClass) -> fmt::Result {
+    let negated_class = class.negated();
+    let negated = class.ranges().is_empty()
+        || (!negated_class.ranges().is_empty()
+            && negated_class.ranges().len() < class.ranges().len());
+
+    let ranges = if negated {
+        f.write_str("[^")?;
+        negated_class.ranges()
+    } else {
+        f.write_str("[")?;
+        class.ranges()
+    };
+
+    for (i, &(start, end)) in ranges.iter().enumerate() {
+        /* Ranges never touch so there is never a && or ~~ to escape */
+        let special = if i == 0 { "\\[]-^" } else { "\\[]-" };
+
+        write_char(f, start, special)?;
+        if end != start {
+            f.write_str("-")?;
+            write_char(f, end, special)?;
+        }
+    }
+
+    f.write_str("]")
+}
+
+#[cfg(test)]
+mod test_super {
+
+    use super::*;
+    use crate::ndfa::{parse_ast, parse_with_groups, Options};
+
+    /// Writes down each node as it is entered and left
+    #[derive(Default)]
+    struct Trace {
+        visits: Vec<String>,
+    }
+
+    impl Visitor for Trace {
+        fn visit_pre(&mut self, ast: &Ast) {
+            let name = match &ast.kind {
+                AstKind::Char(c) => c.to_string(),
+                AstKind::Group { .. } => "group".to_string(),
+                AstKind::Repetition { .. } => "repetition".to_string(),
+                AstKind::Concat(_) => "concat".to_string(),
+                AstKind::Alternation(_) => "alternation".to_string(),
+                kind => format!("{:?}", kind),
+            };
+            self.visits.push(name);
+        }
+
+        fn visit_post(&mut self, _ast: &Ast) {
+            self.visits.push("end".to_string());
+        }
+    }
+
+    #[test]
+    fn walk_visits_depth_first() {
+        let ast = parse_ast("a(b|c)*", &Options::new()).unwrap();
+        let mut trace = Trace::default();
+        ast.walk(&mut trace);
+
+        assert_eq!(
+            trace.visits,
+            vec![
+                "concat",
+                "a",
+                "end",
+                "repetition",
+                "group",
+                "alternation",
+                "b",
+                "end",
+                "c",
+                "end",
+                "end",
+                "end",
+                "end",
+                "end"
+            ]
+        );
+    }
+
+    #[test]
+    fn visitor_counts_captures() {
+        struct Captures(Vec<Option<String>>);
+
+        impl Visitor for Captures {
+            fn visit_pre(&mut self, ast: &Ast) {
+                if let AstKind::Group {
+                    capture: Some(capture),
+                    ..
+                } = &ast.kind
+                {
+                    self.0.push(capture.name.clone());
+                }
+            }
+        }
+
+        let ast = parse_ast("(a)(?:b)(?P<c>(d))", &Options::new()).unwrap();
+        let mut captures = Captures(vec![]);
+        ast.walk(&mut captures);
+
+        assert_eq!(captures.0, vec![None, Some("c".to_string()), None]);
+    }
+
+    fn printed(regex_str: &str) -> String {
+        parse_ast(regex_str, &Options::new()).unwrap().to_string()
+    }
+
+    #[test]
+    fn print_canonical() {
+        assert_eq!(printed("a(b|c)*?d{2,2}e{1,}"), "a(b|c)*?d{2}e{1,}");
+        assert_eq!(printed("\\.\\*a\\{]}-"), "\\.\\*a\\{]}-");
+        assert_eq!(printed("(?P<x>a)(?<y>)(?:)|"), "(?P<x>a)(?P<y>)(?:)|");
+        assert_eq!(printed("\\n\\x7F\\u{2028}é"), "\\n\\x{7F}\\x{2028}é");
+        assert_eq!(printed("[c-ea-b_\\-]"), "[\\-_a-e]");
+        assert_eq!(printed("[^)][\\]^\\[]"), "[^)][\\[\\]-^]");
+        assert_eq!(
+            printed("[\\x{0}-\\x{10FFFF}][a&&b]"),
+            "[\\x{0}-\u{10FFFF}][^\\x{0}-\u{10FFFF}]"
+        );
+        assert_eq!(printed("(?i)k1"), "[Kk\u{212A}]1");
+        assert_eq!(printed("(?ms)^.$\\A(?u)\\b"), "(?m:^)(?s:.)(?m:$)^(?u:\\b)");
+        assert_eq!(printed("(?x) a | b # comment"), "a|b");
+        assert_eq!(printed("(a|b)c"), "(a|b)c");
+        assert_eq!(printed("(?:ab)*(?:c)(?:(?:d))"), "(?:ab)*cd");
+        assert_eq!(printed("(?:a|b)|(?:c|d)"), "a|b|(?:c|d)");
+    }
+
+    #[test]
+    fn print_lowered() {
+        assert_eq!(printed("(?i)hello\\w"), "[Hh][Ee][Ll][Ll][Oo][0-9A-Z_a-z]");
+        assert_eq!(printed("\\d+\\S\\W"), "[0-9]+[^\\t-\\r ][^0-9A-Z_a-z]");
+        assert_eq!(printed("(?i)[[:upper:]]"), "[A-Za-z\u{17F}\u{212A}]");
+    }
+
+    #[test]
+    fn print_adds_groups_an_ast_needs() {
+        let ast = |kind| Ast::new(kind, Span::new(0, 0));
+        let chars = || vec![ast(AstKind::Char('a')), ast(AstKind::Char('b'))];
+
+        let repeated = ast(AstKind::Repetition {
+            repetition: Repetition::OneOrMore,
+            lazy: false,
+            ast: Box::new(ast(AstKind::Concat(chars()))),
+        });
+        assert_eq!(repeated.to_string(), "(?:ab)+");
+
+        let concat = ast(AstKind::Concat(vec![
+            ast(AstKind::Alternation(chars())),
+            ast(AstKind::Empty),
+        ]));
+        assert_eq!(concat.to_string(), "(?:a|b)(?:)");
+    }
+
+    /// Pieces of regexes to put together at random, plenty of what they make does not parse
+    const PIECES: &[&str] = &[
+        "a",
+        "b",
+        "K",
+        "é",
+        "1",
+        " ",
+        "-",
+        "]",
+        "}",
+        "&",
+        ".",
+        "^",
+        "$",
+        "\\A",
+        "\\z",
+        "\\b",
+        "\\B",
+        "\\d",
+        "\\W",
+        "\\.",
+        "\\n",
+        "\\x{1F600}",
+        "\\p{Greek}",
+        "[a-c]",
+        "[^x]",
+        "[]a]",
+        "[[:alpha:]--a]",
+        "[a-z&&[^aeiou]]",
+        "[\\s\\-]",
+        "(",
+        "(",
+        ")",
+        ")",
+        "(?:",
+        "(?P<n>",
+        "(?i)",
+        "(?m)",
+        "(?s:",
+        "(?u)",
+        "(?x)",
+        "#",
+        "|",
+        "|",
+        "*",
+        "+",
+        "?",
+        "{2}",
+        "{1,3}",
+        "{0,}",
+        "{0}",
+        "??",
+        "*?",
+    ];
+
+    #[test]
+    fn print_round_trips() {
+        /* A fixed xorshift so the same regexes are tried every run */
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = |below: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % below as u64) as usize
+        };
+
+        let mut parsed = 0;
+        for _ in 0..2000 {
+            let regex_str = (0..1 + next(10))
+                .map(|_| PIECES[next(PIECES.len())])
+                .collect::<String>();
+            let ast = match parse_ast(&regex_str, &Options::new()) {
+                Ok(ast) => ast,
+                Err(_) => continue,
+            };
+            parsed += 1;
+
+            let print = ast.to_string();
+            assert_eq!(
+                parse_with_groups(&print, &Options::new()).unwrap(),
+                parse_with_groups(&regex_str, &Options::new()).unwrap(),
+                "{} printed as {}",
+                regex_str,
+                print
+            );
+            assert_eq!(printed(&print), print, "{}", regex_str);
+        }
+
+        assert!(parsed > 400, "only {} regexes parsed", parsed);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::unicode;
+
+use std::cmp::Ordering;
+
+/// Smallest and largest scalar values a class can hold
+const MIN_CHAR: char = '\u{0}';
+const MAX_CHAR: char = '\u{10FFFF}';
+
+/// Where an inclusive range is compared to a character, Equal when it holds the character, for
+/// binary searching sorted ranges
+pub(crate) fn range_cmp((start, end): (char, char), c: char) -> Ordering {
+    if end < c {
+        Ordering::Less
+    } else if start > c {
+        Ordering::Greater
+    } else {
+        Ordering::Equal
+    }
+}
+
+/// A set of characters kept as sorted inclusive ranges that never overlap or touch, so two
+/// classes holding the same characters always compare equal
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    /// Creates a class that matches nothing
+    pub fn new() -> CharClass {
+        CharClass { ranges: vec![] }
+    }
+
+    /// Creates a class from inclusive ranges that may overlap and be in any order
+    pub fn from_ranges(ranges: &[(char, char)]) -> CharClass {
+        let mut class = CharClass {
+            ranges: ranges.to_vec(),
+        };
+        class.canonicalize();
+        class
+    }
+
+    /// Adds every character in the other class
+    pub fn union(&mut self, other: &CharClass) {
+        self.ranges.extend_from_slice(&other.ranges);
+        self.canonicalize();
+    }
+
+    /// Keeps only the characters also in the other class
+    pub fn intersect(&mut self, other: &CharClass) {
+        let mut ranges = vec![];
+        let (mut i, mut j) = (0, 0);
+
+        /* Both are sorted so each range only needs comparing with those it could overlap */
+        while let (Some(&(start, end)), Some(&(other_start, other_end))) =
+            (self.ranges.get(i), other.ranges.get(j))
+        {
+            if start.max(other_start) <= end.min(other_end) {
+                ranges.push((start.max(other_start), end.min(other_end)));
+            }
+            if end < other_end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        self.ranges = ranges;
+    }
+
+    /// Removes every character in the other class
+    pub fn difference(&mut self, other: &CharClass) {
+        self.intersect(&other.negated());
+    }
+
+    /// Keeps the characters in exactly one of the two classes
+    pub fn symmetric_difference(&mut self, other: &CharClass) {
+        let mut both = self.clone();
+        both.intersect(other);
+
+        self.union(other);
+        self.difference(&both);
+    }
+
+    /// Adds the inclusive range start..=end, a range given backwards is swapped
+    pub fn push(&mut self, start: char, end: char) {
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        self.ranges.push((start, end));
+        self.canonicalize();
+    }
+
+    /// Returns the class of every character not in this class
+    pub fn negated(&self) -> CharClass {
+        let mut ranges = vec![];
+        let mut next_start = Some(MIN_CHAR);
+
+        for &(start, end) in &self.ranges {
+            if let Some(gap_start) = next_start {
+                if gap_start < start {
+                    ranges.push((gap_start, decrement(start)));
+                }
+            }
+            next_start = increment(end);
+        }
+        if let Some(gap_start) = next_start {
+            ranges.push((gap_start, MAX_CHAR));
+        }
+
+        CharClass { ranges }
+    }
+
+    /// Returns the class with every other case of each character in it added, cases are found
+    /// with unicode simple case folding so one character never becomes several
+    pub fn case_folded(&self) -> CharClass {
+        let mut class = self.clone();
+
+        for &(start, end) in &self.ranges {
+            for &(_, folds) in unicode::simple_case_folds(start, end) {
+                class.ranges.extend(folds.iter().map(|&c| (c, c)));
+            }
+        }
+        class.canonicalize();
+
+        class
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges
+            .binary_search_by(|&range| range_cmp(range, c))
+            .is_ok()
+    }
+
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    /// Sorts then merges any ranges that overlap or sit next to each other
+    fn canonicalize(&mut self) {
+        self.ranges.sort_unstable();
+
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if increment(last.1).is_none_or(|n| n >= start) => {
+                    if end > last.1 {
+                        last.1 = end;
+                    }
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+/// The next scalar value skipping the surrogate gap, None past the last one
+fn increment(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        MAX_CHAR => None,
+        _ => std::char::from_u32(c as u32 + 1),
+    }
+}
+
+/// The previous scalar value skipping the surrogate gap, only called with c above MIN_CHAR
+fn decrement(c: char) -> char {
+    match c {
+        '\u{E000}' => '\u{D7FF}',
+        _ => std::char::from_u32(c as u32 - 1).expect("decremented into the surrogate gap"),
+    }
+}
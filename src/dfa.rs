@@ -1,457 +1,546 @@
-use crate::ndfa::StateType::Branching;
-use crate::ndfa::StateType::Literal;
-
-use crate::ndfa::State as NDFAState;
+use crate::class::range_cmp;
+use crate::error::Error;
 use crate::ndfa::*;
+use crate::nfa::State as NFAState;
+use crate::nfa::{Nfa, StateId};
+use crate::unicode;
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// Every kind of character that can sit either side of a position
+const CHAR_KINDS: [CharKind; 5] = [
+    CharKind::Edge,
+    CharKind::NewLine,
+    CharKind::AsciiWord,
+    CharKind::UnicodeWord,
+    CharKind::Other,
+];
+
+/// Which kinds of character the assertions in the nfa can tell apart. Kinds none of them can
+/// are treated as the same kind, which keeps states from being split for nothing and
+/// transitions from being split around every word character when there is no \b
+#[derive(Clone, Copy, Debug, Default)]
+struct KindsSeen {
+    any: bool,
+    new_line: bool,
+    ascii_word: bool,
+    unicode_word: bool,
+}
 
-use std::collections::{BTreeMap, HashMap};
+impl KindsSeen {
+    fn new(nfa: &Nfa) -> KindsSeen {
+        let mut seen = KindsSeen::default();
+
+        for state in nfa.states() {
+            if let NFAState::Assertion(look, _) = *state {
+                seen.any = true;
+                match look {
+                    Look::StartLine | Look::EndLine => seen.new_line = true,
+                    Look::WordBoundary | Look::NotWordBoundary => seen.ascii_word = true,
+                    Look::UnicodeWordBoundary | Look::NotUnicodeWordBoundary => {
+                        seen.unicode_word = true
+                    }
+                    Look::StartText | Look::EndText => {}
+                }
+            }
+        }
 
-use std::iter::FromIterator;
+        seen
+    }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-struct IntermediateTransition {
-    matched: char,
-    id: Vec<u32>,
+    /// The kind the assertions see a kind of character as
+    fn merged(self, kind: CharKind) -> CharKind {
+        match kind {
+            _ if !self.any => CharKind::Other,
+            CharKind::NewLine if !self.new_line => CharKind::Other,
+            CharKind::AsciiWord if !self.ascii_word && !self.unicode_word => CharKind::Other,
+            CharKind::UnicodeWord if !self.unicode_word => CharKind::Other,
+            kind => kind,
+        }
+    }
+
+    /// Where one kind of character the assertions can tell apart ends and another starts
+    fn boundaries(self) -> Vec<u32> {
+        let mut boundaries = vec![];
+
+        if self.new_line {
+            boundaries.extend(&['\n' as u32, '\n' as u32 + 1]);
+        }
+        if self.ascii_word || self.unicode_word {
+            for &(start, end) in &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')] {
+                boundaries.extend(&[start as u32, end as u32 + 1]);
+            }
+            boundaries.push(0x80);
+        }
+        if self.unicode_word {
+            for &(start, end) in unicode::PERL_WORD {
+                boundaries.extend(&[start as u32, end as u32 + 1]);
+            }
+        }
+
+        boundaries
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-struct IntermediateState {
-    id: u32,
-    ndfsa_ids: Vec<u32>,
-    looping_chars: Vec<char>, /* Chars that simply return to the current state */
-    tran: Vec<IntermediateTransition>,
+/// A set of nfa states that is checked, added to and emptied in constant time. A nfa with many
+/// states that consume nothing is followed through thousands of them for every dfa state, so a
+/// linear search of those already seen would make building the dfa cubic
+#[derive(Debug)]
+struct SparseSet {
+    /// The states in the order they were added
+    dense: Vec<StateId>,
+    /// Where each state is in dense, only meaningful for states that are in the set
+    sparse: Vec<usize>,
 }
 
-impl IntermediateState {
-    fn new(
-        next_state_id: &mut u32,
-        ndfsa_ids: Vec<u32>,
-        looping_chars: Vec<char>,
-        tran: Vec<IntermediateTransition>,
-    ) -> IntermediateState {
-        let nstate = IntermediateState {
-            id: *next_state_id,
-            ndfsa_ids,
-            looping_chars,
-            tran,
-        };
-        *next_state_id += 1;
-        nstate
+impl SparseSet {
+    fn new(nfa: &Nfa) -> SparseSet {
+        SparseSet {
+            dense: Vec::with_capacity(nfa.states().len()),
+            sparse: vec![0; nfa.states().len()],
+        }
+    }
+
+    /// Adds the state, false when it was already in the set
+    fn insert(&mut self, id: StateId) -> bool {
+        let i = self.sparse[id.index()];
+        if i < self.dense.len() && self.dense[i] == id {
+            return false;
+        }
+
+        self.sparse[id.index()] = self.dense.len();
+        self.dense.push(id);
+        true
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
     }
 }
-#[derive(Debug, Clone, Copy)]
-pub struct StateChange {
-    pub matched: char,
-    pub id: u32,
+
+/// The nfa states the dfa has just moved to, kept in priority order so that when two paths
+/// both match the one the regex prefers (the first branch taken) wins. What came before is kept
+/// as assertions reached from these states need it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct IntermediateState {
+    nfa_ids: Vec<StateId>,
+    before: CharKind,
 }
 
-#[derive(Debug, Clone)]
-pub enum Transition {
-    NextStates(Vec<StateChange>),
-    Finish,
+/// Move to another state on any character from start to end inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub start: char,
+    pub end: char,
+    pub id: u32,
 }
 
 #[derive(Debug)]
 pub struct State {
-    pub looping_chars: Vec<char>,
-    pub tran: Transition,
+    /// A match ends at this state when the next character is one of these kinds, whether it
+    /// does can depend on what comes next when the regex ends in an assertion such as $
+    pub accepting: Vec<CharKind>,
+    /// Sorted and non overlapping, a character with no transition ends the search
+    pub tran: Vec<StateChange>,
 }
 
-pub fn create(regex_str: &str) -> HashMap<u32, State> {
-    let fsm = parse(regex_str).expect("error:");
+impl State {
+    pub fn accepts(&self, next: CharKind) -> bool {
+        self.accepting.contains(&next)
+    }
 
-    convert(fsm)
+    /// Transitions are sorted so they are binary searched, classes such as \p{L} can leave a
+    /// state with hundreds of them
+    pub fn next_id(&self, c: char) -> Option<u32> {
+        self.tran
+            .binary_search_by(|x| range_cmp((x.start, x.end), c))
+            .ok()
+            .map(|i| self.tran[i].id)
+    }
 }
 
-fn convert(ndfsm: Vec<NDFAState>) -> HashMap<u32, State> {
-    let ndfsm: HashMap<u32, NDFAState> = HashMap::from_iter(ndfsm.into_iter().map(|x| (x.id, x)));
-    let fst_ndfa_state = *ndfsm.get(&0).unwrap();
+#[derive(Debug)]
+pub struct Dfa {
+    pub states: HashMap<u32, State>,
+    /// The state a search starts from depends on the kind of character before the start as
+    /// assertions such as ^ look behind the search
+    pub start_states: HashMap<CharKind, u32>,
+}
 
-    let mut next_state_id = 0;
-    //initial state for dfa
-    let mut initial_state = IntermediateState::new(&mut next_state_id, vec![], vec![], vec![]);
+impl Dfa {
+    pub fn start_state(&self, before: CharKind) -> &State {
+        &self.states[&self.start_states[&before]]
+    }
 
-    let mut intial_dfsm: Vec<IntermediateState> = vec![];
+    pub fn state(&self, id: u32) -> &State {
+        &self.states[&id]
+    }
+}
 
-    let mut super_states: BTreeMap<char, Vec<u32>> = BTreeMap::new();
+/// Parses the regex and builds the deterministic state machine used for searching
+///
+/// # Errors
+/// Any error the regex parser reports is passed straight back to the caller
+pub fn create(regex_str: &str) -> Result<Dfa, Error> {
+    create_with_options(regex_str, &Options::new())
+}
 
-    let returned_paths = traverse(&fst_ndfa_state, &[], &ndfsm);
+/// Same as `create` but the regex is compiled with the given options
+///
+/// # Errors
+/// The same as `create`
+pub fn create_with_options(regex_str: &str, options: &Options) -> Result<Dfa, Error> {
+    let nfa = parse_with_options(regex_str, options)?;
 
-    /* Get all paths and add to hash or collections  */
-    for (l, mut p) in returned_paths {
-        let super_paths = super_states.entry(l).or_default();
-        super_paths.append(&mut p);
+    Ok(convert(&nfa))
+}
 
-        /* Remove duplicates  */
-        super_paths.sort();
-        super_paths.dedup();
-    }
+/// Subset construction, every dfa state stands for the set of nfa states reachable after the
+/// same input. Transitions are split on class boundaries so that each character range leaving a
+/// state leads to exactly one other state
+fn convert(nfa: &Nfa) -> Dfa {
+    /* What came before is only kept as far as assertions can see it, saving states */
+    let kinds_seen = KindsSeen::new(nfa);
+
+    let mut states: HashMap<u32, State> = HashMap::new();
+    let mut known_states: HashMap<IntermediateState, u32> = HashMap::new();
+    let mut dfsm_queue: VecDeque<IntermediateState> = VecDeque::new();
+
+    let mut add_state = |intermediate_state: IntermediateState,
+                         dfsm_queue: &mut VecDeque<IntermediateState>| {
+        match known_states.get(&intermediate_state) {
+            Some(&id) => id,
+            None => {
+                let id = known_states.len() as u32;
+                known_states.insert(intermediate_state.clone(), id);
+                dfsm_queue.push_back(intermediate_state);
+                id
+            }
+        }
+    };
 
-    /* Consolidate all paths by character  */
-    for (k, v) in &super_states {
-        let n_transition = IntermediateTransition {
-            matched: *k,
-            id: v.clone(),
+    let mut start_states: HashMap<CharKind, u32> = HashMap::new();
+    for &before in &CHAR_KINDS {
+        let initial_state = IntermediateState {
+            nfa_ids: vec![nfa.start()],
+            before: kinds_seen.merged(before),
         };
-
-        initial_state.tran.push(n_transition);
-
-        intial_dfsm.push(IntermediateState::new(
-            &mut next_state_id,
-            v.clone(),
-            vec![],
-            vec![],
-        ));
+        start_states.insert(before, add_state(initial_state, &mut dfsm_queue));
     }
-    intial_dfsm = complete_intermediate_states(&mut next_state_id, &intial_dfsm, &ndfsm);
 
-    intial_dfsm.push(initial_state);
-    intial_dfsm.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut seen = SparseSet::new(nfa);
+
+    while let Some(current_dfa) = dfsm_queue.pop_front() {
+        let current_id = add_state(current_dfa.clone(), &mut dfsm_queue);
+
+        /* Which nfa states can consume the next character depends on what that character is */
+        let mut accepting = vec![];
+        let mut reachable: HashMap<CharKind, Vec<StateId>> = HashMap::new();
+        for &next in &CHAR_KINDS {
+            let (nfa_ids, accepts) = closure(
+                &current_dfa.nfa_ids,
+                current_dfa.before,
+                next,
+                nfa,
+                &mut seen,
+            );
+            if accepts {
+                accepting.push(next);
+            }
+            reachable.insert(next, nfa_ids);
+        }
 
-    intermediate_to_final(intial_dfsm)
+        let mut tran: Vec<StateChange> = vec![];
 
-    // println!("{:?}", dfsm);
-}
-fn intermediate_to_final(inter_dfsm: Vec<IntermediateState>) -> HashMap<u32, State> {
-    let mut final_dfsm: HashMap<u32, State> = HashMap::new();
-
-    let mut next_state_id = 0;
-
-    for s in inter_dfsm.clone() {
-        let dfsa_trans = s
-            .tran
-            .iter()
-            .map(|x| {
-                let loc = inter_dfsm.iter().position(|y| y.ndfsa_ids.eq(&x.id));
-                StateChange {
-                    matched: x.matched,
-                    id: loc.expect("looking for intermediate dsfa that does not exist") as u32,
+        for (start, end) in partition(&reachable, kinds_seen, nfa) {
+            let mut next_nfa_ids: Vec<StateId> = vec![];
+            seen.clear();
+            for next_id in reachable[&CharKind::of(Some(start))]
+                .iter()
+                .map(|&id| nfa.state(id))
+                .filter(|x| x.matches_char(start))
+                .filter_map(NFAState::next_after_char)
+            {
+                /* Only the first and so highest priority path to a state matters */
+                if seen.insert(next_id) {
+                    next_nfa_ids.push(next_id);
                 }
-            })
-            .collect::<Vec<StateChange>>();
+            }
 
-        let new_state = State {
-            looping_chars: s.looping_chars,
-            tran: Transition::NextStates(dfsa_trans),
-        };
+            if next_nfa_ids.is_empty() {
+                continue;
+            }
 
-        final_dfsm.insert(next_state_id, new_state);
-        next_state_id += 1;
-    }
+            let next_dfa = IntermediateState {
+                nfa_ids: next_nfa_ids,
+                before: kinds_seen.merged(CharKind::of(Some(start))),
+            };
+            let next_id = add_state(next_dfa, &mut dfsm_queue);
 
-    for (_, x) in final_dfsm.iter_mut() {
-        if_chain! {
-            if let Transition::NextStates(l) = &x.tran;
-            if l.is_empty();
-            then {
-                x.tran = Transition::Finish;
+            /* Neighbouring ranges going to the same place are joined to keep the state small */
+            match tran.last_mut() {
+                Some(last) if last.id == next_id && last.end as u32 + 1 == start as u32 => {
+                    last.end = end;
+                }
+                _ => tran.push(StateChange {
+                    start,
+                    end,
+                    id: next_id,
+                }),
             }
         }
+
+        states.insert(current_id, State { accepting, tran });
     }
 
-    final_dfsm
+    Dfa {
+        states,
+        start_states,
+    }
 }
 
-/* TODO use a better option perhpas impl equality or library
- * Or at least make the method generic
-*/
-
-fn complete_intermediate_states(
-    mut next_state_id: &mut u32,
-    intial_dfsm: &[IntermediateState],
-    ndfsm: &HashMap<u32, NDFAState>,
-) -> Vec<IntermediateState> {
-    let mut dfsm_queue = intial_dfsm.to_vec();
-
-    let mut returned_dfsm = Vec::new();
-
-    loop {
-        if dfsm_queue.is_empty() {
-            return returned_dfsm;
+/// Follows split, epsilon and assertion states from the given states until states that consume
+/// a character are reached, assertions are checked against the kinds of character either side.
+/// Splits are explored first branch first, once the match state is reached any state found
+/// later has a lower priority than the match and is dropped. The visited set is passed in so it
+/// is allocated once rather than for every call
+fn closure(
+    nfa_ids: &[StateId],
+    before: CharKind,
+    after: CharKind,
+    nfa: &Nfa,
+    visited: &mut SparseSet,
+) -> (Vec<StateId>, bool) {
+    let mut consuming_ids: Vec<StateId> = vec![];
+    let mut stack: Vec<StateId> = nfa_ids.iter().rev().cloned().collect();
+    visited.clear();
+
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
         }
 
-        let mut super_states: BTreeMap<char, Vec<u32>> = BTreeMap::new();
-
-        let mut current_dfa = dfsm_queue.remove(0);
-
-        let potential_searched_ids = &current_dfa.ndfsa_ids.clone();
-
-        for x in potential_searched_ids {
-            let current_ndfa = ndfsm
-                .get(&x)
-                .expect("intermediate dfsa looking for non existant id");
-
-            if_chain! {
-                if let StateType::Literal(c) = current_ndfa.machine_type ;
-                if let Branch::StateId(i) = current_ndfa.branch ;
-                then {
-                    /* If a literal bracnhes leads to an internal branching mahine we have a loop  */
-                    if potential_searched_ids.iter().any(|&t| t == i) {
-                        let mut all_dfsm_vec = dfsm_queue.clone();
-                        all_dfsm_vec.append(&mut Vec::from_iter(returned_dfsm.iter().cloned()));
-
-                        let (new_ndfsa, mut newly_created) = create_looping_state(
-                            &mut next_state_id,
-                            &mut current_dfa,
-                            &mut all_dfsm_vec,
-                            &ndfsm,
-                            i,
-                            c,
-                        );
-
-                        dfsm_queue.append(&mut newly_created);
-
-                        if let Some(new_ndfsa) = new_ndfsa {
-                            returned_dfsm.push(new_ndfsa);
-                        }
-                    } else if let Branch::StateId(j) = current_ndfa.branch {
-                        let current_ndfa = ndfsm.get(&j).unwrap();
-                        let returned_paths = traverse(&current_ndfa, &[], &ndfsm);
-
-                        for (l, mut p) in returned_paths {
-                            let super_paths = super_states.entry(l).or_default();
-                            super_paths.append(&mut p);
-                            /* Remove duplicates  */
-                            super_paths.sort();
-                            super_paths.dedup();
-                        }
-                    }
+        match *nfa.state(id) {
+            NFAState::Match => return (consuming_ids, true),
+            NFAState::Split(first, second) => {
+                stack.push(second);
+                stack.push(first);
+            }
+            NFAState::Assertion(look, next) => {
+                if look.holds(before, after) {
+                    stack.push(next);
                 }
             }
+            /* Where groups matched makes no difference to whether there is a match */
+            NFAState::Epsilon(next) | NFAState::Save(_, next) => stack.push(next),
+            NFAState::Char(..) | NFAState::Class(..) | NFAState::AnyChar { .. } => {
+                consuming_ids.push(id)
+            }
         }
-        for (k, v) in &super_states {
-            /* A dfsa can represent  */
+    }
 
-            if current_dfa.ndfsa_ids.eq(v) {
-                current_dfa.looping_chars.push(*k);
-            } else {
-                let new_trans = IntermediateTransition {
-                    matched: *k,
-                    id: v.clone(),
-                };
-                current_dfa.tran.push(new_trans);
-                let existing_state = dfsm_queue
-                    .iter()
-                    .chain(returned_dfsm.iter())
-                    .position(|x| x.ndfsa_ids.eq(v));
-
-                /* if no intermediate deterministic state exists add one */
-                match existing_state {
-                    Some(_) => (),
-                    None => dfsm_queue.push(IntermediateState::new(
-                        &mut next_state_id,
-                        v.clone(),
-                        vec![],
-                        vec![],
-                    )),
+    (consuming_ids, false)
+}
+
+/// Splits every character the reachable states could consume into ranges that are either
+/// entirely inside or entirely outside each state's literal or class. Ranges are also split
+/// wherever the kind of character the assertions see changes, such as either side of \n
+fn partition(
+    reachable: &HashMap<CharKind, Vec<StateId>>,
+    kinds_seen: KindsSeen,
+    nfa: &Nfa,
+) -> Vec<(char, char)> {
+    /* Each boundary is the first character of a new range */
+    let mut boundaries: BTreeSet<u32> = kinds_seen.boundaries().into_iter().collect();
+
+    for &id in reachable.values().flatten() {
+        match nfa.state(id) {
+            NFAState::Char(c, _) => {
+                boundaries.insert(*c as u32);
+                boundaries.insert(*c as u32 + 1);
+            }
+            NFAState::Class(class, _) => {
+                for &(start, end) in class.ranges() {
+                    boundaries.insert(start as u32);
+                    boundaries.insert(end as u32 + 1);
+                }
+            }
+            NFAState::AnyChar {
+                matches_new_line, ..
+            } => {
+                boundaries.insert(0);
+                boundaries.insert(std::char::MAX as u32 + 1);
+                if !matches_new_line {
+                    boundaries.insert('\n' as u32);
+                    boundaries.insert('\n' as u32 + 1);
                 }
             }
+            _ => {}
         }
-        returned_dfsm.push(current_dfa);
     }
-}
 
-/**
- * Handles ndfsa that loop back to each to make an equivilant intermediate state
- * that has looping built in
- */
-
-fn create_looping_state(
-    next_state_id: &mut u32,
-    from_state: &mut IntermediateState,
-    existing_dfsms: &mut Vec<IntermediateState>,
-    existing_ndfsms: &HashMap<u32, NDFAState>,
-    branched_to_id: u32,
-    c: char,
-) -> (Option<IntermediateState>, Vec<IntermediateState>) {
-    let potential_searched_ids = &from_state.ndfsa_ids;
-
-    let branched_to = existing_ndfsms
-        .get(&branched_to_id)
-        .expect("branching ndfsa looking for non existant id");
-    let returned_paths = traverse(&branched_to, &[], &existing_ndfsms);
-
-    let mut current_super_states: BTreeMap<char, Vec<u32>> =
-        BTreeMap::from_iter(returned_paths.into_iter());
-
-    /* Get all branching states that can form a loop (do not branch too different literal machines) */
-    let branching_states: Vec<u32> = potential_searched_ids
-        .iter()
-        .filter(|y| {
-            if let StateType::Branching(_) = existing_ndfsms
-                .get(&y)
-                .expect("looking non existant branching")
-                .machine_type
-            {
-                branches_to_two_literals(existing_ndfsms.get(&y).unwrap(), existing_ndfsms)
-                    .is_none()
+    /* No character is in the surrogate gap so any boundary inside it moves to just after it */
+    let boundaries: Vec<u32> = boundaries
+        .into_iter()
+        .map(|x| {
+            if (0xD800..=0xDFFF).contains(&x) {
+                0xE000
             } else {
-                false
+                x
             }
         })
-        .cloned()
+        .collect::<BTreeSet<u32>>()
+        .into_iter()
         .collect();
 
-    for (_, v) in current_super_states.iter_mut() {
-        v.append(&mut branching_states.clone());
+    boundaries
+        .windows(2)
+        .map(|x| {
+            let start = std::char::from_u32(x[0]).expect("no boundary is in the surrogate gap");
+            /* A range running up to the gap ends just before it */
+            let end = std::char::from_u32(x[1] - 1).unwrap_or('\u{D7FF}');
+            (start, end)
+        })
+        .filter(|&(start, _)| {
+            reachable[&CharKind::of(Some(start))]
+                .iter()
+                .any(|&id| nfa.state(id).matches_char(start))
+        })
+        .collect()
+}
 
-        v.sort();
-        v.dedup();
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn run(dfsm: &Dfa, input: &str) -> bool {
+        let mut current_state = dfsm.start_state(CharKind::Edge);
+        for c in input.chars() {
+            match current_state.next_id(c) {
+                Some(id) => current_state = dfsm.state(id),
+                None => return false,
+            }
+        }
+        current_state.accepts(CharKind::Edge)
     }
 
-    let ndfsa_ids = current_super_states
-        .get(&c)
-        .expect("non filled hash")
-        .clone();
-
-    /* Add transitions or looping cars then add to return queue  */
-    if from_state.ndfsa_ids.eq(&ndfsa_ids) {
-        from_state.looping_chars.push('a');
-        let returned_dfsm = add_transitions_to_looping_state(
-            current_super_states,
-            c,
-            existing_dfsms,
-            from_state,
-            next_state_id,
-        );
-        (None, returned_dfsm)
-    } else {
-        let mut created_looping_state =
-            IntermediateState::new(next_state_id, ndfsa_ids.clone(), vec![c], vec![]);
-
-        from_state.tran.push(IntermediateTransition {
-            matched: c,
-            id: ndfsa_ids,
-        });
-
-        let returned_dfsm = add_transitions_to_looping_state(
-            current_super_states,
-            c,
-            existing_dfsms,
-            &mut created_looping_state,
-            next_state_id,
+    #[test]
+    fn class_splits_overlapping_ranges() {
+        let dfsm = create("[a-m]x|[h-z]y").unwrap();
+
+        assert_eq!(
+            dfsm.start_state(CharKind::Edge)
+                .tran
+                .iter()
+                .map(|x| (x.start, x.end))
+                .collect::<Vec<_>>(),
+            vec![('a', 'g'), ('h', 'm'), ('n', 'z')]
         );
-        (Some(created_looping_state), returned_dfsm)
+        assert!(run(&dfsm, "hx"));
+        assert!(run(&dfsm, "hy"));
+        assert!(run(&dfsm, "ax"));
+        assert!(!run(&dfsm, "ay"));
+        assert!(!run(&dfsm, "zx"));
     }
-}
 
-/**
-  Adds all tranisitions to looping states
-  these include transitions away from the looping state
-*/
-fn add_transitions_to_looping_state(
-    current_super_states: BTreeMap<char, Vec<u32>>,
-    looping_char: char,
-    existing_dfsms: &mut Vec<IntermediateState>,
-    looping_state: &mut IntermediateState,
-    next_state_id: &mut u32,
-) -> Vec<IntermediateState> {
-    let mut returned_dfsm: Vec<IntermediateState> = vec![];
-    for (new_path_char, new_path_ids) in current_super_states
-        .clone()
-        .into_iter()
-        .filter(|(k, _)| *k != looping_char)
-    {
-        match existing_dfsms
-            .iter_mut()
-            .find(|x| new_path_ids.eq(&x.ndfsa_ids))
-        {
-            Some(_) => {
-                /* Dont need to create s state to loop towards as one already exists  */
-                looping_state.tran.push(IntermediateTransition {
-                    matched: new_path_char,
-                    id: new_path_ids.clone(),
-                });
-            }
-            None => {
-                let ndfsa_ids = current_super_states
-                    .get(&looping_char)
-                    .expect("bad hash")
-                    .to_vec();
-                returned_dfsm.push(IntermediateState::new(
-                    next_state_id,
-                    ndfsa_ids.clone(),
-                    vec![],
-                    vec![],
-                ));
-                looping_state.tran.push(IntermediateTransition {
-                    matched: new_path_char,
-                    id: ndfsa_ids,
-                });
-            }
-        };
+    #[test]
+    fn class_ending_before_surrogates() {
+        let dfsm = create("[\\x{0}-\\x{D7FF}]|.").unwrap();
+
+        assert!(run(&dfsm, "\u{D7FF}"));
+        assert!(run(&dfsm, "\u{E000}"));
+        assert!(run(&dfsm, "\u{F900}"));
+
+        let dfsm = create("\\p{Cn}|.").unwrap();
+
+        assert!(run(&dfsm, "\u{F900}"));
+        assert!(run(&dfsm, "\u{10FFFF}"));
     }
-    returned_dfsm
-}
 
-/** Check if branch maps to two seperate literals */
-fn branches_to_two_literals<'a>(
-    ndfa_state: &'a NDFAState,
-    ndfsm: &'a HashMap<u32, NDFAState>,
-) -> Option<(&'a NDFAState, &'a NDFAState)> {
-    /* Using imoorted macro if chain this prevents excessive drfiting to the right or a pyramid of doom */
-    if_chain! {
-        if let StateType::Branching(b) = &ndfa_state.machine_type;
-        if let Branch::StateId(state_id1) = ndfa_state.branch;
-        if let Branch::StateId(state_id2) = b;
-        if let Some(branched_to_1) = ndfsm.get(&state_id1);
-        if let Some(branched_to_2) = ndfsm.get(&state_id2);
-        if let StateType::Literal(_) = branched_to_1.machine_type;
-        if let StateType::Literal(_) = branched_to_2.machine_type;
-        if branched_to_1.id != branched_to_2.id;
-        then {
-           return Some((branched_to_1,branched_to_2))
-        }
+    #[test]
+    fn loops_back_to_itself() {
+        let dfsm = create("[0-9]+").unwrap();
+
+        assert!(!dfsm.start_state(CharKind::Edge).accepts(CharKind::Edge));
+        assert!(run(&dfsm, "0"));
+        assert!(run(&dfsm, "2020"));
+        assert!(!run(&dfsm, "20a"));
+        assert_eq!(dfsm.states.len(), 2);
     }
-    None
-}
 
-/** Returns the literals that can be reached from current FDA with the excepion of
-   branches that map to two different literals as they should be replaced with
-   a direct path to the unique literal
-*/
-fn traverse(
-    current_ndfa: &NDFAState,
-    prev_states: &[u32],
-    ndfsm: &HashMap<u32, NDFAState>,
-) -> Vec<(char, Vec<u32>)> {
-    match &current_ndfa.machine_type {
-        Literal(l) => {
-            let mut traversed_states = prev_states
-                .iter()
-                .filter(|x| branches_to_two_literals(ndfsm.get(x).unwrap(), ndfsm).is_none())
-                .cloned()
-                .collect::<Vec<u32>>();
-            traversed_states.push(current_ndfa.id);
-            vec![(*l, traversed_states)]
-        }
-        Branching(br) => {
-            let mut prev_vec = prev_states.to_vec();
-            prev_vec.push(current_ndfa.id);
-            let mut rvec = vec![];
-
-            if_chain! {
-                if let Branch::StateId(i) = current_ndfa.branch;
-                if !(prev_states.iter().any(|&x| x == i));
-                then {
-                    let nstate = ndfsm
-                        .get(&i)
-                        .expect("intermediate dfsa looking for non existant id");
-                    rvec.append(&mut traverse(nstate, &prev_vec, ndfsm));
-                }
-            }
-            if_chain! {
-                if let Branch::StateId(i) = br;
-                if !(prev_states.iter().any(|&x| x == *i));
-                  then {
-                    let nstate = ndfsm
-                        .get(&i)
-                        .expect("intermediate dfsa looking for non existant id");
-                    rvec.append(&mut traverse(nstate, &prev_vec, ndfsm));
-                }
-            }
+    #[test]
+    fn any_char_skips_new_line() {
+        let dfsm = create("a.c").unwrap();
 
-            rvec
-        }
+        assert!(run(&dfsm, "abc"));
+        assert!(run(&dfsm, "aλc"));
+        assert!(!run(&dfsm, "a\nc"));
+
+        let dfsm = create_with_options("a.c", &Options::new().dot_matches_new_line(true)).unwrap();
+
+        assert!(run(&dfsm, "a\nc"));
+    }
+
+    #[test]
+    fn start_depends_on_what_came_before() {
+        let dfsm = create_with_options("^a", &Options::new().multi_line(true)).unwrap();
+
+        assert!(dfsm.start_state(CharKind::Edge).next_id('a').is_some());
+        assert!(dfsm.start_state(CharKind::NewLine).next_id('a').is_some());
+        assert!(dfsm.start_state(CharKind::Other).next_id('a').is_none());
+    }
+
+    #[test]
+    fn end_depends_on_what_comes_next() {
+        let dfsm = create_with_options("a$", &Options::new().multi_line(true)).unwrap();
+        let after_a = dfsm.state(dfsm.start_state(CharKind::Edge).next_id('a').unwrap());
+
+        assert!(after_a.accepts(CharKind::Edge));
+        assert!(after_a.accepts(CharKind::NewLine));
+        assert!(!after_a.accepts(CharKind::Other));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let dfsm = create("\\bab\\b").unwrap();
+        let after_ab = |before: CharKind| {
+            let after_a = dfsm.start_state(before).next_id('a')?;
+            dfsm.state(after_a).next_id('b').map(|id| dfsm.state(id))
+        };
+
+        assert!(after_ab(CharKind::Edge).is_some());
+        assert!(after_ab(CharKind::UnicodeWord).is_some());
+        assert!(after_ab(CharKind::AsciiWord).is_none());
+        assert!(after_ab(CharKind::Edge).unwrap().accepts(CharKind::Other));
+        assert!(!after_ab(CharKind::Edge)
+            .unwrap()
+            .accepts(CharKind::AsciiWord));
+
+        let dfsm = create_with_options("\\Bb", &Options::new().unicode(true)).unwrap();
+        assert!(dfsm
+            .start_state(CharKind::UnicodeWord)
+            .next_id('b')
+            .is_some());
+        assert!(dfsm.start_state(CharKind::Other).next_id('b').is_none());
+    }
+
+    #[test]
+    fn transitions_only_split_where_assertions_look() {
+        let dfsm = create("\\b.").unwrap();
+
+        assert_eq!(
+            dfsm.start_state(CharKind::Edge)
+                .tran
+                .iter()
+                .map(|x| (x.start, x.end))
+                .collect::<Vec<_>>(),
+            vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]
+        );
+        /* Only ascii word characters count so the rest of unicode is not split up */
+        assert_eq!(
+            dfsm.start_states[&CharKind::UnicodeWord],
+            dfsm.start_states[&CharKind::Other]
+        );
+        assert!(dfsm.start_state(CharKind::AsciiWord).tran.len() < 10);
     }
 }
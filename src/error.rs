@@ -0,0 +1,163 @@
+use std::fmt;
+
+/// Where in the regex something is, as byte offsets with the end exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+/// Every way a regex can fail to parse
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// A ( that is never closed
+    UnclosedGroup,
+    /// A ) with no ( before it
+    UnopenedGroup,
+    /// A quantifier with nothing before it to repeat, such as the * in a+*
+    DanglingQuantifier,
+    /// A \ with nothing after it, followed by a letter or digit with no meaning as an escape,
+    /// starting a code point escape without the hex digits it needs or a property escape without
+    /// a name
+    BadEscape,
+    /// A \p or \P escape naming something that is not a general category or script
+    UnknownProperty,
+    /// A code point escape for a number that is not a unicode scalar value, such as \u{D800}
+    InvalidScalarValue,
+    /// A [ that is never closed
+    UnclosedClass,
+    /// A [:name:] inside a class naming no posix class
+    UnknownPosixClass,
+    /// A range in a class that ends before it starts, such as z-a, or ends with a shorthand
+    InvalidClassRange,
+    /// A { not followed by numbers and a closing }
+    InvalidRepetition,
+    /// A counted repetition with an upper bound below its lower bound, such as {3,2}
+    InvertedRepetition,
+    /// A counted repetition larger than the repetition limit set in the options, or one that
+    /// would grow the nfa past the size limit
+    RepetitionLimitExceeded,
+    /// A (? not followed by anything a group can start with
+    UnknownGroup,
+    /// A group name that is empty, never closed with > or holds characters names can not have
+    InvalidGroupName,
+    /// A group name used by an earlier group
+    DuplicateGroupName,
+    /// A letter in (?flags) that is not a flag, a second - or a - with no flags after it
+    InvalidFlag,
+    /// A group nested inside more groups than the nest limit set in the options allows
+    NestLimitExceeded,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ErrorKind::UnclosedGroup => "unclosed group",
+            ErrorKind::UnopenedGroup => "unopened group",
+            ErrorKind::DanglingQuantifier => "nothing to quantify",
+            ErrorKind::BadEscape => "invalid escape",
+            ErrorKind::UnknownProperty => "unknown unicode property",
+            ErrorKind::InvalidScalarValue => "code point is not a unicode scalar value",
+            ErrorKind::UnclosedClass => "unclosed character class",
+            ErrorKind::UnknownPosixClass => "unknown posix class",
+            ErrorKind::InvalidClassRange => "character class range ends before it starts",
+            ErrorKind::InvalidRepetition => "invalid counted repetition",
+            ErrorKind::InvertedRepetition => {
+                "counted repetition has a maximum smaller than its minimum"
+            }
+            ErrorKind::RepetitionLimitExceeded => "counted repetition is over the limit",
+            ErrorKind::UnknownGroup => "unknown group type",
+            ErrorKind::InvalidGroupName => "invalid group name",
+            ErrorKind::DuplicateGroupName => "duplicate group name",
+            ErrorKind::InvalidFlag => "invalid flag",
+            ErrorKind::NestLimitExceeded => "groups are nested too deeply",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// A regex that could not be parsed, what went wrong and where
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    span: Span,
+    regex_str: String,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, span: Span, regex_str: &str) -> Error {
+        Error {
+            kind,
+            span,
+            regex_str: regex_str.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The regex the error was found in
+    pub fn regex_str(&self) -> &str {
+        &self.regex_str
+    }
+}
+
+/// The message then the regex with the part that caused the error underlined, e.g.
+///
+/// ```text
+/// nothing to quantify
+///     a+*
+///       ^
+/// ```
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /* Columns are counted in characters so multi byte characters line up */
+        let column = self.regex_str[..self.span.start].chars().count();
+        let width = self.regex_str[self.span.start..self.span.end]
+            .chars()
+            .count()
+            .max(1);
+
+        writeln!(f, "{}", self.kind)?;
+        writeln!(f, "    {}", self.regex_str)?;
+        write!(f, "    {}{}", " ".repeat(column), "^".repeat(width))
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn display_underlines_span() {
+        let error = Error::new(ErrorKind::InvalidClassRange, Span::new(4, 7), "λx[bz-a]");
+
+        assert_eq!(
+            error.to_string(),
+            "character class range ends before it starts\n    λx[bz-a]\n       ^^^"
+        );
+    }
+
+    #[test]
+    fn display_points_at_empty_span() {
+        let error = Error::new(ErrorKind::InvalidRepetition, Span::new(2, 2), "a{");
+
+        assert_eq!(
+            error.to_string(),
+            "invalid counted repetition\n    a{\n      ^"
+        );
+    }
+}
@@ -0,0 +1,9 @@
+pub mod ast;
+pub mod class;
+pub mod dfa;
+pub mod error;
+pub mod ndfa;
+pub mod nfa;
+pub mod pike;
+pub mod search;
+mod unicode;
@@ -1,31 +1,96 @@
-mod dfa;
-mod ndfa;
-mod search;
-
-use crate::dfa::create;
-use crate::search::find_matching;
+use regex::dfa::create;
+use regex::search::find_matching;
 
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
 
-#[macro_use]
-extern crate if_chain;
+/// Exit codes follow grep, 0 when a line matched, 1 when nothing matched and 2 on any error
+const EXIT_MATCHED: i32 = 0;
+const EXIT_NO_MATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() != 3 {
-        panic!("Should have two arguments a regex and a file name");
+        let program = args.first().map_or("regex", String::as_str);
+        eprintln!("usage: {} <regex> <file>", program);
+        process::exit(EXIT_ERROR);
     }
 
-    println!("{:?}", args);
-
     let regex_str = &args[1];
+    let file_name = &args[2];
+
+    let dfsm = match create(regex_str) {
+        Ok(dfsm) => dfsm,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let file = match File::open(file_name) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(EXIT_ERROR);
+        }
+    };
 
-    let dfsm = create(regex_str);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut any_matched = false;
 
-    let found = find_matching("aaaabd\nacd\na", dfsm);
+    /* Streams the file so large files are never held in memory all at once */
+    let mut reader = BufReader::new(file);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}: {}", file_name, e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+
+        /* A line that is not utf-8 is searched with the bad bytes replaced but printed as it was */
+        if !find_matching(&String::from_utf8_lossy(&line), &dfsm).is_empty() {
+            any_matched = true;
+            if let Err(e) = out.write_all(&line).and_then(|_| out.write_all(b"\n")) {
+                write_failed(e);
+            }
+        }
+    }
 
-    for x in found {
-        println!("{}", x);
+    if let Err(e) = out.flush() {
+        write_failed(e);
     }
+
+    if any_matched {
+        process::exit(EXIT_MATCHED);
+    } else {
+        process::exit(EXIT_NO_MATCH);
+    }
+}
+
+/// Ends the search once output can no longer be written. Output going to something that has
+/// stopped reading, such as head, only means nothing more is wanted so it is not an error
+fn write_failed(e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        process::exit(EXIT_MATCHED);
+    }
+
+    eprintln!("error: {}", e);
+    process::exit(EXIT_ERROR);
 }
@@ -1,78 +1,287 @@
+use crate::ast::{Ast, AstKind, Capture, Repetition};
+use crate::class::CharClass;
+use crate::error::{Error, ErrorKind, Span};
+use crate::nfa::{self, Nfa};
+use crate::unicode;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+/// Zero width assertions about the position in the searched text
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub enum StateType {
-    Literal(char),
-    Branching(Branch),
-}
-#[derive(Debug, PartialEq, Eq, Hash)]
-struct Transition {
-    next_state_id: u32,
-    start_group_id: Option<u32>,
+pub enum Look {
+    /// \A and ^ unless multi line
+    StartText,
+    /// \z and $ unless multi line
+    EndText,
+    /// ^ in multi line mode, the start of the text or just after a \n
+    StartLine,
+    /// $ in multi line mode, the end of the text or just before a \n
+    EndLine,
+    /// \b, a word character on one side and not the other, words being made of ascii letters,
+    /// digits and _
+    WordBoundary,
+    /// \B, word characters on both sides or neither
+    NotWordBoundary,
+    /// \b with the unicode flag set, where words are made of the characters unicode's \w has
+    UnicodeWordBoundary,
+    /// \B with the unicode flag set
+    NotUnicodeWordBoundary,
 }
 
+/// The kind of character on one side of a position, which is all an assertion needs to know
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub enum Branch {
-    StateId(u32),
-    Finish,
+pub enum CharKind {
+    /// No character, the position is the start or end of the text
+    Edge,
+    NewLine,
+    /// An ascii letter, digit or _, part of a word with or without the unicode flag
+    AsciiWord,
+    /// A character outside ascii that is only part of a word with the unicode flag
+    UnicodeWord,
+    Other,
 }
 
-/// single state in a ndfa with an id that should be unqiue but no effort is made to enforce this
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct State {
-    pub id: u32,
-    pub machine_type: StateType,
-    pub branch: Branch,
-}
-
-/// State machine creation mehtods note there is currently no way of creating a machine that finishea
-/// an existing state state machine will need to be changed to have an id changed to finish this
-/// is intentional based of use case that on creation of a state machine the finish state will
-/// not be known until all machines are made
-impl State {
-    ///Create amchine that matches on a literal then branches
-    fn new_literal_machine(id: u32, lit: char, branch: u32) -> State {
-        State {
-            id,
-            machine_type: StateType::Literal(lit),
-            branch: Branch::StateId(branch),
+impl CharKind {
+    pub fn of(c: Option<char>) -> CharKind {
+        match c {
+            None => CharKind::Edge,
+            Some('\n') => CharKind::NewLine,
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' => CharKind::AsciiWord,
+            Some(c) if !c.is_ascii() && unicode::is_word_char(c) => CharKind::UnicodeWord,
+            Some(_) => CharKind::Other,
         }
     }
-    /// create a machine that can branch
-    fn new_branching_machine(id: u32, branch_1: u32, branch_2: u32) -> State {
-        State {
-            id,
-            machine_type: StateType::Branching(Branch::StateId(branch_2)),
-            branch: Branch::StateId(branch_1),
+
+    /// Whether the character is part of a word, when unicode is false only ascii ones are
+    fn is_word(self, unicode: bool) -> bool {
+        match self {
+            CharKind::AsciiWord => true,
+            CharKind::UnicodeWord => unicode,
+            _ => false,
         }
     }
+}
 
-    fn change_state_id_to_final(&mut self, final_state_id: u32) {
-        if let Branch::StateId(l) = self.branch {
-            if l == final_state_id {
-                self.branch = Branch::Finish;
-            }
+impl Look {
+    /// Whether the assertion holds between a character of kind before and one of kind after
+    pub fn holds(self, before: CharKind, after: CharKind) -> bool {
+        match self {
+            Look::StartText => before == CharKind::Edge,
+            Look::EndText => after == CharKind::Edge,
+            Look::StartLine => matches!(before, CharKind::Edge | CharKind::NewLine),
+            Look::EndLine => matches!(after, CharKind::Edge | CharKind::NewLine),
+            Look::WordBoundary => before.is_word(false) != after.is_word(false),
+            Look::NotWordBoundary => before.is_word(false) == after.is_word(false),
+            Look::UnicodeWordBoundary => before.is_word(true) != after.is_word(true),
+            Look::NotUnicodeWordBoundary => before.is_word(true) == after.is_word(true),
         }
+    }
+}
 
-        if let StateType::Branching(br) = self.machine_type {
-            if let Branch::StateId(l) = br {
-                if l == final_state_id {
-                    self.machine_type = StateType::Branching(Branch::Finish);
-                }
-            }
+/// What base parsed, None when it was something with nothing to repeat such as (?i), and what is
+/// left of the regex
+type BaseResult<'a> = (Option<Ast>, &'a str);
+
+/// Largest count allowed in a counted repetition unless the options say otherwise
+const DEFAULT_REPETITION_LIMIT: u32 = 1000;
+
+/// Most nfa states counted repetitions may grow a regex to unless the options say otherwise
+const DEFAULT_SIZE_LIMIT: usize = 100_000;
+
+/// Most groups a group may be nested inside unless the options say otherwise
+const DEFAULT_NEST_LIMIT: u32 = 250;
+
+/// Settings that change how a regex is compiled, start from `Options::new()` and chain the
+/// setters for anything that should differ from the defaults
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    case_insensitive: bool,
+    dot_matches_new_line: bool,
+    multi_line: bool,
+    unicode: bool,
+    repetition_limit: u32,
+    size_limit: usize,
+    nest_limit: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            case_insensitive: false,
+            dot_matches_new_line: false,
+            multi_line: false,
+            unicode: false,
+            repetition_limit: DEFAULT_REPETITION_LIMIT,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            nest_limit: DEFAULT_NEST_LIMIT,
         }
     }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Lets letters match any of their cases, found with unicode simple case folding so K also
+    /// matches k and the kelvin sign, off by default
+    pub fn case_insensitive(mut self, yes: bool) -> Options {
+        self.case_insensitive = yes;
+        self
+    }
+
+    /// Makes \d, \s and \w match their unicode rather than ascii definitions, off by default
+    pub fn unicode(mut self, yes: bool) -> Options {
+        self.unicode = yes;
+        self
+    }
+
+    /// Lets ^ and $ match at the start and end of every line rather than only the start and end
+    /// of the text, off by default
+    pub fn multi_line(mut self, yes: bool) -> Options {
+        self.multi_line = yes;
+        self
+    }
+
+    /// Largest count a counted repetition such as a{2,5} may use, every repeat is a copy of the
+    /// repeated states so this bounds how large a single repetition can make the state machine
+    pub fn repetition_limit(mut self, limit: u32) -> Options {
+        self.repetition_limit = limit;
+        self
+    }
+
+    /// Most states the nfa may have once counted repetitions are copied out, this bounds what
+    /// nested repetitions such as (?:a{1000}){1000} can multiply out to
+    pub fn size_limit(mut self, limit: usize) -> Options {
+        self.size_limit = limit;
+        self
+    }
+
+    /// Most groups deep a group may be nested, the regex is parsed recursively so this bounds
+    /// how deep the parser goes rather than letting a regex such as ((((a)))) overflow the stack
+    pub fn nest_limit(mut self, limit: u32) -> Options {
+        self.nest_limit = limit;
+        self
+    }
 
-    fn increment_states(&mut self) {
-        self.id += 1;
+    /// Lets . match \n as well as every other character, off by default
+    pub fn dot_matches_new_line(mut self, yes: bool) -> Options {
+        self.dot_matches_new_line = yes;
+        self
+    }
 
-        if let Branch::StateId(l) = self.branch {
-            self.branch = Branch::StateId(l + 1);
+    /// The flags a regex starts with before any (?flags) in it changes them
+    fn flags(&self) -> Flags {
+        Flags {
+            case_insensitive: self.case_insensitive,
+            multi_line: self.multi_line,
+            unicode: self.unicode,
+            dot_matches_new_line: self.dot_matches_new_line,
+            ..Flags::default()
         }
+    }
+}
 
-        if let StateType::Branching(br) = self.machine_type {
-            if let Branch::StateId(l) = br {
-                self.machine_type = StateType::Branching(Branch::StateId(l + 1));
+/// Settings a regex can change for itself with (?flags) or (?flags:..)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Flags {
+    /// i, the same as `Options::case_insensitive`
+    case_insensitive: bool,
+    /// m, the same as `Options::multi_line`
+    multi_line: bool,
+    /// s, the same as `Options::dot_matches_new_line`
+    dot_matches_new_line: bool,
+    /// u, the same as `Options::unicode`
+    unicode: bool,
+    /// x, whitespace is ignored and # starts a comment running to the end of the line
+    verbose: bool,
+}
+
+/// The capture groups of a regex in the order they are numbered, with an index by name for the
+/// groups that have one. Group 0 is the whole match and never has a name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupNames {
+    names: Vec<Option<String>>,
+    indices: HashMap<String, usize>,
+}
+
+impl Default for GroupNames {
+    fn default() -> GroupNames {
+        GroupNames {
+            names: vec![None],
+            indices: HashMap::new(),
+        }
+    }
+}
+
+impl GroupNames {
+    /// Number of groups including group 0
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Always false as group 0 is always there, only here to go along with `len`
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Number of the group with this name
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    /// The name of each group in number order, None for a group without one
+    pub fn iter(&self) -> impl Iterator<Item = Option<&str>> {
+        self.names.iter().map(|x| x.as_deref())
+    }
+
+    /// Numbers the next group, None if the name is already taken
+    fn push(&mut self, name: Option<&str>) -> Option<u32> {
+        let group = self.names.len();
+
+        if let Some(name) = name {
+            if self.indices.insert(name.to_string(), group).is_some() {
+                return None;
             }
         }
+        self.names.push(name.map(str::to_string));
+
+        Some(group as u32)
+    }
+}
+
+/// Everything the parsing functions share while working through one regex
+struct ParseContext<'a> {
+    regex_str: &'a str,
+    options: &'a Options,
+    /// Capture groups numbered so far, group 0 is the whole match so numbering starts at 1
+    group_names: RefCell<GroupNames>,
+    /// Flags in force at the point reached in the regex
+    flags: Cell<Flags>,
+}
+
+impl ParseContext<'_> {
+    /// Numbers the next capture group, groups are numbered by the order their ( appears in.
+    /// None if the name is already taken
+    fn next_group(&self, name: Option<&str>) -> Option<u32> {
+        self.group_names.borrow_mut().push(name)
+    }
+
+    /// Byte offset into the regex of the remaining chars
+    fn offset(&self, remaining_chars: &str) -> usize {
+        self.regex_str.len() - remaining_chars.len()
+    }
+
+    /// Where the chars from start_chars up to end_chars are in the regex
+    fn span(&self, start_chars: &str, end_chars: &str) -> Span {
+        Span::new(self.offset(start_chars), self.offset(end_chars))
+    }
+
+    /// An error covering the next len bytes of the remaining chars
+    fn error(&self, kind: ErrorKind, remaining_chars: &str, len: usize) -> Error {
+        let start = self.offset(remaining_chars);
+        Error::new(kind, Span::new(start, start + len), self.regex_str)
     }
 }
 
@@ -80,606 +289,1806 @@ impl State {
 ///
 ///  # Errors
 /// Dupliacte quantifiers such as a+* as the + has nothing to quantify technically still recoverable but undesirable
-/// not having a corresponding left and right bracket
-pub fn parse(regex_str: &str) -> Result<std::vec::Vec<State>, &'static str> {
-    match check_valid_regex(regex_str) {
-        Ok(_) => {}
-        Err(e) => return Err(e),
+/// not having a corresponding left and right bracket, malformed classes, escapes and counted
+/// repetitions. The error holds the kind of problem and where in the regex it is
+pub fn parse(regex_str: &str) -> Result<Nfa, Error> {
+    parse_with_options(regex_str, &Options::new())
+}
+
+/// Same as `parse` but compiled with the given options rather than the defaults
+///
+///  # Errors
+/// The same as `parse`
+pub fn parse_with_options(regex_str: &str, options: &Options) -> Result<Nfa, Error> {
+    let (nfa, _) = parse_with_groups(regex_str, options)?;
+
+    Ok(nfa)
+}
+
+/// Same as `parse_with_options` but also returns the capture groups the regex has, counting
+/// group 0 for the whole match
+///
+///  # Errors
+/// The same as `parse`
+pub fn parse_with_groups(regex_str: &str, options: &Options) -> Result<(Nfa, GroupNames), Error> {
+    let (ast, group_names) = parse_ast_with_groups(regex_str, options)?;
+
+    let nfa = nfa::lower(&ast, options.size_limit)
+        .map_err(|span| Error::new(ErrorKind::RepetitionLimitExceeded, span, regex_str))?;
+
+    Ok((nfa, group_names))
+}
+
+/// Parses a regex into an ast rather than compiling it, so it can be looked at before
+/// `nfa::lower` turns it into an nfa
+///
+///  # Errors
+/// The same as `parse`
+pub fn parse_ast(regex_str: &str, options: &Options) -> Result<Ast, Error> {
+    let (ast, _) = parse_ast_with_groups(regex_str, options)?;
+
+    Ok(ast)
+}
+
+fn parse_ast_with_groups(regex_str: &str, options: &Options) -> Result<(Ast, GroupNames), Error> {
+    let context = ParseContext {
+        regex_str,
+        options,
+        group_names: RefCell::new(GroupNames::default()),
+        flags: Cell::new(options.flags()),
+    };
+
+    check_valid_regex(&context)?;
+
+    let (ast, _) = regex(regex_str, &context)?;
+
+    Ok((ast, context.group_names.into_inner()))
+}
+
+/// Alternatives separated by |, the first that matches being preferred
+fn regex<'a>(remaining_chars: &'a str, context: &ParseContext) -> Result<(Ast, &'a str), Error> {
+    let (result_ast, mut looped_chars) = term(remaining_chars, context)?;
+    let mut alternatives = vec![result_ast];
+
+    while looped_chars.starts_with('|') {
+        let (result_ast, result_chars) = term(&looped_chars[1..], context)?;
+
+        alternatives.push(result_ast);
+        looped_chars = result_chars;
+    }
+
+    Ok((joined(alternatives, AstKind::Alternation), looped_chars))
+}
+
+fn term<'a>(remaining_chars: &'a str, context: &ParseContext) -> Result<(Ast, &'a str), Error> {
+    let mut factors = vec![];
+    let mut looped_chars = skip_ignored(remaining_chars, context);
+
+    /* Processes all terms until empty or a non term character appears */
+    while !looped_chars.starts_with('|')
+        && !looped_chars.starts_with(')')
+        && !looped_chars.is_empty()
+    {
+        let (result_ast, result_chars) = factor(looped_chars, context)?;
+
+        factors.extend(result_ast);
+        looped_chars = skip_ignored(result_chars, context);
+    }
+
+    /* A term with nothing in it, such as either side of a|, matches the empty string */
+    if factors.is_empty() {
+        let span = context.span(looped_chars, looped_chars);
+        return Ok((Ast::new(AstKind::Empty, span), looped_chars));
+    }
+
+    Ok((joined(factors, AstKind::Concat), looped_chars))
+}
+
+/// A single ast on its own, or more joined into one spanning all of them
+fn joined(mut asts: Vec<Ast>, kind: fn(Vec<Ast>) -> AstKind) -> Ast {
+    if asts.len() == 1 {
+        return asts.remove(0);
+    }
+
+    let span = Span::new(asts[0].span.start, asts[asts.len() - 1].span.end);
+    Ast::new(kind(asts), span)
+}
+
+/// In verbose mode skips any whitespace and comments, which run from # to the end of the line
+fn skip_ignored<'a>(remaining_chars: &'a str, context: &ParseContext) -> &'a str {
+    if !context.flags.get().verbose {
+        return remaining_chars;
     }
 
-    let optfsm = regex(regex_str, vec![], 0);
-    match optfsm {
-        Some((mut fsm, _, final_state)) => {
-            fsm.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    let mut looped_chars = remaining_chars.trim_start();
 
-            for x in fsm.iter_mut() {
-                x.change_state_id_to_final(final_state);
+    while let Some(comment) = looped_chars.strip_prefix('#') {
+        looped_chars = comment
+            .find('\n')
+            .map_or("", |i| &comment[i + 1..])
+            .trim_start();
+    }
+
+    looped_chars
+}
+
+/// A base and the quantifier after it if there is one, None when the base has nothing to match
+/// such as (?i)
+fn factor<'a>(remaining_chars: &'a str, context: &ParseContext) -> Result<BaseResult<'a>, Error> {
+    let (result_ast, result_chars) = base(remaining_chars, context)?;
+    let result_chars = skip_ignored(result_chars, context);
+
+    let quantifier_len = result_chars.chars().next().map_or(0, |c| c.len_utf8());
+    let dangling_quantifier =
+        || context.error(ErrorKind::DanglingQuantifier, result_chars, quantifier_len);
+
+    let (repetition, result_chars) = match result_chars.chars().next() {
+        Some('*') => (Repetition::ZeroOrMore, &result_chars[1..]),
+        Some('+') => (Repetition::OneOrMore, &result_chars[1..]),
+        Some('?') => (Repetition::ZeroOrOne, &result_chars[1..]),
+        Some('{') => {
+            if result_ast.is_none() {
+                return Err(dangling_quantifier());
             }
+            let (min, max, result_chars) = repetition(&result_chars[1..], context)?;
 
-            Ok(fsm)
+            (Repetition::Range { min, max }, result_chars)
         }
-        _ => Err("invalid regex"),
-    }
+        _ => return Ok((result_ast, result_chars)),
+    };
+
+    let ast = result_ast.ok_or_else(dangling_quantifier)?;
+    let (lazy, result_chars) = lazy_suffix(result_chars);
+    let span = Span::new(ast.span.start, context.offset(result_chars));
+
+    let kind = AstKind::Repetition {
+        repetition,
+        lazy,
+        ast: Box::new(ast),
+    };
+
+    Ok((Some(Ast::new(kind, span)), result_chars))
 }
 
-fn regex(
-    remaining_chars: &str,
-    states: Vec<State>,
-    next_state_id: u32,
-) -> Option<(Vec<State>, &str, u32)> {
-    let group_start_id = next_state_id;
+/// A ? straight after a quantifier makes it lazy so it repeats as few times as it can, gives
+/// back whether there was one and what comes after it
+fn lazy_suffix(remaining_chars: &str) -> (bool, &str) {
+    match remaining_chars.strip_prefix('?') {
+        Some(rest) => (true, rest),
+        None => (false, remaining_chars),
+    }
+}
 
-    let (mut looped_states, mut looped_chars, mut looped_state_id) =
-        term(remaining_chars, states, next_state_id)?;
+fn base<'a>(remaining_chars: &'a str, context: &ParseContext) -> Result<BaseResult<'a>, Error> {
+    let next_char = remaining_chars
+        .chars()
+        .next()
+        .expect("base is only called with characters left");
 
-    while looped_chars.starts_with('|') {
-        let (result_states, result_chars, result_state_id) =
-            term(&looped_chars[1..], looped_states.clone(), looped_state_id)?;
-
-        let new_branch =
-            State::new_branching_machine(group_start_id, group_start_id + 1, looped_state_id + 1);
-
-        /* Since a disjunction is added before some states ids and branches will be broken incrementing
-         *
-         */
-        let mut new_states = result_states
-            .into_iter()
-            .map(|x| {
-                if x.id >= group_start_id {
-                    let mut t = x;
-                    t.id += 1;
-
-                    if let Branch::StateId(l) = t.branch {
-                        if l == looped_state_id {
-                            t.branch = Branch::StateId(result_state_id + 1);
-                        } else {
-                            t.branch = Branch::StateId(l + 1);
-                        }
-                    }
-                    if let StateType::Branching(br) = t.machine_type {
-                        if let Branch::StateId(l) = br {
-                            if l == looped_state_id {
-                                t.machine_type =
-                                    StateType::Branching(Branch::StateId(result_state_id + 1));
-                            } else {
-                                t.machine_type = StateType::Branching(Branch::StateId(l + 1));
-                            }
-                        }
-                    }
+    let (kind, result_chars) = match next_char {
+        '(' => {
+            let outer_flags = context.flags.get();
+            let (kind, group_chars) = group_prefix(&remaining_chars[1..], outer_flags, context)?;
+
+            let (capture, flags) = match kind {
+                GroupKind::Capturing(name) => {
+                    /* The name ends just before the > in front of the group's regex */
+                    let index = context.next_group(name).ok_or_else(|| {
+                        let name_end = context.offset(group_chars) - 1;
+                        let name_len = name.map_or(0, str::len);
+                        Error::new(
+                            ErrorKind::DuplicateGroupName,
+                            Span::new(name_end - name_len, name_end),
+                            context.regex_str,
+                        )
+                    })?;
+                    let name = name.map(str::to_string);
+
+                    (Some(Capture { index, name }), outer_flags)
+                }
+                /* The flags hold from here to the end of the group this is in */
+                GroupKind::SetFlags(flags) => {
+                    context.flags.set(flags);
 
-                    t
-                } else {
-                    x
+                    return Ok((None, group_chars));
                 }
-            })
-            .collect::<Vec<State>>();
-        new_states.push(new_branch);
+                GroupKind::NonCapturing(flags) => (None, flags),
+            };
 
-        /*let result = term(result_chars, new_states, result_state_id + 1)?;*/
+            context.flags.set(flags);
+            let (result_ast, result_remaining_chars) = regex(group_chars, context)?;
+            if !result_remaining_chars.starts_with(')') {
+                return Err(context.error(ErrorKind::UnclosedGroup, remaining_chars, 1));
+            };
+            /* Flags set inside the group end with it */
+            context.flags.set(outer_flags);
 
-        looped_chars = result_chars;
-        looped_states = new_states;
-        looped_state_id = result_state_id + 1;
+            let kind = AstKind::Group {
+                capture,
+                ast: Box::new(result_ast),
+            };
+
+            (kind, &result_remaining_chars[1..])
+        }
+        ')' => return Ok((None, remaining_chars)),
+        '[' => {
+            let (class, result_remaining_chars) = class(&remaining_chars[1..], context)?;
+
+            (AstKind::Class(class), result_remaining_chars)
+        }
+        '.' => {
+            let matches_new_line = context.flags.get().dot_matches_new_line;
+
+            (AstKind::AnyChar { matches_new_line }, &remaining_chars[1..])
+        }
+        '^' | '$' => {
+            let look = match (next_char, context.flags.get().multi_line) {
+                ('^', false) => Look::StartText,
+                ('^', true) => Look::StartLine,
+                (_, false) => Look::EndText,
+                (_, true) => Look::EndLine,
+            };
+
+            (AstKind::Assertion(look), &remaining_chars[1..])
+        }
+        '\\' => {
+            let (escaped, result_remaining_chars) = escape(remaining_chars, context)?;
+
+            let kind = match escaped {
+                Escape::Literal(c) => literal(c, context),
+                Escape::Class(class) => AstKind::Class(class),
+                Escape::Assertion(look) => AstKind::Assertion(look),
+            };
+
+            (kind, result_remaining_chars)
+        }
+        _ => (
+            literal(next_char, context),
+            &remaining_chars[next_char.len_utf8()..],
+        ),
+    };
+
+    let span = context.span(remaining_chars, result_chars);
+    Ok((Some(Ast::new(kind, span)), result_chars))
+}
+
+/// A literal character, under the case insensitive flag a class of it and its other cases
+fn literal(lit: char, context: &ParseContext) -> AstKind {
+    if context.flags.get().case_insensitive {
+        let mut class = CharClass::new();
+        class.push(lit, lit);
+        let class = class.case_folded();
+
+        if class.ranges() != [(lit, lit)] {
+            return AstKind::Class(class);
+        }
     }
 
-    Some((looped_states, looped_chars, looped_state_id))
+    AstKind::Char(lit)
 }
 
-fn term(
-    remaining_chars: &str,
-    states: Vec<State>,
-    next_state_id: u32,
-) -> Option<(Vec<State>, &str, u32)> {
-    if remaining_chars.starts_with('|') {
-        return None;
+/// What an escape sequence stands for
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Escape {
+    Literal(char),
+    Class(CharClass),
+    Assertion(Look),
+}
+
+/// Parses an escape sequence starting from its \ and gives back what it stands for
+///
+/// # Errors
+/// A \ at the end of the regex, a letter or digit that is not an escape and malformed code
+/// points
+fn escape<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<(Escape, &'a str), Error> {
+    let mut chars = remaining_chars[1..].chars();
+    let escaped_char = chars
+        .next()
+        .ok_or_else(|| context.error(ErrorKind::BadEscape, remaining_chars, 1))?;
+
+    let escaped = match escaped_char {
+        'A' => Escape::Assertion(Look::StartText),
+        'z' => Escape::Assertion(Look::EndText),
+        'b' if context.flags.get().unicode => Escape::Assertion(Look::UnicodeWordBoundary),
+        'B' if context.flags.get().unicode => Escape::Assertion(Look::NotUnicodeWordBoundary),
+        'b' => Escape::Assertion(Look::WordBoundary),
+        'B' => Escape::Assertion(Look::NotWordBoundary),
+        'a' => Escape::Literal('\x07'),
+        'f' => Escape::Literal('\x0C'),
+        'n' => Escape::Literal('\n'),
+        'r' => Escape::Literal('\r'),
+        't' => Escape::Literal('\t'),
+        'v' => Escape::Literal('\x0B'),
+        'x' | 'u' => return code_point(remaining_chars, context),
+        'p' | 'P' => return property_class(remaining_chars, context),
+        _ => match perl_class(escaped_char, context) {
+            Some(class) => Escape::Class(class),
+            None if is_escapable(escaped_char) => Escape::Literal(escaped_char),
+            None => {
+                return Err(context.error(
+                    ErrorKind::BadEscape,
+                    remaining_chars,
+                    1 + escaped_char.len_utf8(),
+                ))
+            }
+        },
+    };
+
+    Ok((escaped, chars.as_str()))
+}
+
+/// Parses a code point escape starting from its \, which is \x and two hex digits, \u and four
+/// or either of them followed by any number of hex digits between { and }
+///
+/// # Errors
+/// Missing or too few hex digits and numbers that are not unicode scalar values, such as
+/// surrogates or anything past 10FFFF
+fn code_point<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<(Escape, &'a str), Error> {
+    let digits_len = if remaining_chars.starts_with("\\x") {
+        2
+    } else {
+        4
+    };
+    let digit_chars = &remaining_chars[2..];
+
+    let (digits, result_chars) = match digit_chars.strip_prefix('{') {
+        Some(braced) => {
+            let close_index = braced.find('}').ok_or_else(|| {
+                context.error(ErrorKind::BadEscape, remaining_chars, remaining_chars.len())
+            })?;
+            (&braced[..close_index], &braced[close_index + 1..])
+        }
+        None => {
+            let found_len = digit_chars
+                .chars()
+                .take(digits_len)
+                .take_while(char::is_ascii_hexdigit)
+                .count();
+            (&digit_chars[..found_len], &digit_chars[found_len..])
+        }
+    };
+
+    /* Errors cover the whole escape */
+    let escape_len = remaining_chars.len() - result_chars.len();
+
+    let well_formed = !digits.is_empty()
+        && digits.len() <= 8
+        && digits.chars().all(|c| c.is_ascii_hexdigit())
+        && (digit_chars.starts_with('{') || digits.len() == digits_len);
+    if !well_formed {
+        return Err(context.error(ErrorKind::BadEscape, remaining_chars, escape_len));
     }
 
-    if remaining_chars.chars().count() == 0 {
-        return Some((states, remaining_chars, next_state_id));
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+        .map(|c| (Escape::Literal(c), result_chars))
+        .ok_or_else(|| context.error(ErrorKind::InvalidScalarValue, remaining_chars, escape_len))
+}
+
+/// Parses a unicode property escape starting from its \, which is \p followed by the one letter
+/// name of a general category or the name of a general category or script between { and }. \P
+/// is the negation. Property classes are always unicode whether or not the flag is set, under
+/// the case insensitive flag they take in the other cases of what they hold
+///
+/// # Errors
+/// A missing name or } and names that are neither a general category nor a script
+fn property_class<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<(Escape, &'a str), Error> {
+    let name_chars = &remaining_chars[2..];
+
+    let (name, result_chars) = match name_chars.strip_prefix('{') {
+        Some(braced) => {
+            let close_index = braced.find('}').ok_or_else(|| {
+                context.error(ErrorKind::BadEscape, remaining_chars, remaining_chars.len())
+            })?;
+            (&braced[..close_index], &braced[close_index + 1..])
+        }
+        None => {
+            let name_len = name_chars.chars().next().map_or(0, char::len_utf8);
+            (&name_chars[..name_len], &name_chars[name_len..])
+        }
+    };
+
+    /* Errors cover the whole escape */
+    let escape_len = remaining_chars.len() - result_chars.len();
+
+    if name.is_empty() {
+        return Err(context.error(ErrorKind::BadEscape, remaining_chars, escape_len));
     }
 
-    let (mut looped_states, mut looped_chars, mut looped_state_id) =
-        factor(remaining_chars, states, next_state_id)?;
+    let mut class = unicode::property(name)
+        .map(CharClass::from_ranges)
+        .ok_or_else(|| context.error(ErrorKind::UnknownProperty, remaining_chars, escape_len))?;
 
-    /* Processes all terms until empty or a non term character appears */
-    while !looped_chars.starts_with('|')
-        && !looped_chars.starts_with(')')
-        && looped_chars.chars().count() > 0
+    /* Folded before negating so \P{Lu} leaves out a as well */
+    if context.flags.get().case_insensitive {
+        class = class.case_folded();
+    }
+
+    if remaining_chars.starts_with("\\P") {
+        Ok((Escape::Class(class.negated()), result_chars))
+    } else {
+        Ok((Escape::Class(class), result_chars))
+    }
+}
+
+/// Whether an escaped character stands for itself, only ascii characters that are not letters or
+/// digits do so letters and digits are free to have their own meanings
+fn is_escapable(c: char) -> bool {
+    c.is_ascii() && !c.is_ascii_alphanumeric()
+}
+
+/// The class a perl shorthand such as \d stands for, the upper case letter being the negation.
+/// Ascii only unless the unicode flag is set. None if the character is not a shorthand
+fn perl_class(escaped_char: char, context: &ParseContext) -> Option<CharClass> {
+    let ranges: &[(char, char)] = match (escaped_char, context.flags.get().unicode) {
+        ('d' | 'D', false) => &[('0', '9')],
+        ('s' | 'S', false) => &[('\t', '\r'), (' ', ' ')],
+        ('w' | 'W', false) => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        ('d' | 'D', true) => unicode::PERL_DIGIT,
+        ('s' | 'S', true) => unicode::PERL_SPACE,
+        ('w' | 'W', true) => unicode::PERL_WORD,
+        _ => return None,
+    };
+
+    let class = CharClass::from_ranges(ranges);
+
+    if escaped_char.is_ascii_uppercase() {
+        Some(class.negated())
+    } else {
+        Some(class)
+    }
+}
+
+/// What sort of group a ( starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GroupKind<'a> {
+    /// Saves where it matched, under a name for (?P<name>..) and (?<name>..)
+    Capturing(Option<&'a str>),
+    /// (?:..) or (?flags:..) only groups and saves nothing, the flags hold inside the group
+    NonCapturing(Flags),
+    /// (?flags) is not a group at all, the flags hold until the end of the group it is in
+    SetFlags(Flags),
+}
+
+/// Parses what comes after the ( of a group up to where the regex inside it starts, any flags
+/// are applied to the flags in force before the group. A name is made of ascii letters, digits
+/// and _ and does not start with a digit
+fn group_prefix<'a>(
+    remaining_chars: &'a str,
+    flags: Flags,
+    context: &ParseContext,
+) -> Result<(GroupKind<'a>, &'a str), Error> {
+    let after_question = match remaining_chars.strip_prefix('?') {
+        Some(rest) => rest,
+        None => return Ok((GroupKind::Capturing(None), remaining_chars)),
+    };
+
+    let name_chars = match after_question
+        .strip_prefix("P<")
+        .or_else(|| after_question.strip_prefix('<'))
     {
-        let (result_states, result_chars, result_state_id) =
-            factor(looped_chars, looped_states.clone(), looped_state_id)?;
-        looped_states = result_states;
-        looped_chars = result_chars;
-        looped_state_id = result_state_id;
-    }
-
-    Some((looped_states, looped_chars, looped_state_id))
-}
-
-fn factor(
-    remaining_chars: &str,
-    states: Vec<State>,
-    next_state_id: u32,
-) -> Option<(Vec<State>, &str, u32)> {
-    let (result_current_state, mut result_states, result_chars, result_transition) =
-        base(remaining_chars, states, next_state_id)?;
-
-    match result_chars.chars().next() {
-        Some('*') => {
-            /*
-             * Gets the start of the previous group of states if surounded by brackets
-             * Or the previous state if no brackets
-             */
-            let group_start_id = result_transition.start_group_id?;
-
-            /*
-             * The new branching state will be inserted before previous machine
-             */
-
-            let final_state_id = result_transition.next_state_id + 1;
-
-            let new_branch =
-                State::new_branching_machine(group_start_id, group_start_id + 1, final_state_id);
-
-            /*
-             * Actions now set the states repeated by the encolsure
-             */
-            match result_current_state {
-                /* If there was a singular state no "()"s change to go after the branch state */
-                Some(mut nstate) => {
-                    if let StateType::Literal(_) = nstate.machine_type {
-                        nstate.id = result_transition.next_state_id;
-                        nstate.branch = Branch::StateId(group_start_id);
-                    }
-                    result_states.push(nstate);
-                }
-                /* Groups of states caputured by the () */
-                _ => {
-                    /* increament ids and states bracnhed to by 1 to make room for every sate that is going to be in front of the new branching machine */
-                    result_states = result_states
-                        .into_iter()
-                        .map(|x| {
-                            if x.id >= group_start_id {
-                                let mut res = x;
-                                res.increment_states();
-                                if let Branch::StateId(n) = res.branch {
-                                    if n == final_state_id {
-                                        res.branch = Branch::StateId(group_start_id);
-                                    }
-                                }
-
-                                res
-                            } else {
-                                x
-                            }
-                        })
-                        .collect::<Vec<State>>();
-                }
+        Some(name_chars) => name_chars,
+        None => return group_flags(remaining_chars, flags, context),
+    };
+
+    let close_index = name_chars
+        .find('>')
+        .ok_or_else(|| context.error(ErrorKind::InvalidGroupName, name_chars, name_chars.len()))?;
+    let name = &name_chars[..close_index];
+
+    let valid_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric());
+    if !valid_name {
+        return Err(context.error(ErrorKind::InvalidGroupName, name_chars, close_index));
+    }
+
+    Ok((
+        GroupKind::Capturing(Some(name)),
+        &name_chars[close_index + 1..],
+    ))
+}
+
+/// Parses the flags of a (?flags) or (?flags:..) group starting from its ?, flags before a - are
+/// turned on and flags after it turned off
+fn group_flags<'a>(
+    remaining_chars: &'a str,
+    flags: Flags,
+    context: &ParseContext,
+) -> Result<(GroupKind<'a>, &'a str), Error> {
+    /* Errors about the group as a whole cover it from the ( */
+    let group_error = |kind: ErrorKind, len: usize| {
+        let start = context.offset(remaining_chars) - 1;
+        Error::new(kind, Span::new(start, start + len), context.regex_str)
+    };
+
+    let mut flags = flags;
+    let mut turn_on = true;
+    let mut last_flag = None;
+    let mut looped_chars = &remaining_chars[1..];
+
+    while let Some(flag) = looped_chars.chars().next() {
+        let result_chars = &looped_chars[flag.len_utf8()..];
+
+        match flag {
+            /* (? followed by anything that is not a flag is some other kind of group */
+            _ if last_flag.is_none() && !"imsux-:".contains(flag) => {
+                return Err(group_error(ErrorKind::UnknownGroup, 2 + flag.len_utf8()));
+            }
+            /* A - with nothing after it to turn off */
+            ':' | ')' if last_flag == Some('-') => {
+                let start = context.offset(looped_chars) - 1;
+                return Err(Error::new(
+                    ErrorKind::InvalidFlag,
+                    Span::new(start, start + 1),
+                    context.regex_str,
+                ));
             }
+            ':' => return Ok((GroupKind::NonCapturing(flags), result_chars)),
+            ')' => return Ok((GroupKind::SetFlags(flags), result_chars)),
+            '-' if turn_on => turn_on = false,
+            'i' => flags.case_insensitive = turn_on,
+            'm' => flags.multi_line = turn_on,
+            's' => flags.dot_matches_new_line = turn_on,
+            'u' => flags.unicode = turn_on,
+            'x' => flags.verbose = turn_on,
+            _ => {
+                return Err(context.error(ErrorKind::InvalidFlag, looped_chars, flag.len_utf8()));
+            }
+        }
+
+        last_flag = Some(flag);
+        looped_chars = result_chars;
+    }
+
+    Err(group_error(ErrorKind::UnclosedGroup, 1))
+}
 
-            result_states.push(new_branch);
+/// Parses the bounds of a counted repetition {n}, {n,} or {n,m} up to and including the closing }
+/// the opening { has already been consumed. No upper bound is returned as None
+fn repetition<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<(u32, Option<u32>, &'a str), Error> {
+    /* The error covers the whole repetition from the { */
+    let error = |kind: ErrorKind, len: usize| {
+        let start = context.offset(remaining_chars) - 1;
+        Error::new(kind, Span::new(start, start + 1 + len), context.regex_str)
+    };
 
-            Some((result_states, &result_chars[1..], final_state_id))
+    let close_index = remaining_chars
+        .find('}')
+        .ok_or_else(|| error(ErrorKind::InvalidRepetition, remaining_chars.len()))?;
+    let bounds = &remaining_chars[..close_index];
+    let result_chars = &remaining_chars[close_index + 1..];
+
+    let parse_bound = |bound: &str| {
+        if !bound.is_empty() && bound.chars().all(|c| c.is_ascii_digit()) {
+            bound
+                .parse::<u32>()
+                .map_err(|_| error(ErrorKind::RepetitionLimitExceeded, close_index + 1))
+        } else {
+            Err(error(ErrorKind::InvalidRepetition, close_index + 1))
         }
-        Some('+') => {
-            /*
-             * Gets the start of the previous group of states if surounded by brackets
-             * Or the previous state if no brackets
-             */
-            let group_start_id = result_transition.start_group_id?;
-
-            /* Create new branching machien after expression will branch back to previous group or go to next expression */
-
-            let new_branch = State::new_branching_machine(
-                result_transition.next_state_id,
-                group_start_id,
-                result_transition.next_state_id + 1,
-            );
-
-            /* If there is a new state add it */
-            if let Some(nstate) = result_current_state {
-                result_states.push(nstate);
-            };
+    };
 
-            result_states.push(new_branch);
+    let (min, max) = match bounds.find(',') {
+        None => {
+            let count = parse_bound(bounds)?;
+            (count, Some(count))
+        }
+        Some(comma_index) => {
+            let min = parse_bound(&bounds[..comma_index])?;
+            let max = &bounds[comma_index + 1..];
+
+            if max.is_empty() {
+                (min, None)
+            } else {
+                (min, Some(parse_bound(max)?))
+            }
+        }
+    };
 
-            Some((
-                result_states,
-                &result_chars[1..],
-                result_transition.next_state_id + 1,
-            ))
+    if max.is_some_and(|max| max < min) {
+        return Err(error(ErrorKind::InvertedRepetition, close_index + 1));
+    }
+    if max.unwrap_or(min) > context.options.repetition_limit {
+        return Err(error(ErrorKind::RepetitionLimitExceeded, close_index + 1));
+    }
+
+    Ok((min, max, result_chars))
+}
+
+/// Parses the inside of a bracket expression such as [a-z0-9_] or [^)] up to and including the
+/// closing ], the opening [ has already been consumed
+///
+/// A ] straight after the [ or [^ and a - at either end of the class are taken literally. The
+/// set operations &&, -- and ~~ between unions of items all bind as tightly as each other so are
+/// applied left to right, with any negation applied last
+fn class<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<(CharClass, &'a str), Error> {
+    /* Running out of characters is reported against the whole class from the [ */
+    let unclosed = || {
+        let start = context.offset(remaining_chars) - 1;
+        Error::new(
+            ErrorKind::UnclosedClass,
+            Span::new(start, context.regex_str.len()),
+            context.regex_str,
+        )
+    };
+
+    let (negated, looped_chars) = match remaining_chars.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, remaining_chars),
+    };
+
+    let (mut class, mut looped_chars) = match looped_chars.strip_prefix(']') {
+        Some(rest) => {
+            let (mut class, result_chars) = class_union(rest, context, &unclosed)?;
+            class.push(']', ']');
+            (class, result_chars)
         }
-        _ => {
-            if let Some(nstate) = result_current_state {
-                result_states.push(nstate)
-            };
+        None => class_union(looped_chars, context, &unclosed)?,
+    };
 
-            Some((result_states, result_chars, result_transition.next_state_id))
+    while !looped_chars.starts_with(']') {
+        let (operand, result_chars) = class_union(&looped_chars[2..], context, &unclosed)?;
+        match &looped_chars[..2] {
+            "&&" => class.intersect(&operand),
+            "--" => class.difference(&operand),
+            _ => class.symmetric_difference(&operand),
         }
+        looped_chars = result_chars;
+    }
+
+    if negated {
+        class = class.negated();
     }
+
+    Ok((class, &looped_chars[1..]))
 }
 
-fn base(
-    remaining_chars: &str,
-    states: Vec<State>,
-    next_state_id: u32,
-) -> Option<(Option<State>, Vec<State>, &str, Transition)> {
-    let next_char = remaining_chars.chars().next()?;
+/// Whether a set operation between the unions of items in a class starts here
+fn starts_with_class_operator(remaining_chars: &str) -> bool {
+    ["&&", "--", "~~"]
+        .iter()
+        .any(|operator| remaining_chars.starts_with(operator))
+}
 
-    match next_char {
-        '(' => {
-            let (result_states, result_remaining_chars, result_next_state_id) =
-                regex(&remaining_chars[1..], states, next_state_id)?;
-            if !result_remaining_chars.starts_with(')') {
-                panic!("Fix not ending with )");
+/// Parses the characters, ranges, shorthands and nested classes in a class up to the closing ]
+/// or a set operation, whichever comes first, without consuming it
+fn class_union<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+    unclosed: &impl Fn() -> Error,
+) -> Result<(CharClass, &'a str), Error> {
+    let mut looped_chars = remaining_chars;
+
+    let mut class = CharClass::new();
+    /*
+     * Shorthands such as \d and nested classes are kept apart as case folding only applies to
+     * the characters, a nested class has already been folded
+     */
+    let mut shorthands = CharClass::new();
+
+    while !looped_chars.starts_with(']') && !starts_with_class_operator(looped_chars) {
+        let (range_start, result_chars) =
+            match class_item(looped_chars, context)?.ok_or_else(unclosed)? {
+                (Escape::Literal(c), result_chars) => (c, result_chars),
+                (Escape::Class(shorthand), result_chars) => {
+                    shorthands.union(&shorthand);
+                    looped_chars = result_chars;
+                    continue;
+                }
+                (Escape::Assertion(_), _) => unreachable!("class_item never gives assertions"),
             };
 
-            Some((
-                None,
-                result_states,
-                &result_remaining_chars[1..],
-                Transition {
-                    next_state_id: result_next_state_id,
-                    start_group_id: Some(next_state_id),
-                },
-            ))
+        match result_chars.strip_prefix('-') {
+            Some(range_rest)
+                if !range_rest.is_empty()
+                    && !range_rest.starts_with(']')
+                    && !range_rest.starts_with('-') =>
+            {
+                let (range_end, result_chars) =
+                    match class_item(range_rest, context)?.ok_or_else(unclosed)? {
+                        (Escape::Literal(c), result_chars) => (c, result_chars),
+                        /* A shorthand such as \d can not end a range */
+                        (_, result_chars) => {
+                            return Err(context.error(
+                                ErrorKind::InvalidClassRange,
+                                looped_chars,
+                                looped_chars.len() - result_chars.len(),
+                            ))
+                        }
+                    };
+                if range_end < range_start {
+                    return Err(context.error(
+                        ErrorKind::InvalidClassRange,
+                        looped_chars,
+                        looped_chars.len() - result_chars.len(),
+                    ));
+                }
+                class.push(range_start, range_end);
+                looped_chars = result_chars;
+            }
+            _ => {
+                class.push(range_start, range_start);
+                looped_chars = result_chars;
+            }
         }
-        ')' => Some((
-            None,
-            states,
-            remaining_chars,
-            Transition {
-                next_state_id,
-                start_group_id: None,
-            },
-        )),
-        '\\' => {
-            let escaped_char = remaining_chars.chars().nth(1)?; // next doesnt work not sure why something to do with scope?
+    }
+
+    /* Folded before negating so [^a] leaves out A as well */
+    if context.flags.get().case_insensitive {
+        class = class.case_folded();
+    }
+    class.union(&shorthands);
 
-            let branched_to_id = next_state_id + 1;
+    Ok((class, looped_chars))
+}
 
-            let nstate = State::new_literal_machine(next_state_id, escaped_char, branched_to_id);
+/// A single possibly escaped character, a shorthand class such as \d, a posix class such as
+/// [:alpha:] or a nested class inside a bracket expression, None when the characters run out
+///
+/// # Errors
+/// Any escape that is not valid, assertions such as \A are not valid inside a class
+fn class_item<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<Option<(Escape, &'a str)>, Error> {
+    match remaining_chars.chars().next() {
+        /* A \ with nothing after it means the class was never closed */
+        Some('\\') if remaining_chars.len() > 1 => match escape(remaining_chars, context)? {
+            (Escape::Assertion(_), result_chars) => Err(context.error(
+                ErrorKind::BadEscape,
+                remaining_chars,
+                remaining_chars.len() - result_chars.len(),
+            )),
+            escaped => Ok(Some(escaped)),
+        },
+        Some('\\') | None => Ok(None),
+        Some('[') => match posix_class(remaining_chars, context)? {
+            Some(posix) => Ok(Some(posix)),
+            None => {
+                let (nested, result_chars) = class(&remaining_chars[1..], context)?;
+                Ok(Some((Escape::Class(nested), result_chars)))
+            }
+        },
+        Some(c) => Ok(Some((Escape::Literal(c), &remaining_chars[c.len_utf8()..]))),
+    }
+}
 
-            Some((
-                Some(nstate),
-                states,
-                &remaining_chars[2..],
-                Transition {
-                    next_state_id: branched_to_id,
-                    start_group_id: Some(next_state_id),
-                },
-            ))
-        }
+/// The class a posix class such as [:alpha:] stands for starting from its [, [:^alpha:] being
+/// the negation. These are always ascii only as they are in grep, though under the case
+/// insensitive flag they take in the other cases of what they hold. None when the characters are
+/// not a posix class, the [ then starting a nested class
+///
+/// # Errors
+/// A name made of lower case letters that is not a posix class
+fn posix_class<'a>(
+    remaining_chars: &'a str,
+    context: &ParseContext,
+) -> Result<Option<(Escape, &'a str)>, Error> {
+    let inside = match remaining_chars.strip_prefix("[:") {
+        Some(inside) => inside,
+        None => return Ok(None),
+    };
+    let close_index = match inside.find(":]") {
+        Some(close_index) => close_index,
+        None => return Ok(None),
+    };
+
+    let (negated, name) = match inside[..close_index].strip_prefix('^') {
+        Some(name) => (true, name),
+        None => (false, &inside[..close_index]),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase()) {
+        return Ok(None);
+    }
+
+    let result_chars = &inside[close_index + 2..];
+
+    let ranges: &[(char, char)] = match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "ascii" => &[('\0', '\x7F')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "word" => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
         _ => {
-            let branched_to_id = next_state_id + 1;
-
-            let nstate = State::new_literal_machine(next_state_id, next_char, branched_to_id);
-
-            Some((
-                Some(nstate),
-                states,
-                &remaining_chars[1..],
-                Transition {
-                    next_state_id: branched_to_id,
-                    start_group_id: Some(next_state_id),
-                },
+            return Err(context.error(
+                ErrorKind::UnknownPosixClass,
+                remaining_chars,
+                remaining_chars.len() - result_chars.len(),
             ))
         }
+    };
+
+    let mut class = CharClass::from_ranges(ranges);
+
+    /* Folded before negating so [[:^lower:]] leaves out A as well */
+    if context.flags.get().case_insensitive {
+        class = class.case_folded();
+    }
+
+    if negated {
+        Ok(Some((Escape::Class(class.negated()), result_chars)))
+    } else {
+        Ok(Some((Escape::Class(class), result_chars)))
     }
 }
 
 /// Checks regex is well formed other provides a (hopefully!) helpful error message
 ///
 ///
-fn check_valid_regex(regex_str: &str) -> Result<(), &'static str> {
-    let count = regex_str.chars().count();
-    if count == 0 {
-        return Err("No regex");
-    } else if count > 1 {
-        let mut last_was_quantifier = false;
-        let mut left_bracket_count = 0;
-        let mut right_bracket_count = 0;
-
-        let slice: Vec<char> = regex_str[..].chars().collect();
-
-        if slice.starts_with(&['(']) {
-            left_bracket_count += 1;
-        } else if slice.starts_with(&[')']) {
-            return Err("invalid bracketing");
+fn check_valid_regex(context: &ParseContext) -> Result<(), Error> {
+    let regex_str = context.regex_str;
+
+    /* Whether what came last can be quantified, nothing can at the start of a term */
+    let mut can_repeat = false;
+    /*
+     * Where each ( still waiting for its ) is so an unclosed one can be pointed at, along with
+     * the flags to go back to once it is closed
+     */
+    let mut open_brackets: Vec<(&str, Flags)> = vec![];
+    let mut flags = context.flags.get();
+
+    let mut remaining_chars = regex_str;
+
+    //check for mismatched bracketing, malformed classes and mutiple consectuive qunatifiers
+    while let Some(next_char) = remaining_chars.chars().next() {
+        let current_chars = remaining_chars;
+        remaining_chars = &remaining_chars[next_char.len_utf8()..];
+
+        /* Whitespace and comments in verbose mode do not seperate a quantifier from another */
+        if flags.verbose && next_char.is_whitespace() {
+            continue;
+        }
+        if flags.verbose && next_char == '#' {
+            remaining_chars = remaining_chars
+                .find('\n')
+                .map_or("", |i| &remaining_chars[i + 1..]);
+            continue;
         }
 
-        //check for mismatched bracketing and mutiple consectuive qunatifiers
-        for x in slice.windows(2) {
-            match x {
-                [fst, '('] if *fst != '\\' => {
-                    left_bracket_count += 1;
-                    last_was_quantifier = false;
+        can_repeat = match next_char {
+            '\\' => {
+                let (_, result_chars) = escape(current_chars, context)?;
+                remaining_chars = result_chars;
+                true
+            }
+            '[' => {
+                /* Brackets and quantifiers inside a class are plain characters so skip over it */
+                let (_, result_chars) = class(remaining_chars, context)?;
+                remaining_chars = result_chars;
+                true
+            }
+            '(' => {
+                /* The ? starting a named, non capturing or flag group is not a quantifier */
+                let (kind, result_chars) = group_prefix(remaining_chars, flags, context)?;
+                remaining_chars = result_chars;
+
+                match kind {
+                    GroupKind::SetFlags(group_flags) => flags = group_flags,
+                    GroupKind::NonCapturing(group_flags) => {
+                        open_brackets.push((current_chars, flags));
+                        flags = group_flags;
+                    }
+                    GroupKind::Capturing(_) => open_brackets.push((current_chars, flags)),
                 }
-                [fst, ')'] if *fst != '\\' => {
-                    right_bracket_count += 1;
-                    last_was_quantifier = false;
+
+                /* Checked here so the recursive parse never goes deeper than the limit */
+                if open_brackets.len() > context.options.nest_limit as usize {
+                    return Err(context.error(ErrorKind::NestLimitExceeded, current_chars, 1));
                 }
-                [fst, '+'] | [fst, '*'] if *fst != '\\' => {
-                    if last_was_quantifier {
-                        return Err("nothing to qunatify");
-                    } else {
-                        last_was_quantifier = true;
-                    }
+                false
+            }
+            ')' => {
+                match open_brackets.pop() {
+                    Some((_, outer_flags)) => flags = outer_flags,
+                    None => return Err(context.error(ErrorKind::UnopenedGroup, current_chars, 1)),
                 }
-                _ => last_was_quantifier = false,
+                true
             }
-            if right_bracket_count > left_bracket_count {
-                return Err("invalid bracketing");
+            '+' | '*' | '?' => {
+                if !can_repeat {
+                    return Err(context.error(ErrorKind::DanglingQuantifier, current_chars, 1));
+                }
+                /* A ? straight after makes the quantifier lazy rather than quantifying it */
+                remaining_chars = lazy_suffix(remaining_chars).1;
+                false
             }
-        }
-        if right_bracket_count != left_bracket_count {
-            return Err("mismatched number of brackets");
-        }
-    };
+            '{' => {
+                let (_, _, result_chars) = repetition(remaining_chars, context)?;
+                remaining_chars = lazy_suffix(result_chars).1;
+
+                if !can_repeat {
+                    return Err(context.error(
+                        ErrorKind::DanglingQuantifier,
+                        current_chars,
+                        current_chars.len() - remaining_chars.len(),
+                    ));
+                }
+                false
+            }
+            '|' => false,
+            _ => true,
+        };
+    }
+
+    if let Some((unclosed, _)) = open_brackets.pop() {
+        return Err(context.error(ErrorKind::UnclosedGroup, unclosed, 1));
+    }
 
-    Ok(()) // have to have some return value
+    Ok(())
 }
 
 /*
 
 <regex> ::= <term> '|' <regex>
-            | term
+            | <term>
+
+<term> ::= { <factor> }
 
-<term> ::= { factor }
+<factor> ::= <base> [ ( '*' | '+' | '?' | <repetition> ) [ '?' ] ]
 
-<factor> ::= <base> { '*' }
+<repetition> ::= '{' <number> '}'
+            | '{' <number> ',' '}'
+            | '{' <number> ',' <number> '}'
 
 <base> ::= <char>
-            | '\' <char>
+            | '.'
+            | '^'
+            | '$'
+            | <escape>
+            | '[' <class> ']'
             | '(' <regex> ')'
+            | '(' '?' 'P' '<' <name> '>' <regex> ')'
+            | '(' '?' '<' <name> '>' <regex> ')'
+            | '(' '?' <flags> ':' <regex> ')'
+            | '(' '?' <flags> ')'
+
+<flags> ::= { <flag> } [ '-' { <flag> } ]
+
+<flag> ::= 'i' | 'm' | 's' | 'u' | 'x'
+
+<class> ::= [ '^' ] <class-union> { <class-operator> <class-union> }
+
+<class-operator> ::= '&&' | '--' | '~~'
+
+<class-union> ::= { <class-char> [ '-' <class-char> ] | '\' <perl-class> | <property>
+            | <posix-class> | '[' <class> ']' }
+
+<escape> ::= '\' <char>
+            | '\' <perl-class>
+            | '\' <control>
+            | '\' 'A'
+            | '\' 'z'
+            | '\' 'b'
+            | '\' 'B'
+            | <code-point>
+            | <property>
+
+<code-point> ::= '\' 'x' <hex> <hex>
+            | '\' 'u' <hex> <hex> <hex> <hex>
+            | '\' ( 'x' | 'u' ) '{' <hex> { <hex> } '}'
+
+<perl-class> ::= 'd' | 'D' | 's' | 'S' | 'w' | 'W'
+
+<control> ::= 'a' | 'f' | 'n' | 'r' | 't' | 'v'
+
+<property> ::= '\' ( 'p' | 'P' ) <char>
+            | '\' ( 'p' | 'P' ) '{' [ <property-name> '=' ] <property-value> '}'
+
+<posix-class> ::= '[' ':' [ '^' ] <posix-name> ':' ']'
+
+<posix-name> ::= 'alnum' | 'alpha' | 'ascii' | 'blank' | 'cntrl' | 'digit' | 'graph'
+            | 'lower' | 'print' | 'punct' | 'space' | 'upper' | 'word' | 'xdigit'
+
+<class-char> ::= <char>
+            | '\' <char>
+            | '\' <control>
+            | <code-point>
 
 */
 #[cfg(test)]
 mod test_super {
 
     use super::*; // appears to do nothing not sure why
-    use crate::ndfa::Branch::Finish;
-    use crate::ndfa::Branch::StateId;
-    use crate::ndfa::StateType::Branching;
-    use crate::ndfa::StateType::Literal;
+    use crate::nfa::State::{AnyChar, Assertion, Char, Class, Epsilon, Match, Save, Split};
+    use crate::nfa::{State, StateId};
+
+    fn char_class(ranges: &[(char, char)]) -> CharClass {
+        let mut class = CharClass::new();
+        for &(start, end) in ranges {
+            class.push(start, end);
+        }
+        class
+    }
+
+    fn id(index: u32) -> StateId {
+        StateId::new(index)
+    }
+
+    /// Where the nfa of the regex starts along with all of its states
+    fn nfa(regex_str: &str) -> (StateId, Vec<State>) {
+        nfa_with_options(regex_str, &Options::new())
+    }
+
+    fn nfa_with_options(regex_str: &str, options: &Options) -> (StateId, Vec<State>) {
+        let nfa = parse_with_options(regex_str, options).unwrap();
+        (nfa.start(), nfa.states().to_vec())
+    }
+
+    /// The class the regex starts with
+    fn class_of(regex_str: &str) -> CharClass {
+        match nfa(regex_str).1.remove(0) {
+            Class(class, _) => class,
+            state => panic!("expected a class, got {:?}", state),
+        }
+    }
+
+    /// The kind of error the regex fails with and where it starts and ends
+    fn error_at(regex_str: &str) -> (ErrorKind, usize, usize) {
+        let error = parse(regex_str).unwrap_err();
+        (error.kind(), error.span().start, error.span().end)
+    }
 
     #[test]
     fn basic_concat() {
-        let correct = vec![
-            State {
-                id: 0,
-                machine_type: Literal('a'),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('b'),
-                branch: Finish,
-            },
-        ];
+        let correct = vec![Char('a', id(1)), Char('b', id(2)), Match];
 
-        assert_eq!(parse("ab").unwrap(), correct);
+        assert_eq!(nfa("ab"), (id(0), correct));
     }
     #[test]
     fn basic_kleen_closure() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Literal('a'),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Branching(StateId(3)),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Literal('b'),
-                branch: StateId(1),
-            },
-            State {
-                id: 3,
-                machine_type: Literal('c'),
-                branch: Finish,
-            },
+            Char('a', id(2)),
+            Char('b', id(2)),
+            Split(id(1), id(3)),
+            Char('c', id(4)),
+            Match,
         ];
 
-        assert_eq!(parse("ab*c").unwrap(), correct);
+        assert_eq!(nfa("ab*c"), (id(0), correct));
     }
     #[test]
     fn bracket() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Literal('a'),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('b'),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Literal('c'),
-                branch: Finish,
-            },
+            Save(2, id(1)),
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Save(3, id(4)),
+            Char('c', id(5)),
+            Match,
         ];
 
-        assert_eq!(parse("(ab)c").unwrap(), correct);
+        assert_eq!(nfa("(ab)c"), (id(0), correct));
     }
 
     #[test]
     fn bracket_kleene_closure() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Branching(StateId(3)),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('a'),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Literal('b'),
-                branch: StateId(0),
-            },
-            State {
-                id: 3,
-                machine_type: Literal('c'),
-                branch: Finish,
-            },
+            Save(2, id(1)),
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Save(3, id(4)),
+            Split(id(0), id(5)),
+            Char('c', id(6)),
+            Match,
         ];
 
-        assert_eq!(parse("(ab)*c").unwrap(), correct);
+        assert_eq!(nfa("(ab)*c"), (id(4), correct));
     }
     #[test]
     fn basic_plus() {
-        let corrct = vec![
-            State {
-                id: 0,
-                machine_type: Literal('a'),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Branching(Finish),
-                branch: StateId(0),
-            },
-        ];
-        assert_eq!(parse("a+").unwrap(), corrct);
+        let corrct = vec![Char('a', id(1)), Split(id(0), id(2)), Match];
+        assert_eq!(nfa("a+"), (id(0), corrct));
     }
     #[test]
     fn plus_bracket() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Literal('a'),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('b'),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Branching(StateId(3)),
-                branch: StateId(0),
-            },
-            State {
-                id: 3,
-                machine_type: Literal('c'),
-                branch: Finish,
-            },
+            Save(2, id(1)),
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Save(3, id(4)),
+            Split(id(0), id(5)),
+            Char('c', id(6)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(ab)+c"), (id(0), correct));
+    }
+    #[test]
+    fn basic_optional() {
+        let correct = vec![Char('a', id(2)), Split(id(0), id(2)), Match];
+        assert_eq!(nfa("a?"), (id(1), correct));
+    }
+    #[test]
+    fn optional_bracket() {
+        let correct = vec![
+            Save(2, id(1)),
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Save(3, id(5)),
+            Split(id(0), id(5)),
+            Char('c', id(6)),
+            Match,
         ];
 
-        assert_eq!(parse("(ab)+c").unwrap(), correct);
+        assert_eq!(nfa("(ab)?c"), (id(4), correct));
+    }
+    #[test]
+    fn optional_in_middle() {
+        let correct = vec![
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Split(id(1), id(3)),
+            Char('c', id(4)),
+            Match,
+        ];
+
+        assert_eq!(nfa("ab?c"), (id(0), correct));
+    }
+    #[test]
+    fn exact_repetition() {
+        let correct = vec![
+            Save(2, id(1)),
+            Char('a', id(2)),
+            Char('b', id(3)),
+            Save(3, id(4)),
+            Save(2, id(5)),
+            Char('a', id(6)),
+            Char('b', id(7)),
+            Save(3, id(8)),
+            Char('c', id(9)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(ab){2}c"), (id(0), correct));
+    }
+    #[test]
+    fn bounded_repetition() {
+        let correct = vec![
+            Char('a', id(4)),
+            Char('a', id(3)),
+            Char('a', id(5)),
+            Split(id(2), id(5)),
+            Split(id(1), id(5)),
+            Char('b', id(6)),
+            Match,
+        ];
+
+        assert_eq!(nfa("a{1,3}b"), (id(0), correct));
+    }
+    #[test]
+    fn unbounded_repetition() {
+        let correct = vec![
+            Char('a', id(2)),
+            Char('a', id(2)),
+            Split(id(1), id(3)),
+            Match,
+        ];
+
+        assert_eq!(nfa("a{1,}"), (id(0), correct));
+    }
+    #[test]
+    fn bad_repetition() {
+        assert!(parse("a{").is_err());
+        assert!(parse("a{x}").is_err());
+        assert!(parse("a{,2}").is_err());
+        assert!(parse("a{3,2}").is_err());
+        assert!(parse("a{2}{3}").is_err());
+        assert!(parse("a{1001}").is_err());
+        assert!(parse_with_options("a{3}", &Options::new().repetition_limit(2)).is_err());
+        assert!(parse_with_options("a{1,}", &Options::new().repetition_limit(u32::MAX)).is_ok());
+        assert!(parse_with_options("a{11}", &Options::new().size_limit(10)).is_err());
+        assert!(parse_with_options("a{10}", &Options::new().size_limit(10)).is_ok());
+    }
+    #[test]
+    fn deeply_nested_groups() {
+        let nested = |depth| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert!(parse(&nested(250)).is_ok());
+        assert_eq!(
+            error_at(&nested(3000)),
+            (ErrorKind::NestLimitExceeded, 250, 251)
+        );
+        assert!(parse_with_options("((a))", &Options::new().nest_limit(1)).is_err());
+        assert!(parse_with_options("(?i)(a)(?:b)", &Options::new().nest_limit(1)).is_ok());
     }
     #[test]
     fn basic_disjunction() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Branching(StateId(2)),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('a'),
-                branch: Finish,
-            },
-            State {
-                id: 2,
-                machine_type: Literal('b'),
-                branch: Finish,
-            },
+            Char('a', id(3)),
+            Char('b', id(3)),
+            Split(id(0), id(1)),
+            Match,
         ];
-        assert_eq!(parse("a|b").unwrap(), correct);
+
+        assert_eq!(nfa("a|b"), (id(2), correct));
     }
     #[test]
     fn grouped_disjunction() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Branching(StateId(3)),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Literal('a'),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Literal('b'),
-                branch: StateId(5),
-            },
-            State {
-                id: 3,
-                machine_type: Literal('b'),
-                branch: StateId(4),
-            },
-            State {
-                id: 4,
-                machine_type: Literal('c'),
-                branch: StateId(5),
-            },
-            State {
-                id: 5,
-                machine_type: Literal('d'),
-                branch: Finish,
-            },
+            Save(2, id(5)),
+            Char('a', id(2)),
+            Char('b', id(6)),
+            Char('b', id(4)),
+            Char('c', id(6)),
+            Split(id(1), id(3)),
+            Save(3, id(7)),
+            Char('d', id(8)),
+            Match,
         ];
-        assert_eq!(parse("(ab|bc)d").unwrap(), correct);
+
+        assert_eq!(nfa("(ab|bc)d"), (id(0), correct));
     }
     #[test]
     fn multiple_disjunction() {
         let correct = vec![
-            State {
-                id: 0,
-                machine_type: Branching(StateId(4)),
-                branch: StateId(1),
-            },
-            State {
-                id: 1,
-                machine_type: Branching(StateId(3)),
-                branch: StateId(2),
-            },
-            State {
-                id: 2,
-                machine_type: Literal('a'),
-                branch: StateId(5),
-            },
-            State {
-                id: 3,
-                machine_type: Literal('b'),
-                branch: StateId(5),
-            },
-            State {
-                id: 4,
-                machine_type: Literal('c'),
-                branch: StateId(5),
+            Save(2, id(5)),
+            Char('a', id(6)),
+            Char('b', id(6)),
+            Split(id(1), id(2)),
+            Char('c', id(6)),
+            Split(id(3), id(4)),
+            Save(3, id(7)),
+            Char('d', id(8)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(a|b|c)d"), (id(0), correct));
+    }
+    #[test]
+    fn bracket_class() {
+        let correct = vec![
+            Class(char_class(&[('0', '9'), ('_', '_'), ('a', 'z')]), id(1)),
+            Char('c', id(2)),
+            Match,
+        ];
+
+        assert_eq!(nfa("[a-z0-9_]c"), (id(0), correct));
+    }
+    #[test]
+    fn negated_class() {
+        let correct = vec![
+            Class(char_class(&[('\u{0}', '('), ('*', '\u{10FFFF}')]), id(1)),
+            Match,
+        ];
+
+        assert_eq!(nfa("[^)]"), (id(0), correct));
+    }
+    #[test]
+    fn any_char() {
+        let correct = vec![
+            Char('a', id(1)),
+            AnyChar {
+                matches_new_line: false,
+                next: id(2),
             },
-            State {
-                id: 5,
-                machine_type: Literal('d'),
-                branch: Finish,
+            Char('.', id(3)),
+            Match,
+        ];
+
+        assert_eq!(nfa("a.\\."), (id(0), correct));
+    }
+    #[test]
+    fn any_char_dot_all() {
+        let correct = vec![
+            AnyChar {
+                matches_new_line: true,
+                next: id(1),
             },
+            Match,
+        ];
+
+        assert_eq!(
+            nfa_with_options(".", &Options::new().dot_matches_new_line(true)),
+            (id(0), correct)
+        );
+    }
+    #[test]
+    fn anchors() {
+        let correct = vec![
+            Assertion(Look::StartText, id(1)),
+            Char('a', id(2)),
+            Assertion(Look::EndText, id(3)),
+            Assertion(Look::EndText, id(4)),
+            Match,
+        ];
+
+        assert_eq!(nfa("^a$\\z"), (id(0), correct));
+    }
+    #[test]
+    fn multi_line_anchors() {
+        let correct = vec![
+            Assertion(Look::StartText, id(1)),
+            Assertion(Look::StartLine, id(2)),
+            Assertion(Look::EndLine, id(3)),
+            Match,
         ];
 
-        assert_eq!(parse("(a|b|c)d").unwrap(), correct);
+        assert_eq!(
+            nfa_with_options("\\A^$", &Options::new().multi_line(true)),
+            (id(0), correct)
+        );
+    }
+    #[test]
+    fn word_boundaries() {
+        let looks = |regex_str: &str, options: &Options| {
+            nfa_with_options(regex_str, options)
+                .1
+                .into_iter()
+                .filter_map(|state| match state {
+                    Assertion(look, _) => Some(look),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            looks("\\b\\B", &Options::new()),
+            vec![Look::WordBoundary, Look::NotWordBoundary]
+        );
+        assert_eq!(
+            looks("\\b\\B", &Options::new().unicode(true)),
+            vec![Look::UnicodeWordBoundary, Look::NotUnicodeWordBoundary]
+        );
+
+        assert_eq!(parse("[\\b]").unwrap_err().kind(), ErrorKind::BadEscape);
+    }
+
+    #[test]
+    fn perl_classes() {
+        let correct = vec![
+            Class(char_class(&[('0', '9')]), id(1)),
+            Class(char_class(&[('\t', '\r'), (' ', ' ')]).negated(), id(2)),
+            Class(
+                char_class(&[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]),
+                id(3),
+            ),
+            Class(char_class(&[('\t', '\r'), (' ', '-'), ('0', '9')]), id(4)),
+            Match,
+        ];
+
+        assert_eq!(nfa("\\d\\S\\w[\\d\\s!-\\-]"), (id(0), correct));
+
+        let (_, states) = nfa_with_options("\\d", &Options::new().unicode(true));
+        assert_eq!(
+            states[0],
+            Class(CharClass::from_ranges(unicode::PERL_DIGIT), id(1))
+        );
+
+        assert_eq!(parse("a\\q").unwrap_err().span(), Span::new(1, 3));
+        assert_eq!(parse("[a\\é]").unwrap_err().span(), Span::new(2, 5));
+        assert_eq!(parse("\\1").unwrap_err().kind(), ErrorKind::BadEscape);
+    }
+
+    #[test]
+    fn code_point_escapes() {
+        let literals = |regex_str: &str| {
+            nfa(regex_str)
+                .1
+                .into_iter()
+                .filter_map(|state| match state {
+                    Char(c, _) => Some(c),
+                    _ => None,
+                })
+                .collect::<String>()
+        };
+
+        assert_eq!(literals("\\n\\t\\r\\a\\f\\v"), "\n\t\r\x07\x0C\x0B");
+        assert_eq!(
+            literals("\\x7F\\x{1F600}\\u00e9\\u{41}\\x410"),
+            "\x7F\u{1F600}éAA0"
+        );
+        assert_eq!(
+            nfa("[\\x41-\\u{5A}\\n]").1[0],
+            Class(char_class(&[('\n', '\n'), ('A', 'Z')]), id(1))
+        );
+    }
+
+    #[test]
+    fn bad_code_point_escapes() {
+        assert_eq!(error_at("a\\x4"), (ErrorKind::BadEscape, 1, 4));
+        assert_eq!(error_at("a\\u12g4"), (ErrorKind::BadEscape, 1, 5));
+        assert_eq!(error_at("\\x{}"), (ErrorKind::BadEscape, 0, 4));
+        assert_eq!(error_at("\\u{41"), (ErrorKind::BadEscape, 0, 5));
+        assert_eq!(error_at("\\x{123456789}"), (ErrorKind::BadEscape, 0, 13));
+        assert_eq!(
+            error_at("a\\u{D800}b"),
+            (ErrorKind::InvalidScalarValue, 1, 9)
+        );
+        assert_eq!(error_at("\\uDFFF"), (ErrorKind::InvalidScalarValue, 0, 6));
+        assert_eq!(
+            error_at("\\x{110000}"),
+            (ErrorKind::InvalidScalarValue, 0, 10)
+        );
+        assert_eq!(
+            error_at("[a\\x{D800}]"),
+            (ErrorKind::InvalidScalarValue, 2, 10)
+        );
+        assert_eq!(error_at("[\\A]"), (ErrorKind::BadEscape, 1, 3));
+        assert_eq!(error_at("[a-\\d]"), (ErrorKind::InvalidClassRange, 1, 5));
+    }
+
+    #[test]
+    fn bad_class() {
+        assert!(parse("[a-").is_err());
+        assert!(parse("[z-a]").is_err());
+        assert!(parse("a[b").is_err());
     }
     #[test]
     fn bad_bracketing() {
         assert!(parse("((a)").is_err())
     }
     #[test]
+    fn property_classes() {
+        let greek = class_of("\\p{Greek}");
+        assert!(greek.contains('λ') && greek.contains('Ω') && !greek.contains('a'));
+        assert_eq!(class_of("\\p{sc=Grek}"), greek);
+        assert_eq!(class_of("\\p{ script = greek }"), greek);
+        assert_eq!(class_of("\\P{Greek}"), greek.negated());
+
+        let letters = class_of("\\pL");
+        assert!(letters.contains('a') && letters.contains('ж') && !letters.contains('1'));
+        assert_eq!(class_of("\\p{Letter}"), letters);
+        assert_eq!(class_of("\\p{gc=L}"), letters);
+        assert_eq!(class_of("\\p{General_Category=letter}"), letters);
+
+        let upper_or_digit = class_of("[\\p{Lu}\\d]");
+        assert!(upper_or_digit.contains('Ж') && upper_or_digit.contains('7'));
+        assert!(!upper_or_digit.contains('ж'));
+
+        let not_letter = class_of("[^\\p{L}]");
+        assert_eq!(not_letter, letters.negated());
+    }
+
+    #[test]
+    fn bad_property_classes() {
+        assert_eq!(
+            error_at("a\\p{Klingon}b"),
+            (ErrorKind::UnknownProperty, 1, 12)
+        );
+        assert_eq!(error_at("\\p{sc=L}"), (ErrorKind::UnknownProperty, 0, 8));
+        assert_eq!(
+            error_at("\\p{foo=Greek}"),
+            (ErrorKind::UnknownProperty, 0, 13)
+        );
+        assert_eq!(error_at("\\pX"), (ErrorKind::UnknownProperty, 0, 3));
+        assert_eq!(error_at("\\p{}"), (ErrorKind::BadEscape, 0, 4));
+        assert_eq!(error_at("\\p{Greek"), (ErrorKind::BadEscape, 0, 8));
+        assert_eq!(error_at("a\\P"), (ErrorKind::BadEscape, 1, 3));
+    }
+
+    #[test]
+    fn posix_classes() {
+        let correct = vec![
+            Class(char_class(&[('A', 'Z'), ('_', '_'), ('a', 'z')]), id(1)),
+            Class(char_class(&[('0', '9')]).negated(), id(2)),
+            Class(
+                char_class(&[('\t', '\t'), (' ', ' '), ('0', '9')]).negated(),
+                id(3),
+            ),
+            Class(char_class(&[(':', ':'), ('[', '['), ('a', 'b')]), id(4)),
+            Match,
+        ];
+
+        assert_eq!(
+            nfa("[[:alpha:]_][[:^digit:]][^[:blank:][:digit:]][\\[:a-b:]"),
+            (id(0), correct)
+        );
+
+        let error = parse("a[[:alphabet:]]").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnknownPosixClass);
+        assert_eq!(error.span(), Span::new(2, 14));
+        assert_eq!(
+            parse("[a-[:digit:]]").unwrap_err().kind(),
+            ErrorKind::InvalidClassRange
+        );
+    }
+
+    #[test]
+    fn class_set_operations() {
+        assert_eq!(
+            class_of("[a-z&&[^aeiou]]"),
+            char_class(&[('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z')])
+        );
+        assert_eq!(class_of("[a-z--aeiou]"), class_of("[a-z&&[^aeiou]]"));
+        assert_eq!(
+            class_of("[a-m~~h-z]"),
+            char_class(&[('a', 'g'), ('n', 'z')])
+        );
+        assert_eq!(
+            class_of("[[a-c][x-z]]"),
+            char_class(&[('a', 'c'), ('x', 'z')])
+        );
+
+        /* Left to right with negation last */
+        assert_eq!(
+            class_of("[^a-z--d-z&&a-e]"),
+            char_class(&[('a', 'c')]).negated()
+        );
+
+        let greek_upper = class_of("[\\p{Greek}&&\\p{Lu}]");
+        assert!(greek_upper.contains('Ω') && !greek_upper.contains('ω'));
+        assert!(!greek_upper.contains('A'));
+
+        assert_eq!(class_of("[a&b]"), char_class(&[('&', '&'), ('a', 'b')]));
+        assert_eq!(class_of("[]&&\\]]"), char_class(&[(']', ']')]));
+
+        assert_eq!(
+            parse("[a-z&&[aeiou]").unwrap_err().kind(),
+            ErrorKind::UnclosedClass
+        );
+    }
+
+    #[test]
+    fn ast_with_spans() {
+        let ast = parse_ast("ab|(?i)x{2,}?", &Options::new()).unwrap();
+        let char_at = |c, start| Ast::new(AstKind::Char(c), Span::new(start, start + 1));
+
+        let repeated = Ast::new(
+            AstKind::Class(char_class(&[('X', 'X'), ('x', 'x')])),
+            Span::new(7, 8),
+        );
+        let correct = Ast::new(
+            AstKind::Alternation(vec![
+                Ast::new(
+                    AstKind::Concat(vec![char_at('a', 0), char_at('b', 1)]),
+                    Span::new(0, 2),
+                ),
+                Ast::new(
+                    AstKind::Repetition {
+                        repetition: Repetition::Range { min: 2, max: None },
+                        lazy: true,
+                        ast: Box::new(repeated),
+                    },
+                    Span::new(7, 13),
+                ),
+            ]),
+            Span::new(0, 13),
+        );
+
+        assert_eq!(ast, correct);
+
+        let ast = parse_ast("(?<n>)|", &Options::new()).unwrap();
+        let correct = Ast::new(
+            AstKind::Alternation(vec![
+                Ast::new(
+                    AstKind::Group {
+                        capture: Some(Capture {
+                            index: 1,
+                            name: Some("n".to_string()),
+                        }),
+                        ast: Box::new(Ast::new(AstKind::Empty, Span::new(5, 5))),
+                    },
+                    Span::new(0, 6),
+                ),
+                Ast::new(AstKind::Empty, Span::new(7, 7)),
+            ]),
+            Span::new(0, 7),
+        );
+
+        assert_eq!(ast, correct);
+        assert_eq!(
+            nfa::lower(&parse_ast("(a|b)c{1,3}", &Options::new()).unwrap(), 100),
+            Ok(parse("(a|b)c{1,3}").unwrap())
+        );
+    }
+
+    #[test]
+    fn error_kind_and_span() {
+        assert_eq!(error_at("a(b(c)"), (ErrorKind::UnclosedGroup, 1, 2));
+        assert_eq!(error_at("ab)"), (ErrorKind::UnopenedGroup, 2, 3));
+        assert_eq!(error_at("a+*"), (ErrorKind::DanglingQuantifier, 2, 3));
+        assert_eq!(error_at("a*{2}"), (ErrorKind::DanglingQuantifier, 2, 5));
+        assert_eq!(error_at("*a"), (ErrorKind::DanglingQuantifier, 0, 1));
+        assert_eq!(error_at("+"), (ErrorKind::DanglingQuantifier, 0, 1));
+        assert_eq!(error_at("a|*"), (ErrorKind::DanglingQuantifier, 2, 3));
+        assert_eq!(error_at("(*)"), (ErrorKind::DanglingQuantifier, 1, 2));
+        assert_eq!(error_at("{2}"), (ErrorKind::DanglingQuantifier, 0, 3));
+        assert_eq!(error_at("a(?i)?"), (ErrorKind::DanglingQuantifier, 5, 6));
+        assert_eq!(error_at("ab\\"), (ErrorKind::BadEscape, 2, 3));
+        assert_eq!(error_at("a[bc"), (ErrorKind::UnclosedClass, 1, 4));
+        assert_eq!(error_at("[ab-\\!]"), (ErrorKind::InvalidClassRange, 2, 6));
+        assert_eq!(error_at("a{2,x}"), (ErrorKind::InvalidRepetition, 1, 6));
+        assert_eq!(error_at("ab{2"), (ErrorKind::InvalidRepetition, 2, 4));
+        assert_eq!(error_at("a{3,2}b"), (ErrorKind::InvertedRepetition, 1, 6));
+        assert_eq!(
+            error_at("a{5000}"),
+            (ErrorKind::RepetitionLimitExceeded, 1, 7)
+        );
+        assert_eq!(
+            error_at("b(?:a{1000}){1000}"),
+            (ErrorKind::RepetitionLimitExceeded, 1, 18)
+        );
+        assert_eq!(
+            error_at("((a{1000}){1000}){1000}"),
+            (ErrorKind::RepetitionLimitExceeded, 1, 16)
+        );
+    }
+    #[test]
+    fn empty_alternatives_and_groups() {
+        let correct = vec![Char('a', id(3)), Epsilon(id(3)), Split(id(0), id(1)), Match];
+
+        assert_eq!(nfa("a|"), (id(2), correct));
+
+        let correct = vec![
+            Save(2, id(3)),
+            Epsilon(id(4)),
+            Char('b', id(4)),
+            Split(id(1), id(2)),
+            Save(3, id(5)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(|b)"), (id(0), correct));
+
+        let epsilon = (id(0), vec![Epsilon(id(1)), Match]);
+
+        assert_eq!(nfa(""), epsilon);
+        assert_eq!(nfa("a{0}"), epsilon);
+        assert_eq!(nfa("(?i)"), epsilon);
+        assert!(parse("a||b").is_ok());
+        assert!(parse("()*").is_ok());
+    }
+
+    #[test]
+    fn non_capturing_group() {
+        let correct = vec![
+            Char('a', id(1)),
+            Char('b', id(2)),
+            Split(id(0), id(3)),
+            Char('c', id(4)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(?:ab)*c"), (id(2), correct));
+
+        let (fsm, group_names) = parse_with_groups("(?:a)(b)", &Options::new()).unwrap();
+        assert_eq!(fsm.states()[1], Save(2, id(2)));
+        assert_eq!(group_names.len(), 2);
+    }
+
+    #[test]
+    fn inline_flags() {
+        let correct = vec![
+            Char('a', id(1)),
+            Class(char_class(&[('B', 'B'), ('b', 'b')]), id(2)),
+            Class(char_class(&[('0', '9'), ('C', 'D'), ('c', 'd')]), id(3)),
+            Char('1', id(4)),
+            AnyChar {
+                matches_new_line: true,
+                next: id(5),
+            },
+            Assertion(Look::StartLine, id(6)),
+            AnyChar {
+                matches_new_line: false,
+                next: id(7),
+            },
+            Match,
+        ];
+
+        assert_eq!(nfa("a(?i)b[c-d0-9]1(?sm:.^)(?-i)."), (id(0), correct));
+
+        let correct = vec![
+            Char('a', id(2)),
+            Char('b', id(2)),
+            Split(id(1), id(3)),
+            Char(' ', id(4)),
+            Match,
+        ];
+
+        assert_eq!(nfa("(?x) a b * # a comment\n \\ "), (id(0), correct));
+    }
+
+    #[test]
+    fn bad_flags() {
+        assert_eq!(error_at("a(?y)"), (ErrorKind::UnknownGroup, 1, 4));
+        assert_eq!(error_at("(?)"), (ErrorKind::UnknownGroup, 0, 3));
+        assert_eq!(error_at("(?iy)"), (ErrorKind::InvalidFlag, 3, 4));
+        assert_eq!(error_at("(?i-m-s)"), (ErrorKind::InvalidFlag, 5, 6));
+        assert_eq!(error_at("(?i-:a)"), (ErrorKind::InvalidFlag, 3, 4));
+        assert_eq!(error_at("a(?i"), (ErrorKind::UnclosedGroup, 1, 2));
+        assert_eq!(error_at("(?i)*"), (ErrorKind::DanglingQuantifier, 4, 5));
+        assert_eq!(error_at("(?x)a+ *"), (ErrorKind::DanglingQuantifier, 7, 8));
+        assert_eq!(
+            error_at("(?x)a+ #*\n*"),
+            (ErrorKind::DanglingQuantifier, 10, 11)
+        );
+        assert!(parse("(?x:a+) *").is_ok());
+    }
+
+    #[test]
+    fn named_group() {
+        let (fsm, group_names) =
+            parse_with_groups("(?P<a>x)(y)(?<b_2>z)", &Options::new()).unwrap();
+
+        assert_eq!(fsm.states()[0], Save(2, id(1)));
+        assert_eq!(fsm.states()[3], Save(4, id(4)));
+        assert_eq!(fsm.states()[6], Save(6, id(7)));
+        assert_eq!(group_names.len(), 4);
+        assert_eq!(group_names.index("a"), Some(1));
+        assert_eq!(group_names.index("b_2"), Some(3));
+        assert_eq!(group_names.index("c"), None);
+
+        assert_eq!(
+            parse("(?<a>x)|(?<a>y)").unwrap_err().span(),
+            Span::new(11, 12)
+        );
+        assert_eq!(
+            parse("(?P<1a>x)").unwrap_err().kind(),
+            ErrorKind::InvalidGroupName
+        );
+        assert_eq!(
+            parse("(?<>x)").unwrap_err().kind(),
+            ErrorKind::InvalidGroupName
+        );
+        assert_eq!(
+            parse("(?<a+x)").unwrap_err().kind(),
+            ErrorKind::InvalidGroupName
+        );
+        assert_eq!(parse("(?Ya)").unwrap_err().span(), Span::new(0, 3));
+    }
+    #[test]
     fn excess_quantifier() {
         assert!(parse("a+*").is_err());
+        assert!(parse("a?*").is_err());
+        assert!(parse("a*??").is_err());
+        assert!(parse("a+?*").is_err());
+        assert!(parse("a{2}?+").is_err());
+    }
+
+    #[test]
+    fn lazy_quantifiers() {
+        /* The same states as greedy ones with the branches of each split swapped */
+        let swapped = |regex_str: &str| {
+            let (start, states) = nfa(regex_str);
+            let states = states
+                .into_iter()
+                .map(|state| match state {
+                    Split(first, second) => Split(second, first),
+                    _ => state,
+                })
+                .collect::<Vec<_>>();
+            (start, states)
+        };
+
+        for (lazy, greedy) in &[
+            ("a*?b", "a*b"),
+            ("a+?b", "a+b"),
+            ("a??b", "a?b"),
+            ("(ab)*?c", "(ab)*c"),
+            ("a{1,3}?b", "a{1,3}b"),
+            ("a{2,}?b", "a{2,}b"),
+        ] {
+            assert_eq!(nfa(lazy), swapped(greedy), "{}", lazy);
+        }
+
+        assert_eq!(parse("a{2}?").unwrap(), parse("a{2}").unwrap());
     }
 }
@@ -0,0 +1,471 @@
+use crate::ast::{Ast, AstKind, Repetition};
+use crate::class::CharClass;
+use crate::error::Span;
+use crate::ndfa::Look;
+
+/// Where a state is in the nfa, states are never moved once added so an id stays the same for
+/// as long as the nfa is built
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StateId(u32);
+
+impl StateId {
+    pub fn new(index: u32) -> StateId {
+        StateId(index)
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Stands in for a transition that has not been patched yet
+const UNPATCHED: StateId = StateId(u32::MAX);
+
+/// A single state in an epsilon nfa, each goes on to the states it names
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum State {
+    /// Consumes the character
+    Char(char, StateId),
+    /// Consumes any character in the class
+    Class(CharClass, StateId),
+    /// Consumes any character, \n only when matches_new_line is set
+    AnyChar {
+        matches_new_line: bool,
+        next: StateId,
+    },
+    /// Consumes nothing, only lets the match carry on when the assertion holds
+    Assertion(Look, StateId),
+    /// Consumes nothing, records the position in the capture slot of this number. Group n starts
+    /// at slot 2n and ends at slot 2n + 1, group 0 being the whole match
+    Save(u32, StateId),
+    /// Consumes nothing and always carries on
+    Epsilon(StateId),
+    /// Carries on down both, the first being the one the regex prefers
+    Split(StateId, StateId),
+    /// The regex has matched
+    Match,
+}
+
+impl State {
+    /// Whether the state consumes the character, states that never consume one always say no
+    pub fn matches_char(&self, c: char) -> bool {
+        match self {
+            State::Char(l, _) => *l == c,
+            State::Class(class, _) => class.contains(c),
+            State::AnyChar {
+                matches_new_line, ..
+            } => *matches_new_line || c != '\n',
+            _ => false,
+        }
+    }
+
+    /// Where the state goes after consuming a character, None for states that never consume one
+    pub fn next_after_char(&self) -> Option<StateId> {
+        match *self {
+            State::Char(_, next) | State::Class(_, next) | State::AnyChar { next, .. } => {
+                Some(next)
+            }
+            _ => None,
+        }
+    }
+
+    /// Every transition out of the state so they can all be changed at once
+    fn targets_mut(&mut self) -> Vec<&mut StateId> {
+        match self {
+            State::Char(_, next)
+            | State::Class(_, next)
+            | State::AnyChar { next, .. }
+            | State::Assertion(_, next)
+            | State::Save(_, next)
+            | State::Epsilon(next) => vec![next],
+            State::Split(first, second) => vec![first, second],
+            State::Match => vec![],
+        }
+    }
+}
+
+/// An nfa built with thompson's construction, a regex matches when some path from the start
+/// reaches the match state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Nfa {
+    states: Vec<State>,
+    start: StateId,
+}
+
+impl Nfa {
+    pub fn start(&self) -> StateId {
+        self.start
+    }
+
+    pub fn state(&self, id: StateId) -> &State {
+        &self.states[id.index()]
+    }
+
+    /// Every state in the order they were added, the index of each being its id
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+}
+
+/// A transition out of a fragment that is left for whatever comes after the fragment to be
+/// patched into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hole {
+    /// The only transition out of a state or the first one out of a split
+    First(StateId),
+    /// The second transition out of a split
+    Second(StateId),
+}
+
+impl Hole {
+    fn id(self) -> StateId {
+        match self {
+            Hole::First(id) | Hole::Second(id) => id,
+        }
+    }
+}
+
+/// Part of an nfa still being built, which starts at start and leaves through its holes
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Fragment {
+    start: StateId,
+    holes: Vec<Hole>,
+}
+
+/// Builds an nfa a fragment at a time, a fragment is only ever added to or joined to others by
+/// patching its holes so no state ever needs renumbering
+#[derive(Debug)]
+struct Builder {
+    states: Vec<State>,
+    /// Most states a repetition may grow the nfa to
+    size_limit: usize,
+}
+
+impl Builder {
+    fn new(size_limit: usize) -> Builder {
+        Builder {
+            states: vec![],
+            size_limit,
+        }
+    }
+
+    fn next_id(&self) -> StateId {
+        StateId(self.states.len() as u32)
+    }
+
+    fn push(&mut self, state: State) -> StateId {
+        let id = self.next_id();
+        self.states.push(state);
+        id
+    }
+
+    fn patch(&mut self, holes: &[Hole], to: StateId) {
+        for &hole in holes {
+            match (hole, &mut self.states[hole.id().index()]) {
+                (Hole::Second(_), State::Split(_, second)) => *second = to,
+                (Hole::Second(_), _) => unreachable!("only splits have a second transition"),
+                (Hole::First(_), state) => *state.targets_mut()[0] = to,
+            }
+        }
+    }
+
+    /// A fragment of one state with one way out, the state's transition is left unpatched
+    fn single(&mut self, state: State) -> Fragment {
+        let id = self.push(state);
+
+        Fragment {
+            start: id,
+            holes: vec![Hole::First(id)],
+        }
+    }
+
+    fn char(&mut self, c: char) -> Fragment {
+        self.single(State::Char(c, UNPATCHED))
+    }
+
+    fn class(&mut self, class: CharClass) -> Fragment {
+        self.single(State::Class(class, UNPATCHED))
+    }
+
+    fn any_char(&mut self, matches_new_line: bool) -> Fragment {
+        self.single(State::AnyChar {
+            matches_new_line,
+            next: UNPATCHED,
+        })
+    }
+
+    fn assertion(&mut self, look: Look) -> Fragment {
+        self.single(State::Assertion(look, UNPATCHED))
+    }
+
+    fn save(&mut self, slot: u32) -> Fragment {
+        self.single(State::Save(slot, UNPATCHED))
+    }
+
+    fn epsilon(&mut self) -> Fragment {
+        self.single(State::Epsilon(UNPATCHED))
+    }
+
+    /// first then second
+    fn concat(&mut self, first: Fragment, second: Fragment) -> Fragment {
+        self.patch(&first.holes, second.start);
+
+        Fragment {
+            start: first.start,
+            holes: second.holes,
+        }
+    }
+
+    /// Either first or second, preferring first
+    fn alternate(&mut self, first: Fragment, second: Fragment) -> Fragment {
+        let split = self.push(State::Split(first.start, second.start));
+
+        let mut holes = first.holes;
+        holes.extend(second.holes);
+
+        Fragment {
+            start: split,
+            holes,
+        }
+    }
+
+    /// A split going on to repeat the fragment or skipping it through the split's hole, a lazy
+    /// split prefers skipping
+    fn quantifier_split(&mut self, repeated: StateId, lazy: bool) -> (StateId, Hole) {
+        let split = if lazy {
+            self.push(State::Split(UNPATCHED, repeated))
+        } else {
+            self.push(State::Split(repeated, UNPATCHED))
+        };
+
+        if lazy {
+            (split, Hole::First(split))
+        } else {
+            (split, Hole::Second(split))
+        }
+    }
+
+    /// Whether the fragment can be gone through without consuming a character, which is when
+    /// one of its holes is reached from its start through states that consume nothing
+    fn can_be_empty(&self, fragment: &Fragment) -> bool {
+        let mut visited = vec![false; self.states.len()];
+        let mut stack = vec![fragment.start];
+
+        while let Some(id) = stack.pop() {
+            if id == UNPATCHED {
+                return true;
+            }
+            if std::mem::replace(&mut visited[id.index()], true) {
+                continue;
+            }
+
+            match self.states[id.index()] {
+                State::Split(first, second) => stack.extend(&[second, first]),
+                State::Epsilon(next) | State::Save(_, next) | State::Assertion(_, next) => {
+                    stack.push(next)
+                }
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    /// The fragment any number of times including none
+    ///
+    /// A fragment that can match nothing is built as (?:x+)? instead. Going through it without
+    /// consuming anything would lead straight back to the split in front of it, which has already
+    /// been followed so the path would be dropped, losing an empty match the regex prefers and
+    /// any groups set along the way
+    fn star(&mut self, fragment: Fragment, lazy: bool) -> Fragment {
+        if self.can_be_empty(&fragment) {
+            let fragment = self.plus(fragment, lazy);
+            return self.question(fragment, lazy);
+        }
+
+        let (split, hole) = self.quantifier_split(fragment.start, lazy);
+        self.patch(&fragment.holes, split);
+
+        Fragment {
+            start: split,
+            holes: vec![hole],
+        }
+    }
+
+    /// The fragment at least once
+    fn plus(&mut self, fragment: Fragment, lazy: bool) -> Fragment {
+        let (split, hole) = self.quantifier_split(fragment.start, lazy);
+        self.patch(&fragment.holes, split);
+
+        Fragment {
+            start: fragment.start,
+            holes: vec![hole],
+        }
+    }
+
+    /// The fragment once or not at all
+    fn question(&mut self, fragment: Fragment, lazy: bool) -> Fragment {
+        let (split, hole) = self.quantifier_split(fragment.start, lazy);
+
+        let mut holes = fragment.holes;
+        holes.push(hole);
+
+        Fragment {
+            start: split,
+            holes,
+        }
+    }
+
+    /// What compile builds from min to max times, or any number above min without a max. Each
+    /// repeat is built by calling compile again so it gets states of its own, a max of 0 never
+    /// calls it
+    ///
+    /// # Errors
+    /// The span when the copies would take the nfa past the size limit, which is known once the
+    /// first copy is built. Any error from compile is passed on
+    fn repeat<F>(
+        &mut self,
+        min: u32,
+        max: Option<u32>,
+        lazy: bool,
+        span: Span,
+        mut compile: F,
+    ) -> Result<Fragment, Span>
+    where
+        F: FnMut(&mut Builder) -> Result<Fragment, Span>,
+    {
+        if max == Some(0) {
+            return Ok(self.epsilon());
+        }
+
+        let copies_len = max.map_or(u64::from(min) + 1, u64::from);
+        let states_before = self.states.len();
+        let mut copies = vec![compile(self)?];
+
+        /* Every copy is as big as the first, along with a split for each optional one */
+        let copy_len = (self.states.len() - states_before) as u64;
+        let repeat_len = copies_len.saturating_mul(copy_len) + (copies_len - u64::from(min));
+        if repeat_len > self.size_limit.saturating_sub(states_before) as u64 {
+            return Err(span);
+        }
+        while (copies.len() as u64) < copies_len {
+            copies.push(compile(self)?);
+        }
+
+        let optional_copies = copies.split_off(min as usize);
+        let optional = match max {
+            /* Each optional copy is nested in the one before so skipping one skips the rest */
+            Some(_) => optional_copies.into_iter().rev().fold(None, |inner, copy| {
+                let copy = match inner {
+                    Some(inner) => self.concat(copy, inner),
+                    None => copy,
+                };
+                Some(self.question(copy, lazy))
+            }),
+            None => optional_copies
+                .into_iter()
+                .next()
+                .map(|copy| self.star(copy, lazy)),
+        };
+
+        let fragment = copies
+            .into_iter()
+            .chain(optional)
+            .reduce(|first, second| self.concat(first, second))
+            .expect("a repeat with a max above 0 has a copy");
+
+        Ok(fragment)
+    }
+
+    /// Ends the fragment with the match state, giving back the finished nfa
+    fn finish(mut self, fragment: Fragment) -> Nfa {
+        let match_id = self.push(State::Match);
+        self.patch(&fragment.holes, match_id);
+
+        Nfa {
+            states: self.states,
+            start: fragment.start,
+        }
+    }
+}
+
+/// Lowers a parsed regex into an nfa, counted repetitions may not grow it past size_limit states
+///
+/// # Errors
+/// The span of the counted repetition that would grow the nfa past the size limit
+pub fn lower(ast: &Ast, size_limit: usize) -> Result<Nfa, Span> {
+    let mut builder = Builder::new(size_limit);
+    let fragment = lower_fragment(ast, &mut builder)?;
+
+    Ok(builder.finish(fragment))
+}
+
+fn lower_fragment(ast: &Ast, builder: &mut Builder) -> Result<Fragment, Span> {
+    let fragment = match &ast.kind {
+        AstKind::Empty => builder.epsilon(),
+        AstKind::Char(c) => builder.char(*c),
+        AstKind::Class(class) => builder.class(class.clone()),
+        AstKind::AnyChar { matches_new_line } => builder.any_char(*matches_new_line),
+        AstKind::Assertion(look) => builder.assertion(*look),
+        AstKind::Group {
+            capture: Some(capture),
+            ast,
+        } => {
+            /* The group is wrapped in states saving where it starts and ends */
+            let group_start = builder.save(capture.index * 2);
+            let fragment = lower_fragment(ast, builder)?;
+            let group_end = builder.save(capture.index * 2 + 1);
+
+            let fragment = builder.concat(group_start, fragment);
+            builder.concat(fragment, group_end)
+        }
+        AstKind::Group { capture: None, ast } => lower_fragment(ast, builder)?,
+        AstKind::Repetition {
+            repetition,
+            lazy,
+            ast: repeated,
+        } => match *repetition {
+            Repetition::ZeroOrMore => {
+                let fragment = lower_fragment(repeated, builder)?;
+                builder.star(fragment, *lazy)
+            }
+            Repetition::OneOrMore => {
+                let fragment = lower_fragment(repeated, builder)?;
+                builder.plus(fragment, *lazy)
+            }
+            Repetition::ZeroOrOne => {
+                let fragment = lower_fragment(repeated, builder)?;
+                builder.question(fragment, *lazy)
+            }
+            Repetition::Range { min, max } => {
+                builder.repeat(min, max, *lazy, ast.span, |builder| {
+                    lower_fragment(repeated, builder)
+                })?
+            }
+        },
+        AstKind::Concat(asts) => lower_each(asts, builder, Builder::concat)?,
+        AstKind::Alternation(asts) => lower_each(asts, builder, Builder::alternate)?,
+    };
+
+    Ok(fragment)
+}
+
+/// Lowers each ast, joining each to those before it as soon as it is lowered so the states come
+/// out in the same order as the regex
+fn lower_each(
+    asts: &[Ast],
+    builder: &mut Builder,
+    join: fn(&mut Builder, Fragment, Fragment) -> Fragment,
+) -> Result<Fragment, Span> {
+    let mut looped_fragment: Option<Fragment> = None;
+
+    for ast in asts {
+        let fragment = lower_fragment(ast, builder)?;
+        looped_fragment = Some(match looped_fragment {
+            Some(before) => join(builder, before, fragment),
+            None => fragment,
+        });
+    }
+
+    Ok(looped_fragment.unwrap_or_else(|| builder.epsilon()))
+}
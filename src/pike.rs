@@ -0,0 +1,279 @@
+use crate::error::Error;
+use crate::ndfa::*;
+use crate::nfa::State as NFAState;
+use crate::nfa::{Nfa, StateId};
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Runs the nfa directly rather than through a dfa, every path through the nfa is a thread
+/// carrying the positions its capture groups matched at. This is slower than the dfa but unlike
+/// it can tell where each group matched
+#[derive(Debug)]
+pub struct PikeVm {
+    nfa: Nfa,
+    /// Shared with every `Captures` found so they can look groups up by name
+    group_names: Rc<GroupNames>,
+}
+
+/// Where each capture group starts and ends, group n starts at slot 2n and ends at slot 2n + 1
+pub type Slots = Vec<Option<usize>>;
+
+/// Position in the searched line along with the kinds of character either side for assertions
+#[derive(Clone, Copy, Debug)]
+struct Position {
+    index: usize,
+    before: CharKind,
+    after: CharKind,
+}
+
+/// Threads waiting at one position in priority order. A state already holding a thread is not
+/// given another as the one there first came from a path the regex prefers
+#[derive(Debug, Default)]
+struct Threads {
+    list: Vec<(StateId, Slots)>,
+    visited: HashSet<StateId>,
+}
+
+/// Work left to do while following states in `add_thread`, kept on a stack of its own rather
+/// than the call stack as a pattern can chain together any number of states that consume nothing
+#[derive(Debug)]
+enum Frame {
+    /// Follow the state with the slots as they are now
+    Explore(StateId),
+    /// Put a slot back as it was before a save state changed it, once everything after that
+    /// save state has been followed
+    Restore(u32, Option<usize>),
+}
+
+/// Parses the regex and builds the state machine used to find capture groups
+///
+/// # Errors
+/// Any error the regex parser reports is passed straight back to the caller
+pub fn create(regex_str: &str) -> Result<PikeVm, Error> {
+    create_with_options(regex_str, &Options::new())
+}
+
+/// Same as `create` but the regex is compiled with the given options
+///
+/// # Errors
+/// The same as `create`
+pub fn create_with_options(regex_str: &str, options: &Options) -> Result<PikeVm, Error> {
+    let (nfa, group_names) = parse_with_groups(regex_str, options)?;
+
+    Ok(PikeVm {
+        nfa,
+        group_names: Rc::new(group_names),
+    })
+}
+
+impl PikeVm {
+    /// Number of capture groups including group 0 for the whole match
+    pub fn group_count(&self) -> usize {
+        self.group_names.len()
+    }
+
+    /// The name of each capture group in number order, None for a group without one
+    pub fn group_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.group_names.iter()
+    }
+
+    pub(crate) fn shared_group_names(&self) -> Rc<GroupNames> {
+        Rc::clone(&self.group_names)
+    }
+
+    /// Finds the leftmost match in the line starting at or after the given byte index and
+    /// returns the slots of every group, a group that took no part in the match has None
+    pub fn search(&self, searched_line: &str, from: usize) -> Option<Slots> {
+        let mut current = Threads::default();
+        let mut matched: Option<Slots> = None;
+        let mut index = from;
+        let mut before = searched_line[..from].chars().next_back();
+
+        loop {
+            let next = searched_line[index..].chars().next();
+
+            /* A thread starting here has a lower priority than any that started earlier */
+            if matched.is_none() {
+                let mut slots = vec![None; self.group_count() * 2];
+                slots[0] = Some(index);
+
+                let position = Position {
+                    index,
+                    before: CharKind::of(before),
+                    after: CharKind::of(next),
+                };
+                self.add_thread(&mut current, self.nfa.start(), slots, position);
+            }
+
+            let mut following = Threads::default();
+
+            for (id, mut slots) in current.list.drain(..) {
+                let state = self.nfa.state(id);
+
+                /* Every thread after this one has a lower priority than the match */
+                if *state == NFAState::Match {
+                    slots[1] = Some(index);
+                    matched = Some(slots);
+                    break;
+                }
+
+                if let Some(c) = next.filter(|&c| state.matches_char(c)) {
+                    let next_index = index + c.len_utf8();
+                    let position = Position {
+                        index: next_index,
+                        before: CharKind::of(Some(c)),
+                        after: CharKind::of(searched_line[next_index..].chars().next()),
+                    };
+                    let next_id = state
+                        .next_after_char()
+                        .expect("a state that consumed a character goes somewhere after it");
+                    self.add_thread(&mut following, next_id, slots, position);
+                }
+            }
+
+            match next {
+                Some(c) if matched.is_none() || !following.list.is_empty() => {
+                    index += c.len_utf8();
+                    before = Some(c);
+                    current = following;
+                }
+                _ => return matched,
+            }
+        }
+    }
+
+    /// Follows split, epsilon, assertion and save states from the state until states that
+    /// consume a character or the match state are reached, adding a thread for each. The first
+    /// branch of a split is followed first so threads are added in priority order
+    fn add_thread(&self, threads: &mut Threads, id: StateId, mut slots: Slots, at: Position) {
+        let mut stack = vec![Frame::Explore(id)];
+
+        while let Some(frame) = stack.pop() {
+            let id = match frame {
+                Frame::Explore(id) => id,
+                Frame::Restore(slot, value) => {
+                    slots[slot as usize] = value;
+                    continue;
+                }
+            };
+
+            if !threads.visited.insert(id) {
+                continue;
+            }
+
+            match *self.nfa.state(id) {
+                NFAState::Split(first, second) => {
+                    stack.push(Frame::Explore(second));
+                    stack.push(Frame::Explore(first));
+                }
+                NFAState::Assertion(look, next) => {
+                    if look.holds(at.before, at.after) {
+                        stack.push(Frame::Explore(next));
+                    }
+                }
+                NFAState::Epsilon(next) => stack.push(Frame::Explore(next)),
+                NFAState::Save(slot, next) => {
+                    stack.push(Frame::Restore(slot, slots[slot as usize]));
+                    slots[slot as usize] = Some(at.index);
+                    stack.push(Frame::Explore(next));
+                }
+                _ => threads.list.push((id, slots.clone())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn slots_of_each_group() {
+        let vm = create("(a+)(b*)c").unwrap();
+
+        assert_eq!(vm.group_count(), 3);
+        assert_eq!(
+            vm.search("xaabbc", 0),
+            Some(vec![Some(1), Some(6), Some(1), Some(3), Some(3), Some(5)])
+        );
+        assert_eq!(
+            vm.search("xaabbc", 2),
+            Some(vec![Some(2), Some(6), Some(2), Some(3), Some(3), Some(5)])
+        );
+        assert_eq!(vm.search("aabb", 0), None);
+    }
+
+    #[test]
+    fn group_not_taking_part() {
+        let vm = create("(a)|(b)").unwrap();
+
+        assert_eq!(
+            vm.search("b", 0),
+            Some(vec![Some(0), Some(1), None, None, Some(0), Some(1)])
+        );
+    }
+
+    #[test]
+    fn repeated_group_keeps_last() {
+        let vm = create("(ab)+").unwrap();
+        assert_eq!(
+            vm.search("xababy", 0),
+            Some(vec![Some(1), Some(5), Some(3), Some(5)])
+        );
+
+        let vm = create("([0-9]){2}").unwrap();
+        assert_eq!(
+            vm.search("123", 0),
+            Some(vec![Some(0), Some(2), Some(1), Some(2)])
+        );
+    }
+
+    #[test]
+    fn named_groups() {
+        let vm = create("(?P<year>[0-9]{4})-([0-9]{2})-(?<day>[0-9]{2})").unwrap();
+
+        assert_eq!(vm.group_count(), 4);
+        assert_eq!(
+            vm.group_names().collect::<Vec<_>>(),
+            vec![None, Some("year"), None, Some("day")]
+        );
+        assert_eq!(
+            vm.search("2020-07-03", 0),
+            Some(vec![
+                Some(0),
+                Some(10),
+                Some(0),
+                Some(4),
+                Some(5),
+                Some(7),
+                Some(8),
+                Some(10)
+            ])
+        );
+    }
+
+    #[test]
+    fn same_match_as_dfa() {
+        let vm = create("a|ab").unwrap();
+        assert_eq!(vm.search("ab", 0), Some(vec![Some(0), Some(1)]));
+
+        let vm = create("a*").unwrap();
+        assert_eq!(vm.search("baa", 0), Some(vec![Some(0), Some(0)]));
+
+        let vm = create("ab$").unwrap();
+        assert_eq!(vm.search("abab", 0), Some(vec![Some(2), Some(4)]));
+    }
+
+    #[test]
+    fn long_chain_of_empty_states() {
+        let vm = create("(?:(?:a?){1000}){45}").unwrap();
+        assert_eq!(vm.search("baa", 0), Some(vec![Some(0), Some(0)]));
+
+        let vm = create("(?:(?:){1000}){20}(a)").unwrap();
+        assert_eq!(
+            vm.search("ba", 0),
+            Some(vec![Some(1), Some(2), Some(1), Some(2)])
+        );
+    }
+}
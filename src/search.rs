@@ -1,50 +1,647 @@
-use crate::dfa::State;
-use crate::dfa::Transition;
-use std::collections::HashMap;
+use crate::dfa::Dfa;
+use crate::ndfa::{CharKind, GroupNames};
+use crate::pike::{PikeVm, Slots};
+
+use std::rc::Rc;
+
+/// Where a match was found, as byte offsets into the searched string with the end exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<'h> {
+	haystack: &'h str,
+	start: usize,
+	end: usize,
+}
+
+impl<'h> Match<'h> {
+	fn new(haystack: &'h str, start: usize, end: usize) -> Match<'h> {
+		Match {
+			haystack,
+			start,
+			end,
+		}
+	}
+
+	pub fn start(&self) -> usize {
+		self.start
+	}
+
+	pub fn end(&self) -> usize {
+		self.end
+	}
+
+	pub fn range(&self) -> std::ops::Range<usize> {
+		self.start..self.end
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.start == self.end
+	}
+
+	pub fn len(&self) -> usize {
+		self.end - self.start
+	}
+
+	/// The matched text, borrowed from the searched string
+	pub fn as_str(&self) -> &'h str {
+		&self.haystack[self.start..self.end]
+	}
+}
+
+/// Where the match and each of the capture groups in it were found, group 0 is the whole match
+/// and the rest are numbered by the order their ( appears in the regex
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures<'h> {
+	haystack: &'h str,
+	slots: Slots,
+	group_names: Rc<GroupNames>,
+}
+
+impl<'h> Captures<'h> {
+	/// The group numbered i, None when there is no such group or it took no part in the match
+	pub fn get(&self, i: usize) -> Option<Match<'h>> {
+		let start = (*self.slots.get(i * 2)?)?;
+		let end = (*self.slots.get(i * 2 + 1)?)?;
+
+		Some(Match::new(self.haystack, start, end))
+	}
+
+	/// The group with this name, None when there is no such group or it took no part in the match
+	pub fn name(&self, name: &str) -> Option<Match<'h>> {
+		self.get(self.group_names.index(name)?)
+	}
+
+	/// The name of each group in number order, None for a group without one
+	pub fn group_names(&self) -> impl Iterator<Item = Option<&str>> {
+		self.group_names.iter()
+	}
+
+	/// Number of groups including group 0, whether or not they took part in the match
+	pub fn len(&self) -> usize {
+		self.group_names.len()
+	}
+
+	/// The same as `GroupNames::is_empty`
+	pub fn is_empty(&self) -> bool {
+		self.group_names.is_empty()
+	}
+}
+
+/// Finds the leftmost match on each line as grep would, the offsets of each match are into the
+/// whole of searched_str rather than the line it was found on. Each line is searched as a text
+/// of its own, so \A and \z match at the start and end of every line just as ^ and $ do and no
+/// match ever crosses a line. `find`, `find_iter` and `captures` search the whole text instead
+pub fn find_matching<'h>(searched_str: &'h str, dfsm: &Dfa) -> Vec<Match<'h>> {
+	let mut line_start = 0;
 
-pub fn find_matching(searched_str: &str, dfsm: HashMap<u32, State>) -> Vec<String> {
 	searched_str
 		.split('\n')
-		.filter_map(|x| find_line(x, &dfsm))
+		.filter_map(|line| {
+			let found = find_from(line, 0, dfsm)
+				.map(|m| Match::new(searched_str, line_start + m.start, line_start + m.end));
+			line_start += line.len() + 1;
+			found
+		})
 		.collect()
 }
 
-fn find_line(searched_line: &str, dfsm: &HashMap<u32, State>) -> Option<String> {
-	let mut search_start_index = 0;
+/// Finds the leftmost match in the whole of searched_str, which is searched as one text so ^ and
+/// $ only match at the start and end of it unless the multi line flag is set
+pub fn find<'h>(searched_str: &'h str, dfsm: &Dfa) -> Option<Match<'h>> {
+	find_from(searched_str, 0, dfsm)
+}
+
+/// Finds the leftmost match in the whole of searched_str as `find` would along with where each
+/// capture group in it matched
+pub fn captures<'h>(searched_str: &'h str, vm: &PikeVm) -> Option<Captures<'h>> {
+	let slots = vm.search(searched_str, 0)?;
 
-	'search_loop: loop {
-		let searched_chars = searched_line.chars().skip(search_start_index);
+	Some(Captures {
+		haystack: searched_str,
+		slots,
+		group_names: vm.shared_group_names(),
+	})
+}
 
-		let mut next_id = 0;
-		let mut found_string = String::new();
+/// Finds every match in the whole of searched_str that does not overlap another, from left to
+/// right
+pub fn find_iter<'h, 'd>(searched_str: &'h str, dfsm: &'d Dfa) -> Matches<'h, 'd> {
+	Matches {
+		searched_str,
+		dfsm,
+		search_start: Some(0),
+		last_match_end: None,
+	}
+}
 
-		for s in searched_chars {
-			let current_state = dfsm.get(&next_id).unwrap();
+/// Iterator over the matches in a string, created by `find_iter`
+#[derive(Debug)]
+pub struct Matches<'h, 'd> {
+	searched_str: &'h str,
+	dfsm: &'d Dfa,
+	/// Where the next search starts from, None once the end of the string has been searched
+	search_start: Option<usize>,
+	last_match_end: Option<usize>,
+}
 
-			if current_state.looping_chars.contains(&s) {
-				found_string.push(s);
-				continue;
-			}
+impl<'h, 'd> Iterator for Matches<'h, 'd> {
+	type Item = Match<'h>;
 
-			match &current_state.tran {
-				Transition::Finish => {
-					return Some(found_string);
+	fn next(&mut self) -> Option<Match<'h>> {
+		loop {
+			let found = find_from(self.searched_str, self.search_start?, self.dfsm);
+			let found = match found {
+				Some(found) => found,
+				None => {
+					self.search_start = None;
+					return None;
 				}
-				Transition::NextStates(ns) => match ns.iter().find(|x| x.matched == s) {
-					Some(next_tran) => {
-						next_id = next_tran.id;
-						found_string.push(s);
-					}
-					None => {
-						search_start_index += 1;
-						continue 'search_loop;
-					}
-				},
+			};
+
+			/*
+			 * An empty match straight after the last match would let the search stand still, so
+			 * the search moves on a character and tries again
+			 */
+			if found.is_empty() && self.last_match_end == Some(found.end) {
+				self.search_start = self.searched_str[found.end..]
+					.chars()
+					.next()
+					.map(|c| found.end + c.len_utf8());
+				continue;
 			}
+
+			self.search_start = Some(found.end);
+			self.last_match_end = Some(found.end);
+			return Some(found);
+		}
+	}
+}
+
+/// Finds the leftmost match at or after the given byte index, trying each start position in turn
+fn find_from<'h>(searched_str: &'h str, from: usize, dfsm: &Dfa) -> Option<Match<'h>> {
+	let mut search_start_index = from;
+
+	loop {
+		if let Some(end) = find_at(searched_str, search_start_index, dfsm) {
+			return Some(Match::new(searched_str, search_start_index, end));
+		}
+
+		match searched_str[search_start_index..].chars().next() {
+			Some(c) => search_start_index += c.len_utf8(),
+			None => return None,
+		}
+	}
+}
+
+/// Runs the dfa from the given byte index until no transition is left and returns the end of the
+/// last match seen, which the dfa's priority ordering makes the one the regex prefers.
+/// The characters either side of each position are passed on so anchors can be checked
+fn find_at(searched_str: &str, search_start_index: usize, dfsm: &Dfa) -> Option<usize> {
+	let before = CharKind::of(searched_str[..search_start_index].chars().next_back());
+
+	let mut current_state = dfsm.start_state(before);
+	let mut found_end = None;
+
+	for (i, s) in searched_str[search_start_index..].char_indices() {
+		if current_state.accepts(CharKind::of(Some(s))) {
+			found_end = Some(search_start_index + i);
 		}
-		match dfsm.get(&next_id).unwrap().tran {
-			Transition::Finish => return Some(found_string),
-			_ => return None,
+
+		match current_state.next_id(s) {
+			Some(id) => current_state = dfsm.state(id),
+			None => return found_end,
 		}
 	}
+
+	if current_state.accepts(CharKind::Edge) {
+		found_end = Some(searched_str.len());
+	}
+
+	found_end
+}
+
+#[cfg(test)]
+mod test_super {
+	use super::*;
+	use crate::dfa::{create, create_with_options};
+	use crate::ndfa::Options;
+	use crate::pike;
+
+	fn find_per_line<'h>(regex_str: &str, searched_str: &'h str) -> Vec<&'h str> {
+		find_matching(searched_str, &create(regex_str).unwrap())
+			.iter()
+			.map(Match::as_str)
+			.collect()
+	}
+
+	#[test]
+	fn unanchored_search() {
+		assert_eq!(find_per_line("ab*d", "aaaabd\nacd\nad"), vec!["abd", "ad"]);
+	}
+
+	#[test]
+	fn match_offsets_into_searched_str() {
+		let searched_str = "xaby\nλab";
+		let matches = find_matching(searched_str, &create("ab").unwrap());
+
+		assert_eq!(
+			matches.iter().map(Match::range).collect::<Vec<_>>(),
+			vec![1..3, 7..9]
+		);
+		assert_eq!(&searched_str[matches[1].range()], "ab");
+		assert_eq!(matches[1].as_str(), "ab");
+
+		let matches = find_matching("a\n", &create("b*").unwrap());
+		assert_eq!(
+			matches.iter().map(Match::range).collect::<Vec<_>>(),
+			vec![0..0, 2..2]
+		);
+		assert!(matches[0].is_empty());
+	}
+
+	fn find_all<'h>(regex_str: &str, searched_str: &'h str) -> Vec<(usize, &'h str)> {
+		find_iter(searched_str, &create(regex_str).unwrap())
+			.map(|m| (m.start(), m.as_str()))
+			.collect()
+	}
+
+	#[test]
+	fn every_match() {
+		assert_eq!(
+			find_all("ab+", "abxabb\nzab"),
+			vec![(0, "ab"), (3, "abb"), (8, "ab")]
+		);
+		assert_eq!(find_all("aa", "aaaaa"), vec![(0, "aa"), (2, "aa")]);
+		assert_eq!(find_all("^a", "aa\na"), vec![(0, "a")]);
+		assert_eq!(find_all("(?m)^a", "aa\na"), vec![(0, "a"), (3, "a")]);
+		assert_eq!(find_all("x", "abc"), vec![]);
+	}
+
+	#[test]
+	fn every_match_empty() {
+		assert_eq!(find_all("a*", "baaac"), vec![(0, ""), (1, "aaa"), (5, "")]);
+		assert_eq!(find_all("b*", "λ\n"), vec![(0, ""), (2, ""), (3, "")]);
+		assert_eq!(find_all("a?", "ab"), vec![(0, "a"), (2, "")]);
+		assert_eq!(find_all("$", "a\nb"), vec![(3, "")]);
+		assert_eq!(find_all("(?m)$", "a\nb"), vec![(1, ""), (3, "")]);
+	}
+
+	fn groups<'h>(regex_str: &str, searched_str: &'h str) -> Vec<Option<(usize, &'h str)>> {
+		let caps = captures(searched_str, &pike::create(regex_str).unwrap()).unwrap();
+
+		(0..caps.len())
+			.map(|i| caps.get(i).map(|m| (m.start(), m.as_str())))
+			.collect()
+	}
+
+	#[test]
+	fn capture_groups() {
+		assert_eq!(
+			groups("(a+)(b*)c", "xaabbc"),
+			vec![Some((1, "aabbc")), Some((1, "aa")), Some((3, "bb"))]
+		);
+		assert_eq!(
+			groups("((a)b)c", "abc"),
+			vec![Some((0, "abc")), Some((0, "ab")), Some((0, "a"))]
+		);
+		assert_eq!(groups("a(b)?c", "ac"), vec![Some((0, "ac")), None]);
+		assert_eq!(
+			groups("(x)|(y)", "zz\nzy"),
+			vec![Some((4, "y")), None, Some((4, "y"))]
+		);
+		assert_eq!(
+			groups("(ab)*c", "ababc"),
+			vec![Some((0, "ababc")), Some((2, "ab"))]
+		);
+
+		let caps = captures("ab", &pike::create("(a)b").unwrap()).unwrap();
+		assert_eq!(caps.get(2), None);
+		assert!(captures("ab", &pike::create("(c)").unwrap()).is_none());
+	}
+
+	#[test]
+	fn named_capture_groups() {
+		let searched_str = "level=warn\nlevel=info msg=started";
+		let vm = pike::create("level=(?P<level>[a-z]+) msg=(?<msg>[a-z]+)").unwrap();
+		let caps = captures(searched_str, &vm).unwrap();
+
+		assert_eq!(caps.name("level").map(|m| m.as_str()), Some("info"));
+		assert_eq!(caps.name("msg").map(|m| m.range()), Some(26..33));
+		assert_eq!(caps.name("time"), None);
+		assert_eq!(
+			caps.group_names().collect::<Vec<_>>(),
+			vec![None, Some("level"), Some("msg")]
+		);
+
+		let caps = captures("ac", &pike::create("a(?<b>b)?c").unwrap()).unwrap();
+		assert_eq!(caps.name("b"), None);
+	}
+
+	#[test]
+	fn non_capturing_groups() {
+		assert_eq!(
+			groups("(?:ab)+(c)", "xababc"),
+			vec![Some((1, "ababc")), Some((5, "c"))]
+		);
+		assert_eq!(
+			groups("(?:(?:a|b)(c))*", "acbc"),
+			vec![Some((0, "acbc")), Some((3, "c"))]
+		);
+		assert_eq!(find_per_line("(?:a|b){2}", "xbay"), vec!["ba"]);
+	}
+
+	#[test]
+	fn group_in_later_alternative() {
+		assert_eq!(find_per_line("a|(b)+", "bbb"), vec!["bbb"]);
+		assert_eq!(find_per_line("a|b|(c)*d", "ccd"), vec!["ccd"]);
+		assert_eq!(
+			groups("a|(b)+", "bbb"),
+			vec![Some((0, "bbb")), Some((2, "b"))]
+		);
+	}
+
+	#[test]
+	fn bracket_class() {
+		assert_eq!(find_per_line("[a-c0-9_]+", "xx_b9y"), vec!["_b9"]);
+		assert_eq!(find_per_line("[^a-z ]+", "abc DEF ghi"), vec!["DEF"]);
+		assert_eq!(find_per_line("[]x]", "a]"), vec!["]"]);
+		assert_eq!(find_per_line("[a\\-]+", "x-a-y"), vec!["-a-"]);
+		assert_eq!(find_per_line("[α-ω]+", "abc λόγος"), vec!["λ"]);
+	}
+
+	#[test]
+	fn optional() {
+		assert_eq!(
+			find_per_line("colou?r", "color\ncolour\ncolouur"),
+			vec!["color", "colour"]
+		);
+		assert_eq!(
+			find_per_line("a(bc)?d", "xadx\nabcd\nabd"),
+			vec!["ad", "abcd"]
+		);
+	}
+
+	#[test]
+	fn counted_repetition() {
+		assert_eq!(
+			find_per_line("[0-9]{4}-[0-9]{2}", "on 2020-07-03\nin 20-07"),
+			vec!["2020-07"]
+		);
+		assert_eq!(find_per_line("(ab){2,}", "ababab"), vec!["ababab"]);
+		assert_eq!(find_per_line("a{2,3}", "a\naaaa"), vec!["aaa"]);
+		assert_eq!(find_per_line("xa{0}y", "xy"), vec!["xy"]);
+	}
+
+	#[test]
+	fn anchors() {
+		assert_eq!(find_per_line("^ab", "ab\ncab"), vec!["ab"]);
+		assert_eq!(find_per_line("ab$", "abc\ncab"), vec!["ab"]);
+		assert_eq!(find_per_line("\\Aa+\\z", "aa\naab\nbaa"), vec!["aa"]);
+		assert_eq!(find_per_line("^$", "\nx"), vec![""]);
+
+		let dfsm = create("\\Aa+\\z").unwrap();
+		assert_eq!(find("aa\naab\nbaa", &dfsm), None);
+		assert_eq!(find("aa", &dfsm).map(|m| m.as_str()), Some("aa"));
+		assert_eq!(find_all("\\Aa", "a\na"), vec![(0, "a")]);
+		assert_eq!(find_all("a\\z", "a\na"), vec![(2, "a")]);
+		assert!(captures("b\na", &pike::create("\\A(a)").unwrap()).is_none());
+	}
+
+	#[test]
+	fn multi_line_anchors() {
+		let dfsm = create("^b").unwrap();
+		assert_eq!(find("a\nb", &dfsm), None);
+
+		let dfsm = create_with_options("^b$", &Options::new().multi_line(true)).unwrap();
+		assert_eq!(find("a\nb\nc", &dfsm).map(|m| m.as_str()), Some("b"));
+
+		let dfsm = create_with_options("^.", &Options::new().multi_line(true)).unwrap();
+		assert_eq!(
+			find_iter("ab\ncd", &dfsm)
+				.map(|m| m.as_str())
+				.collect::<Vec<_>>(),
+			vec!["a", "c"]
+		);
+
+		let dfsm = create_with_options("\\Ab", &Options::new().multi_line(true)).unwrap();
+		assert_eq!(find("a\nb", &dfsm), None);
+	}
+
+	#[test]
+	fn inline_flags() {
+		assert_eq!(find_per_line("(?i)hello", "say HeLLo"), vec!["HeLLo"]);
+		assert_eq!(find_per_line("a(?i:b)c", "ABc\naBc"), vec!["aBc"]);
+		assert_eq!(find_per_line("((?i)a)a", "AA\nAa"), vec!["Aa"]);
+		assert_eq!(find_per_line("(?i)[^a-c]+", "ABCdef"), vec!["def"]);
+		assert_eq!(
+			find_per_line("(?x) [0-9]+ \\ - # the year\n [0-9]+", "2020 -07"),
+			vec!["2020 -07"]
+		);
+
+		let dfsm = create("(?m)^b$").unwrap();
+		assert_eq!(find("a\nb\nc", &dfsm).map(|m| m.as_str()), Some("b"));
+
+		let dfsm = create_with_options("(?-m)^b", &Options::new().multi_line(true)).unwrap();
+		assert_eq!(find("a\nb", &dfsm), None);
+
+		let dfsm = create("a(?s).b").unwrap();
+		assert_eq!(find("a\nb", &dfsm).map(|m| m.as_str()), Some("a\nb"));
+		assert_eq!(find("a\nb", &create("a.b").unwrap()), None);
+
+		let dfsm = create_with_options("a.b", &Options::new().dot_matches_new_line(true)).unwrap();
+		assert_eq!(find("a\nb", &dfsm).map(|m| m.as_str()), Some("a\nb"));
+		assert_eq!(
+			groups("(?s)(a.)b", "xa\nb"),
+			vec![Some((1, "a\nb")), Some((1, "a\n"))]
+		);
+	}
+
+	#[test]
+	fn case_insensitive() {
+		assert_eq!(
+			find_per_line("(?i)straße", "STRASSE\nSTRAẞE"),
+			vec!["STRAẞE"]
+		);
+		assert_eq!(find_per_line("(?i)σοφός", "ΣΟΦΌΣ"), vec!["ΣΟΦΌΣ"]);
+		assert_eq!(find_per_line("(?i)ΣΟΦΌΣ", "σοφός"), vec!["σοφός"]);
+		assert_eq!(find_per_line("(?i)привет", "ПРИВЕТ"), vec!["ПРИВЕТ"]);
+		assert_eq!(find_per_line("(?i)[а-я]+", "МИР"), vec!["МИР"]);
+		assert_eq!(find_per_line("(?i)[^k]+", "kK\u{212A}x"), vec!["x"]);
+
+		let dfsm = create_with_options("k", &Options::new().case_insensitive(true)).unwrap();
+		assert_eq!(
+			find_from("\u{212A}", 0, &dfsm).map(|m| m.as_str()),
+			Some("\u{212A}")
+		);
+
+		let dfsm = create_with_options("(?-i)k", &Options::new().case_insensitive(true)).unwrap();
+		assert_eq!(find_from("K", 0, &dfsm), None);
+	}
+
+	#[test]
+	fn perl_classes() {
+		assert_eq!(find_per_line("\\d+", "x٣42"), vec!["42"]);
+		assert_eq!(find_per_line("\\w+", "héllo"), vec!["h"]);
+		assert_eq!(find_per_line("\\s\\S+", "a \tb\u{A0}c"), vec!["\tb\u{A0}c"]);
+		assert_eq!(find_per_line("[\\d_]+", "ab_12c"), vec!["_12"]);
+		assert_eq!(find_per_line("[^\\W\\d]+", "12ab_3"), vec!["ab_"]);
+		assert_eq!(find_per_line("(?u)\\w+", "héllo wörld"), vec!["héllo"]);
+		assert_eq!(find_per_line("(?u)\\d+", "x٣42"), vec!["٣42"]);
+		assert_eq!(
+			find_per_line("(?u)\\s+", "a\u{A0}\u{3000}b"),
+			vec!["\u{A0}\u{3000}"]
+		);
+
+		let dfsm = create_with_options("\\W", &Options::new().unicode(true)).unwrap();
+		assert_eq!(find_from("é!", 0, &dfsm).map(|m| m.as_str()), Some("!"));
+	}
+
+	#[test]
+	fn control_and_code_point_escapes() {
+		assert_eq!(find_per_line("a\\tb", "a b\na\tb"), vec!["a\tb"]);
+		assert_eq!(find_per_line("\\u{1F600}+", "hi 😀😀!"), vec!["😀😀"]);
+		assert_eq!(
+			find_per_line("[\\x{1F600}-\\x{1F64F}]", "ok 🙂"),
+			vec!["🙂"]
+		);
+		assert_eq!(find_per_line("caf\\u00e9", "café"), vec!["café"]);
+		assert_eq!(find_per_line("\\x2A\\x2a", "a**"), vec!["**"]);
+
+		let dfsm = create("a\\nb").unwrap();
+		assert_eq!(find("xa\nb", &dfsm).map(|m| m.range()), Some(1..4));
+		assert_eq!(find_all("a\\n", "a\na\n"), vec![(0, "a\n"), (2, "a\n")]);
+		assert!(find_per_line("a\\nb", "a\nb").is_empty());
+	}
+
+	#[test]
+	fn property_classes() {
+		assert_eq!(
+			find_per_line("\\p{Greek}+", "name: Αλέξανδρος"),
+			vec!["Αλέξανδρος"]
+		);
+		assert_eq!(find_per_line("\\p{Cyrillic}+", "Ivan Иван"), vec!["Иван"]);
+		assert_eq!(find_per_line("\\pL+", "42 Zoë Žofie"), vec!["Zoë"]);
+		assert_eq!(find_per_line("\\P{L}+", "abc, 123def"), vec![", 123"]);
+		assert_eq!(find_per_line("[\\p{Greek}\\d]+", "ab π3 c"), vec!["π3"]);
+		assert_eq!(
+			find_per_line("\\p{Han}\\p{Hiragana}", "漢字は"),
+			vec!["字は"]
+		);
+		assert_eq!(find_per_line("(?i)\\p{Lu}+", "Hello"), vec!["Hello"]);
+		assert_eq!(find_per_line("(?i)[\\p{Ll}\\d]+", "HI 42"), vec!["HI"]);
+		assert_eq!(find_per_line("(?i)\\P{Lu}+", "Hi, 42"), vec![", 42"]);
+		assert_eq!(find_per_line("(?i)[\\P{Ll}]+", "Hi, 42"), vec![", 42"]);
+	}
+
+	#[test]
+	fn posix_classes() {
+		assert_eq!(
+			find_per_line("[[:upper:]][[:lower:]]+", "hello World"),
+			vec!["World"]
+		);
+		assert_eq!(find_per_line("[[:xdigit:]]+", "colour #1fA2zz"), vec!["c"]);
+		assert_eq!(
+			find_per_line("#[[:xdigit:]]+", "colour #1fA2zz"),
+			vec!["#1fA2"]
+		);
+		assert_eq!(find_per_line("[[:punct:]]+", "wait... what?!"), vec!["..."]);
+		assert_eq!(
+			find_per_line("[^[:space:][:digit:]]+", " 42 abc"),
+			vec!["abc"]
+		);
+		assert_eq!(find_per_line("[[:^alnum:]]", "ab_c"), vec!["_"]);
+		assert_eq!(find_per_line("(?i)[[:lower:]]+", "HELLO"), vec!["HELLO"]);
+		assert_eq!(find_per_line("(?i)[[:alpha:]--a]", "aAſ"), vec!["ſ"]);
+		assert_eq!(find_per_line("(?i)[[:^lower:]]", "aA1"), vec!["1"]);
+	}
+
+	#[test]
+	fn class_set_operations() {
+		assert_eq!(
+			find_per_line("[a-z&&[^aeiou]]+", "rhythm and blues"),
+			vec!["rhythm"]
+		);
+		assert_eq!(
+			find_per_line("[\\p{Greek}&&\\p{Lu}]+", "αβΓΔεζ"),
+			vec!["ΓΔ"]
+		);
+		assert_eq!(find_per_line("[\\pL--\\p{Latin}]+", "abc Жук"), vec!["Жук"]);
+		assert_eq!(find_per_line("(?i)[a-z&&[^k]]+", "Kayak"), vec!["aya"]);
+	}
+
+	#[test]
+	fn word_boundaries() {
+		assert_eq!(
+			find_all("\\bcat\\b", "cat concat cats cat_ (cat)"),
+			vec![(0, "cat"), (22, "cat")]
+		);
+		assert_eq!(find_all("\\Bcat", "cat concat"), vec![(7, "cat")]);
+		assert_eq!(
+			find_all("\\b", "ab c"),
+			vec![(0, ""), (2, ""), (3, ""), (4, "")]
+		);
+
+		/* Only ascii letters make words without the unicode flag */
+		assert_eq!(find_all("\\bo", "señor o"), vec![(4, "o"), (7, "o")]);
+		assert_eq!(find_all("(?u)\\bo", "señor o"), vec![(7, "o")]);
+		assert_eq!(find_all("(?u)\\w+\\b", "señor"), vec![(0, "señor")]);
+
+		assert_eq!(
+			groups("\\b(\\w+) \\b", "an apple"),
+			vec![Some((0, "an ")), Some((0, "an"))]
+		);
+		assert_eq!(
+			groups("(?u)o\\B(.)", "ño ñoñ"),
+			vec![Some((6, "oñ")), Some((7, "ñ"))]
+		);
+	}
+
+	#[test]
+	fn lazy_quantifiers() {
+		assert_eq!(find_per_line("<.+>", "<a><b>"), vec!["<a><b>"]);
+		assert_eq!(find_all("<.+?>", "<a><b>"), vec![(0, "<a>"), (3, "<b>")]);
+		assert_eq!(find_per_line("a*?", "aaa"), vec![""]);
+		assert_eq!(find_per_line("a+?", "aaa"), vec!["a"]);
+		assert_eq!(find_per_line("ab??", "ab"), vec!["a"]);
+		assert_eq!(find_per_line("a{2,4}?", "aaaa"), vec!["aa"]);
+		assert_eq!(find_per_line("a{2,}?b", "aaaab"), vec!["aaaab"]);
+
+		assert_eq!(
+			groups("(a+?)(a*)", "aaa"),
+			vec![Some((0, "aaa")), Some((0, "a")), Some((1, "aa"))]
+		);
+		assert_eq!(
+			groups("\"(.*?)\"", "say \"hi\" and \"bye\""),
+			vec![Some((4, "\"hi\"")), Some((5, "hi"))]
+		);
+	}
+
+	#[test]
+	fn empty_alternatives_and_groups() {
+		assert_eq!(find_per_line("colou(|r)", "color\ncolour"), vec!["colou"]);
+		assert_eq!(find_per_line("colou(r|)", "color\ncolour"), vec!["colour"]);
+		assert_eq!(find_per_line("cats(|s)", "cats"), vec!["cats"]);
+		assert_eq!(find_all("", "ab"), vec![(0, ""), (1, ""), (2, "")]);
+		assert_eq!(find_all("a|", "ba"), vec![(0, ""), (1, "a")]);
+		assert_eq!(find_all("x{0}y", "xy"), vec![(1, "y")]);
+
+		assert_eq!(groups("a()b", "ab"), vec![Some((0, "ab")), Some((1, ""))]);
+		assert_eq!(groups("(|b)c", "bc"), vec![Some((0, "bc")), Some((0, "b"))]);
+	}
+
+	#[test]
+	fn star_of_what_can_be_empty() {
+		assert_eq!(find_per_line("(?:|a)*", "a"), vec![""]);
+		assert_eq!(find_per_line("(?:a|)*", "aa"), vec!["aa"]);
+		assert_eq!(find_per_line("(?:|a)*?b", "aab"), vec!["aab"]);
+
+		assert_eq!(groups("()*", "a"), vec![Some((0, "")), Some((0, ""))]);
+		assert_eq!(groups("(a|)*b", "b"), vec![Some((0, "b")), Some((0, ""))]);
+		assert_eq!(groups("(?:|a)*", "a"), vec![Some((0, ""))]);
+	}
+
+	#[test]
+	fn first_alternative_preferred() {
+		assert_eq!(find_per_line("a|ab", "ab"), vec!["a"]);
+		assert_eq!(find_per_line("ab|a", "ab"), vec!["ab"]);
+	}
 }
@@ -0,0 +1,65 @@
+//! Unicode data the parser needs, the tables in src/unicode/ are generated by
+//! scripts/unicode-tables.pl rather than written by hand
+
+mod case_folding;
+mod general_category;
+mod perl;
+mod script;
+
+use crate::class::range_cmp;
+
+use case_folding::CASE_FOLDING_SIMPLE;
+pub use perl::{PERL_DIGIT, PERL_SPACE, PERL_WORD};
+
+/// Each character from start to end inclusive that has other cases under simple case folding,
+/// along with those cases
+pub fn simple_case_folds(start: char, end: char) -> &'static [(char, &'static [char])] {
+    let first = CASE_FOLDING_SIMPLE.partition_point(|&(c, _)| c < start);
+    let last = CASE_FOLDING_SIMPLE.partition_point(|&(c, _)| c <= end);
+
+    &CASE_FOLDING_SIMPLE[first..last.max(first)]
+}
+
+/// Whether the character is in unicode's \w, which is what \b looks for with the unicode flag
+pub fn is_word_char(c: char) -> bool {
+    PERL_WORD
+        .binary_search_by(|&range| range_cmp(range, c))
+        .is_ok()
+}
+
+/// The characters with a general category or script, given by any of its names such as L,
+/// Letter, Greek or Grek. A gc= or sc= prefix, or General_Category= or Script=, picks which of
+/// them the name is looked up in. None when nothing has the name
+pub fn property(name: &str) -> Option<&'static [(char, char)]> {
+    let (property_name, value_name) = match name.split_once('=') {
+        Some((property_name, value_name)) => (Some(loose_name(property_name)), value_name),
+        None => (None, name),
+    };
+    let value_name = loose_name(value_name);
+
+    match property_name.as_deref() {
+        None => lookup(general_category::BY_NAME, &value_name)
+            .or_else(|| lookup(script::BY_NAME, &value_name)),
+        Some("gc" | "generalcategory") => lookup(general_category::BY_NAME, &value_name),
+        Some("sc" | "script") => lookup(script::BY_NAME, &value_name),
+        Some(_) => None,
+    }
+}
+
+/// Names are compared ignoring case, spaces, underscores and hyphens, the same as the tables do
+fn loose_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '-'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn lookup(
+    by_name: &'static [(&str, &'static [(char, char)])],
+    name: &str,
+) -> Option<&'static [(char, char)]> {
+    by_name
+        .binary_search_by(|&(x, _)| x.cmp(name))
+        .ok()
+        .map(|i| by_name[i].1)
+}
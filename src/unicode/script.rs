@@ -0,0 +1,2393 @@
+// Generated by scripts/unicode-tables.pl from unicode 14.0.0, do not edit by hand
+
+/// The characters in each script by every name they go by, sorted by name. Names are
+/// matched loosely by ignoring case, spaces, underscores and hyphens
+pub const BY_NAME: &[(&str, &[(char, char)])] = &[
+    ("adlam", ADLAM),
+    ("adlm", ADLAM),
+    ("aghb", CAUCASIAN_ALBANIAN),
+    ("ahom", AHOM),
+    ("anatolianhieroglyphs", ANATOLIAN_HIEROGLYPHS),
+    ("arab", ARABIC),
+    ("arabic", ARABIC),
+    ("armenian", ARMENIAN),
+    ("armi", IMPERIAL_ARAMAIC),
+    ("armn", ARMENIAN),
+    ("avestan", AVESTAN),
+    ("avst", AVESTAN),
+    ("bali", BALINESE),
+    ("balinese", BALINESE),
+    ("bamu", BAMUM),
+    ("bamum", BAMUM),
+    ("bass", BASSA_VAH),
+    ("bassavah", BASSA_VAH),
+    ("batak", BATAK),
+    ("batk", BATAK),
+    ("beng", BENGALI),
+    ("bengali", BENGALI),
+    ("bhaiksuki", BHAIKSUKI),
+    ("bhks", BHAIKSUKI),
+    ("bopo", BOPOMOFO),
+    ("bopomofo", BOPOMOFO),
+    ("brah", BRAHMI),
+    ("brahmi", BRAHMI),
+    ("brai", BRAILLE),
+    ("braille", BRAILLE),
+    ("bugi", BUGINESE),
+    ("buginese", BUGINESE),
+    ("buhd", BUHID),
+    ("buhid", BUHID),
+    ("cakm", CHAKMA),
+    ("canadianaboriginal", CANADIAN_ABORIGINAL),
+    ("cans", CANADIAN_ABORIGINAL),
+    ("cari", CARIAN),
+    ("carian", CARIAN),
+    ("caucasianalbanian", CAUCASIAN_ALBANIAN),
+    ("chakma", CHAKMA),
+    ("cham", CHAM),
+    ("cher", CHEROKEE),
+    ("cherokee", CHEROKEE),
+    ("chorasmian", CHORASMIAN),
+    ("chrs", CHORASMIAN),
+    ("common", COMMON),
+    ("copt", COPTIC),
+    ("coptic", COPTIC),
+    ("cpmn", CYPRO_MINOAN),
+    ("cprt", CYPRIOT),
+    ("cuneiform", CUNEIFORM),
+    ("cypriot", CYPRIOT),
+    ("cyprominoan", CYPRO_MINOAN),
+    ("cyrillic", CYRILLIC),
+    ("cyrl", CYRILLIC),
+    ("deseret", DESERET),
+    ("deva", DEVANAGARI),
+    ("devanagari", DEVANAGARI),
+    ("diak", DIVES_AKURU),
+    ("divesakuru", DIVES_AKURU),
+    ("dogr", DOGRA),
+    ("dogra", DOGRA),
+    ("dsrt", DESERET),
+    ("dupl", DUPLOYAN),
+    ("duployan", DUPLOYAN),
+    ("egyp", EGYPTIAN_HIEROGLYPHS),
+    ("egyptianhieroglyphs", EGYPTIAN_HIEROGLYPHS),
+    ("elba", ELBASAN),
+    ("elbasan", ELBASAN),
+    ("elym", ELYMAIC),
+    ("elymaic", ELYMAIC),
+    ("ethi", ETHIOPIC),
+    ("ethiopic", ETHIOPIC),
+    ("geor", GEORGIAN),
+    ("georgian", GEORGIAN),
+    ("glag", GLAGOLITIC),
+    ("glagolitic", GLAGOLITIC),
+    ("gong", GUNJALA_GONDI),
+    ("gonm", MASARAM_GONDI),
+    ("goth", GOTHIC),
+    ("gothic", GOTHIC),
+    ("gran", GRANTHA),
+    ("grantha", GRANTHA),
+    ("greek", GREEK),
+    ("grek", GREEK),
+    ("gujarati", GUJARATI),
+    ("gujr", GUJARATI),
+    ("gunjalagondi", GUNJALA_GONDI),
+    ("gurmukhi", GURMUKHI),
+    ("guru", GURMUKHI),
+    ("han", HAN),
+    ("hang", HANGUL),
+    ("hangul", HANGUL),
+    ("hani", HAN),
+    ("hanifirohingya", HANIFI_ROHINGYA),
+    ("hano", HANUNOO),
+    ("hanunoo", HANUNOO),
+    ("hatr", HATRAN),
+    ("hatran", HATRAN),
+    ("hebr", HEBREW),
+    ("hebrew", HEBREW),
+    ("hira", HIRAGANA),
+    ("hiragana", HIRAGANA),
+    ("hluw", ANATOLIAN_HIEROGLYPHS),
+    ("hmng", PAHAWH_HMONG),
+    ("hmnp", NYIAKENG_PUACHUE_HMONG),
+    ("hung", OLD_HUNGARIAN),
+    ("imperialaramaic", IMPERIAL_ARAMAIC),
+    ("inherited", INHERITED),
+    ("inscriptionalpahlavi", INSCRIPTIONAL_PAHLAVI),
+    ("inscriptionalparthian", INSCRIPTIONAL_PARTHIAN),
+    ("ital", OLD_ITALIC),
+    ("java", JAVANESE),
+    ("javanese", JAVANESE),
+    ("kaithi", KAITHI),
+    ("kali", KAYAH_LI),
+    ("kana", KATAKANA),
+    ("kannada", KANNADA),
+    ("katakana", KATAKANA),
+    ("kayahli", KAYAH_LI),
+    ("khar", KHAROSHTHI),
+    ("kharoshthi", KHAROSHTHI),
+    ("khitansmallscript", KHITAN_SMALL_SCRIPT),
+    ("khmer", KHMER),
+    ("khmr", KHMER),
+    ("khoj", KHOJKI),
+    ("khojki", KHOJKI),
+    ("khudawadi", KHUDAWADI),
+    ("kits", KHITAN_SMALL_SCRIPT),
+    ("knda", KANNADA),
+    ("kthi", KAITHI),
+    ("lana", TAI_THAM),
+    ("lao", LAO),
+    ("laoo", LAO),
+    ("latin", LATIN),
+    ("latn", LATIN),
+    ("lepc", LEPCHA),
+    ("lepcha", LEPCHA),
+    ("limb", LIMBU),
+    ("limbu", LIMBU),
+    ("lina", LINEAR_A),
+    ("linb", LINEAR_B),
+    ("lineara", LINEAR_A),
+    ("linearb", LINEAR_B),
+    ("lisu", LISU),
+    ("lyci", LYCIAN),
+    ("lycian", LYCIAN),
+    ("lydi", LYDIAN),
+    ("lydian", LYDIAN),
+    ("mahajani", MAHAJANI),
+    ("mahj", MAHAJANI),
+    ("maka", MAKASAR),
+    ("makasar", MAKASAR),
+    ("malayalam", MALAYALAM),
+    ("mand", MANDAIC),
+    ("mandaic", MANDAIC),
+    ("mani", MANICHAEAN),
+    ("manichaean", MANICHAEAN),
+    ("marc", MARCHEN),
+    ("marchen", MARCHEN),
+    ("masaramgondi", MASARAM_GONDI),
+    ("medefaidrin", MEDEFAIDRIN),
+    ("medf", MEDEFAIDRIN),
+    ("meeteimayek", MEETEI_MAYEK),
+    ("mend", MENDE_KIKAKUI),
+    ("mendekikakui", MENDE_KIKAKUI),
+    ("merc", MEROITIC_CURSIVE),
+    ("mero", MEROITIC_HIEROGLYPHS),
+    ("meroiticcursive", MEROITIC_CURSIVE),
+    ("meroitichieroglyphs", MEROITIC_HIEROGLYPHS),
+    ("miao", MIAO),
+    ("mlym", MALAYALAM),
+    ("modi", MODI),
+    ("mong", MONGOLIAN),
+    ("mongolian", MONGOLIAN),
+    ("mro", MRO),
+    ("mroo", MRO),
+    ("mtei", MEETEI_MAYEK),
+    ("mult", MULTANI),
+    ("multani", MULTANI),
+    ("myanmar", MYANMAR),
+    ("mymr", MYANMAR),
+    ("nabataean", NABATAEAN),
+    ("nand", NANDINAGARI),
+    ("nandinagari", NANDINAGARI),
+    ("narb", OLD_NORTH_ARABIAN),
+    ("nbat", NABATAEAN),
+    ("newa", NEWA),
+    ("newtailue", NEW_TAI_LUE),
+    ("nko", NKO),
+    ("nkoo", NKO),
+    ("nshu", NUSHU),
+    ("nushu", NUSHU),
+    ("nyiakengpuachuehmong", NYIAKENG_PUACHUE_HMONG),
+    ("ogam", OGHAM),
+    ("ogham", OGHAM),
+    ("olchiki", OL_CHIKI),
+    ("olck", OL_CHIKI),
+    ("oldhungarian", OLD_HUNGARIAN),
+    ("olditalic", OLD_ITALIC),
+    ("oldnortharabian", OLD_NORTH_ARABIAN),
+    ("oldpermic", OLD_PERMIC),
+    ("oldpersian", OLD_PERSIAN),
+    ("oldsogdian", OLD_SOGDIAN),
+    ("oldsoutharabian", OLD_SOUTH_ARABIAN),
+    ("oldturkic", OLD_TURKIC),
+    ("olduyghur", OLD_UYGHUR),
+    ("oriya", ORIYA),
+    ("orkh", OLD_TURKIC),
+    ("orya", ORIYA),
+    ("osage", OSAGE),
+    ("osge", OSAGE),
+    ("osma", OSMANYA),
+    ("osmanya", OSMANYA),
+    ("ougr", OLD_UYGHUR),
+    ("pahawhhmong", PAHAWH_HMONG),
+    ("palm", PALMYRENE),
+    ("palmyrene", PALMYRENE),
+    ("pauc", PAU_CIN_HAU),
+    ("paucinhau", PAU_CIN_HAU),
+    ("perm", OLD_PERMIC),
+    ("phag", PHAGS_PA),
+    ("phagspa", PHAGS_PA),
+    ("phli", INSCRIPTIONAL_PAHLAVI),
+    ("phlp", PSALTER_PAHLAVI),
+    ("phnx", PHOENICIAN),
+    ("phoenician", PHOENICIAN),
+    ("plrd", MIAO),
+    ("prti", INSCRIPTIONAL_PARTHIAN),
+    ("psalterpahlavi", PSALTER_PAHLAVI),
+    ("qaac", COPTIC),
+    ("qaai", INHERITED),
+    ("rejang", REJANG),
+    ("rjng", REJANG),
+    ("rohg", HANIFI_ROHINGYA),
+    ("runic", RUNIC),
+    ("runr", RUNIC),
+    ("samaritan", SAMARITAN),
+    ("samr", SAMARITAN),
+    ("sarb", OLD_SOUTH_ARABIAN),
+    ("saur", SAURASHTRA),
+    ("saurashtra", SAURASHTRA),
+    ("sgnw", SIGNWRITING),
+    ("sharada", SHARADA),
+    ("shavian", SHAVIAN),
+    ("shaw", SHAVIAN),
+    ("shrd", SHARADA),
+    ("sidd", SIDDHAM),
+    ("siddham", SIDDHAM),
+    ("signwriting", SIGNWRITING),
+    ("sind", KHUDAWADI),
+    ("sinh", SINHALA),
+    ("sinhala", SINHALA),
+    ("sogd", SOGDIAN),
+    ("sogdian", SOGDIAN),
+    ("sogo", OLD_SOGDIAN),
+    ("sora", SORA_SOMPENG),
+    ("sorasompeng", SORA_SOMPENG),
+    ("soyo", SOYOMBO),
+    ("soyombo", SOYOMBO),
+    ("sund", SUNDANESE),
+    ("sundanese", SUNDANESE),
+    ("sylo", SYLOTI_NAGRI),
+    ("sylotinagri", SYLOTI_NAGRI),
+    ("syrc", SYRIAC),
+    ("syriac", SYRIAC),
+    ("tagalog", TAGALOG),
+    ("tagb", TAGBANWA),
+    ("tagbanwa", TAGBANWA),
+    ("taile", TAI_LE),
+    ("taitham", TAI_THAM),
+    ("taiviet", TAI_VIET),
+    ("takr", TAKRI),
+    ("takri", TAKRI),
+    ("tale", TAI_LE),
+    ("talu", NEW_TAI_LUE),
+    ("tamil", TAMIL),
+    ("taml", TAMIL),
+    ("tang", TANGUT),
+    ("tangsa", TANGSA),
+    ("tangut", TANGUT),
+    ("tavt", TAI_VIET),
+    ("telu", TELUGU),
+    ("telugu", TELUGU),
+    ("tfng", TIFINAGH),
+    ("tglg", TAGALOG),
+    ("thaa", THAANA),
+    ("thaana", THAANA),
+    ("thai", THAI),
+    ("tibetan", TIBETAN),
+    ("tibt", TIBETAN),
+    ("tifinagh", TIFINAGH),
+    ("tirh", TIRHUTA),
+    ("tirhuta", TIRHUTA),
+    ("tnsa", TANGSA),
+    ("toto", TOTO),
+    ("ugar", UGARITIC),
+    ("ugaritic", UGARITIC),
+    ("unknown", UNKNOWN),
+    ("vai", VAI),
+    ("vaii", VAI),
+    ("vith", VITHKUQI),
+    ("vithkuqi", VITHKUQI),
+    ("wancho", WANCHO),
+    ("wara", WARANG_CITI),
+    ("warangciti", WARANG_CITI),
+    ("wcho", WANCHO),
+    ("xpeo", OLD_PERSIAN),
+    ("xsux", CUNEIFORM),
+    ("yezi", YEZIDI),
+    ("yezidi", YEZIDI),
+    ("yi", YI),
+    ("yiii", YI),
+    ("zanabazarsquare", ZANABAZAR_SQUARE),
+    ("zanb", ZANABAZAR_SQUARE),
+    ("zinh", INHERITED),
+    ("zyyy", COMMON),
+    ("zzzz", UNKNOWN),
+];
+
+/// Adlam, Adlm
+pub const ADLAM: &[(char, char)] = &[
+    ('\u{1E900}', '\u{1E94B}'),
+    ('\u{1E950}', '\u{1E959}'),
+    ('\u{1E95E}', '\u{1E95F}'),
+];
+
+/// Caucasian_Albanian, Aghb
+pub const CAUCASIAN_ALBANIAN: &[(char, char)] =
+    &[('\u{10530}', '\u{10563}'), ('\u{1056F}', '\u{1056F}')];
+
+/// Ahom
+pub const AHOM: &[(char, char)] = &[
+    ('\u{11700}', '\u{1171A}'),
+    ('\u{1171D}', '\u{1172B}'),
+    ('\u{11730}', '\u{11746}'),
+];
+
+/// Arabic, Arab
+pub const ARABIC: &[(char, char)] = &[
+    ('\u{600}', '\u{604}'),
+    ('\u{606}', '\u{60B}'),
+    ('\u{60D}', '\u{61A}'),
+    ('\u{61C}', '\u{61E}'),
+    ('\u{620}', '\u{63F}'),
+    ('\u{641}', '\u{64A}'),
+    ('\u{656}', '\u{66F}'),
+    ('\u{671}', '\u{6DC}'),
+    ('\u{6DE}', '\u{6FF}'),
+    ('\u{750}', '\u{77F}'),
+    ('\u{870}', '\u{88E}'),
+    ('\u{890}', '\u{891}'),
+    ('\u{898}', '\u{8E1}'),
+    ('\u{8E3}', '\u{8FF}'),
+    ('\u{FB50}', '\u{FBC2}'),
+    ('\u{FBD3}', '\u{FD3D}'),
+    ('\u{FD40}', '\u{FD8F}'),
+    ('\u{FD92}', '\u{FDC7}'),
+    ('\u{FDCF}', '\u{FDCF}'),
+    ('\u{FDF0}', '\u{FDFF}'),
+    ('\u{FE70}', '\u{FE74}'),
+    ('\u{FE76}', '\u{FEFC}'),
+    ('\u{10E60}', '\u{10E7E}'),
+    ('\u{1EE00}', '\u{1EE03}'),
+    ('\u{1EE05}', '\u{1EE1F}'),
+    ('\u{1EE21}', '\u{1EE22}'),
+    ('\u{1EE24}', '\u{1EE24}'),
+    ('\u{1EE27}', '\u{1EE27}'),
+    ('\u{1EE29}', '\u{1EE32}'),
+    ('\u{1EE34}', '\u{1EE37}'),
+    ('\u{1EE39}', '\u{1EE39}'),
+    ('\u{1EE3B}', '\u{1EE3B}'),
+    ('\u{1EE42}', '\u{1EE42}'),
+    ('\u{1EE47}', '\u{1EE47}'),
+    ('\u{1EE49}', '\u{1EE49}'),
+    ('\u{1EE4B}', '\u{1EE4B}'),
+    ('\u{1EE4D}', '\u{1EE4F}'),
+    ('\u{1EE51}', '\u{1EE52}'),
+    ('\u{1EE54}', '\u{1EE54}'),
+    ('\u{1EE57}', '\u{1EE57}'),
+    ('\u{1EE59}', '\u{1EE59}'),
+    ('\u{1EE5B}', '\u{1EE5B}'),
+    ('\u{1EE5D}', '\u{1EE5D}'),
+    ('\u{1EE5F}', '\u{1EE5F}'),
+    ('\u{1EE61}', '\u{1EE62}'),
+    ('\u{1EE64}', '\u{1EE64}'),
+    ('\u{1EE67}', '\u{1EE6A}'),
+    ('\u{1EE6C}', '\u{1EE72}'),
+    ('\u{1EE74}', '\u{1EE77}'),
+    ('\u{1EE79}', '\u{1EE7C}'),
+    ('\u{1EE7E}', '\u{1EE7E}'),
+    ('\u{1EE80}', '\u{1EE89}'),
+    ('\u{1EE8B}', '\u{1EE9B}'),
+    ('\u{1EEA1}', '\u{1EEA3}'),
+    ('\u{1EEA5}', '\u{1EEA9}'),
+    ('\u{1EEAB}', '\u{1EEBB}'),
+    ('\u{1EEF0}', '\u{1EEF1}'),
+];
+
+/// Imperial_Aramaic, Armi
+pub const IMPERIAL_ARAMAIC: &[(char, char)] =
+    &[('\u{10840}', '\u{10855}'), ('\u{10857}', '\u{1085F}')];
+
+/// Armenian, Armn
+pub const ARMENIAN: &[(char, char)] = &[
+    ('\u{531}', '\u{556}'),
+    ('\u{559}', '\u{58A}'),
+    ('\u{58D}', '\u{58F}'),
+    ('\u{FB13}', '\u{FB17}'),
+];
+
+/// Avestan, Avst
+pub const AVESTAN: &[(char, char)] = &[('\u{10B00}', '\u{10B35}'), ('\u{10B39}', '\u{10B3F}')];
+
+/// Balinese, Bali
+pub const BALINESE: &[(char, char)] = &[('\u{1B00}', '\u{1B4C}'), ('\u{1B50}', '\u{1B7E}')];
+
+/// Bamum, Bamu
+pub const BAMUM: &[(char, char)] = &[('\u{A6A0}', '\u{A6F7}'), ('\u{16800}', '\u{16A38}')];
+
+/// Bassa_Vah, Bass
+pub const BASSA_VAH: &[(char, char)] = &[('\u{16AD0}', '\u{16AED}'), ('\u{16AF0}', '\u{16AF5}')];
+
+/// Batak, Batk
+pub const BATAK: &[(char, char)] = &[('\u{1BC0}', '\u{1BF3}'), ('\u{1BFC}', '\u{1BFF}')];
+
+/// Bengali, Beng
+pub const BENGALI: &[(char, char)] = &[
+    ('\u{980}', '\u{983}'),
+    ('\u{985}', '\u{98C}'),
+    ('\u{98F}', '\u{990}'),
+    ('\u{993}', '\u{9A8}'),
+    ('\u{9AA}', '\u{9B0}'),
+    ('\u{9B2}', '\u{9B2}'),
+    ('\u{9B6}', '\u{9B9}'),
+    ('\u{9BC}', '\u{9C4}'),
+    ('\u{9C7}', '\u{9C8}'),
+    ('\u{9CB}', '\u{9CE}'),
+    ('\u{9D7}', '\u{9D7}'),
+    ('\u{9DC}', '\u{9DD}'),
+    ('\u{9DF}', '\u{9E3}'),
+    ('\u{9E6}', '\u{9FE}'),
+];
+
+/// Bhaiksuki, Bhks
+pub const BHAIKSUKI: &[(char, char)] = &[
+    ('\u{11C00}', '\u{11C08}'),
+    ('\u{11C0A}', '\u{11C36}'),
+    ('\u{11C38}', '\u{11C45}'),
+    ('\u{11C50}', '\u{11C6C}'),
+];
+
+/// Bopomofo, Bopo
+pub const BOPOMOFO: &[(char, char)] = &[
+    ('\u{2EA}', '\u{2EB}'),
+    ('\u{3105}', '\u{312F}'),
+    ('\u{31A0}', '\u{31BF}'),
+];
+
+/// Brahmi, Brah
+pub const BRAHMI: &[(char, char)] = &[
+    ('\u{11000}', '\u{1104D}'),
+    ('\u{11052}', '\u{11075}'),
+    ('\u{1107F}', '\u{1107F}'),
+];
+
+/// Braille, Brai
+pub const BRAILLE: &[(char, char)] = &[('\u{2800}', '\u{28FF}')];
+
+/// Buginese, Bugi
+pub const BUGINESE: &[(char, char)] = &[('\u{1A00}', '\u{1A1B}'), ('\u{1A1E}', '\u{1A1F}')];
+
+/// Buhid, Buhd
+pub const BUHID: &[(char, char)] = &[('\u{1740}', '\u{1753}')];
+
+/// Chakma, Cakm
+pub const CHAKMA: &[(char, char)] = &[('\u{11100}', '\u{11134}'), ('\u{11136}', '\u{11147}')];
+
+/// Canadian_Aboriginal, Cans
+pub const CANADIAN_ABORIGINAL: &[(char, char)] = &[
+    ('\u{1400}', '\u{167F}'),
+    ('\u{18B0}', '\u{18F5}'),
+    ('\u{11AB0}', '\u{11ABF}'),
+];
+
+/// Carian, Cari
+pub const CARIAN: &[(char, char)] = &[('\u{102A0}', '\u{102D0}')];
+
+/// Cham
+pub const CHAM: &[(char, char)] = &[
+    ('\u{AA00}', '\u{AA36}'),
+    ('\u{AA40}', '\u{AA4D}'),
+    ('\u{AA50}', '\u{AA59}'),
+    ('\u{AA5C}', '\u{AA5F}'),
+];
+
+/// Cherokee, Cher
+pub const CHEROKEE: &[(char, char)] = &[
+    ('\u{13A0}', '\u{13F5}'),
+    ('\u{13F8}', '\u{13FD}'),
+    ('\u{AB70}', '\u{ABBF}'),
+];
+
+/// Chorasmian, Chrs
+pub const CHORASMIAN: &[(char, char)] = &[('\u{10FB0}', '\u{10FCB}')];
+
+/// Coptic, Copt, Qaac
+pub const COPTIC: &[(char, char)] = &[
+    ('\u{3E2}', '\u{3EF}'),
+    ('\u{2C80}', '\u{2CF3}'),
+    ('\u{2CF9}', '\u{2CFF}'),
+];
+
+/// Cypro_Minoan, Cpmn
+pub const CYPRO_MINOAN: &[(char, char)] = &[('\u{12F90}', '\u{12FF2}')];
+
+/// Cypriot, Cprt
+pub const CYPRIOT: &[(char, char)] = &[
+    ('\u{10800}', '\u{10805}'),
+    ('\u{10808}', '\u{10808}'),
+    ('\u{1080A}', '\u{10835}'),
+    ('\u{10837}', '\u{10838}'),
+    ('\u{1083C}', '\u{1083C}'),
+    ('\u{1083F}', '\u{1083F}'),
+];
+
+/// Cyrillic, Cyrl
+pub const CYRILLIC: &[(char, char)] = &[
+    ('\u{400}', '\u{484}'),
+    ('\u{487}', '\u{52F}'),
+    ('\u{1C80}', '\u{1C88}'),
+    ('\u{1D2B}', '\u{1D2B}'),
+    ('\u{1D78}', '\u{1D78}'),
+    ('\u{2DE0}', '\u{2DFF}'),
+    ('\u{A640}', '\u{A69F}'),
+    ('\u{FE2E}', '\u{FE2F}'),
+];
+
+/// Devanagari, Deva
+pub const DEVANAGARI: &[(char, char)] = &[
+    ('\u{900}', '\u{950}'),
+    ('\u{955}', '\u{963}'),
+    ('\u{966}', '\u{97F}'),
+    ('\u{A8E0}', '\u{A8FF}'),
+];
+
+/// Dives_Akuru, Diak
+pub const DIVES_AKURU: &[(char, char)] = &[
+    ('\u{11900}', '\u{11906}'),
+    ('\u{11909}', '\u{11909}'),
+    ('\u{1190C}', '\u{11913}'),
+    ('\u{11915}', '\u{11916}'),
+    ('\u{11918}', '\u{11935}'),
+    ('\u{11937}', '\u{11938}'),
+    ('\u{1193B}', '\u{11946}'),
+    ('\u{11950}', '\u{11959}'),
+];
+
+/// Dogra, Dogr
+pub const DOGRA: &[(char, char)] = &[('\u{11800}', '\u{1183B}')];
+
+/// Deseret, Dsrt
+pub const DESERET: &[(char, char)] = &[('\u{10400}', '\u{1044F}')];
+
+/// Duployan, Dupl
+pub const DUPLOYAN: &[(char, char)] = &[
+    ('\u{1BC00}', '\u{1BC6A}'),
+    ('\u{1BC70}', '\u{1BC7C}'),
+    ('\u{1BC80}', '\u{1BC88}'),
+    ('\u{1BC90}', '\u{1BC99}'),
+    ('\u{1BC9C}', '\u{1BC9F}'),
+];
+
+/// Egyptian_Hieroglyphs, Egyp
+pub const EGYPTIAN_HIEROGLYPHS: &[(char, char)] =
+    &[('\u{13000}', '\u{1342E}'), ('\u{13430}', '\u{13438}')];
+
+/// Elbasan, Elba
+pub const ELBASAN: &[(char, char)] = &[('\u{10500}', '\u{10527}')];
+
+/// Elymaic, Elym
+pub const ELYMAIC: &[(char, char)] = &[('\u{10FE0}', '\u{10FF6}')];
+
+/// Ethiopic, Ethi
+pub const ETHIOPIC: &[(char, char)] = &[
+    ('\u{1200}', '\u{1248}'),
+    ('\u{124A}', '\u{124D}'),
+    ('\u{1250}', '\u{1256}'),
+    ('\u{1258}', '\u{1258}'),
+    ('\u{125A}', '\u{125D}'),
+    ('\u{1260}', '\u{1288}'),
+    ('\u{128A}', '\u{128D}'),
+    ('\u{1290}', '\u{12B0}'),
+    ('\u{12B2}', '\u{12B5}'),
+    ('\u{12B8}', '\u{12BE}'),
+    ('\u{12C0}', '\u{12C0}'),
+    ('\u{12C2}', '\u{12C5}'),
+    ('\u{12C8}', '\u{12D6}'),
+    ('\u{12D8}', '\u{1310}'),
+    ('\u{1312}', '\u{1315}'),
+    ('\u{1318}', '\u{135A}'),
+    ('\u{135D}', '\u{137C}'),
+    ('\u{1380}', '\u{1399}'),
+    ('\u{2D80}', '\u{2D96}'),
+    ('\u{2DA0}', '\u{2DA6}'),
+    ('\u{2DA8}', '\u{2DAE}'),
+    ('\u{2DB0}', '\u{2DB6}'),
+    ('\u{2DB8}', '\u{2DBE}'),
+    ('\u{2DC0}', '\u{2DC6}'),
+    ('\u{2DC8}', '\u{2DCE}'),
+    ('\u{2DD0}', '\u{2DD6}'),
+    ('\u{2DD8}', '\u{2DDE}'),
+    ('\u{AB01}', '\u{AB06}'),
+    ('\u{AB09}', '\u{AB0E}'),
+    ('\u{AB11}', '\u{AB16}'),
+    ('\u{AB20}', '\u{AB26}'),
+    ('\u{AB28}', '\u{AB2E}'),
+    ('\u{1E7E0}', '\u{1E7E6}'),
+    ('\u{1E7E8}', '\u{1E7EB}'),
+    ('\u{1E7ED}', '\u{1E7EE}'),
+    ('\u{1E7F0}', '\u{1E7FE}'),
+];
+
+/// Georgian, Geor
+pub const GEORGIAN: &[(char, char)] = &[
+    ('\u{10A0}', '\u{10C5}'),
+    ('\u{10C7}', '\u{10C7}'),
+    ('\u{10CD}', '\u{10CD}'),
+    ('\u{10D0}', '\u{10FA}'),
+    ('\u{10FC}', '\u{10FF}'),
+    ('\u{1C90}', '\u{1CBA}'),
+    ('\u{1CBD}', '\u{1CBF}'),
+    ('\u{2D00}', '\u{2D25}'),
+    ('\u{2D27}', '\u{2D27}'),
+    ('\u{2D2D}', '\u{2D2D}'),
+];
+
+/// Glagolitic, Glag
+pub const GLAGOLITIC: &[(char, char)] = &[
+    ('\u{2C00}', '\u{2C5F}'),
+    ('\u{1E000}', '\u{1E006}'),
+    ('\u{1E008}', '\u{1E018}'),
+    ('\u{1E01B}', '\u{1E021}'),
+    ('\u{1E023}', '\u{1E024}'),
+    ('\u{1E026}', '\u{1E02A}'),
+];
+
+/// Gunjala_Gondi, Gong
+pub const GUNJALA_GONDI: &[(char, char)] = &[
+    ('\u{11D60}', '\u{11D65}'),
+    ('\u{11D67}', '\u{11D68}'),
+    ('\u{11D6A}', '\u{11D8E}'),
+    ('\u{11D90}', '\u{11D91}'),
+    ('\u{11D93}', '\u{11D98}'),
+    ('\u{11DA0}', '\u{11DA9}'),
+];
+
+/// Masaram_Gondi, Gonm
+pub const MASARAM_GONDI: &[(char, char)] = &[
+    ('\u{11D00}', '\u{11D06}'),
+    ('\u{11D08}', '\u{11D09}'),
+    ('\u{11D0B}', '\u{11D36}'),
+    ('\u{11D3A}', '\u{11D3A}'),
+    ('\u{11D3C}', '\u{11D3D}'),
+    ('\u{11D3F}', '\u{11D47}'),
+    ('\u{11D50}', '\u{11D59}'),
+];
+
+/// Gothic, Goth
+pub const GOTHIC: &[(char, char)] = &[('\u{10330}', '\u{1034A}')];
+
+/// Grantha, Gran
+pub const GRANTHA: &[(char, char)] = &[
+    ('\u{11300}', '\u{11303}'),
+    ('\u{11305}', '\u{1130C}'),
+    ('\u{1130F}', '\u{11310}'),
+    ('\u{11313}', '\u{11328}'),
+    ('\u{1132A}', '\u{11330}'),
+    ('\u{11332}', '\u{11333}'),
+    ('\u{11335}', '\u{11339}'),
+    ('\u{1133C}', '\u{11344}'),
+    ('\u{11347}', '\u{11348}'),
+    ('\u{1134B}', '\u{1134D}'),
+    ('\u{11350}', '\u{11350}'),
+    ('\u{11357}', '\u{11357}'),
+    ('\u{1135D}', '\u{11363}'),
+    ('\u{11366}', '\u{1136C}'),
+    ('\u{11370}', '\u{11374}'),
+];
+
+/// Greek, Grek
+pub const GREEK: &[(char, char)] = &[
+    ('\u{370}', '\u{373}'),
+    ('\u{375}', '\u{377}'),
+    ('\u{37A}', '\u{37D}'),
+    ('\u{37F}', '\u{37F}'),
+    ('\u{384}', '\u{384}'),
+    ('\u{386}', '\u{386}'),
+    ('\u{388}', '\u{38A}'),
+    ('\u{38C}', '\u{38C}'),
+    ('\u{38E}', '\u{3A1}'),
+    ('\u{3A3}', '\u{3E1}'),
+    ('\u{3F0}', '\u{3FF}'),
+    ('\u{1D26}', '\u{1D2A}'),
+    ('\u{1D5D}', '\u{1D61}'),
+    ('\u{1D66}', '\u{1D6A}'),
+    ('\u{1DBF}', '\u{1DBF}'),
+    ('\u{1F00}', '\u{1F15}'),
+    ('\u{1F18}', '\u{1F1D}'),
+    ('\u{1F20}', '\u{1F45}'),
+    ('\u{1F48}', '\u{1F4D}'),
+    ('\u{1F50}', '\u{1F57}'),
+    ('\u{1F59}', '\u{1F59}'),
+    ('\u{1F5B}', '\u{1F5B}'),
+    ('\u{1F5D}', '\u{1F5D}'),
+    ('\u{1F5F}', '\u{1F7D}'),
+    ('\u{1F80}', '\u{1FB4}'),
+    ('\u{1FB6}', '\u{1FC4}'),
+    ('\u{1FC6}', '\u{1FD3}'),
+    ('\u{1FD6}', '\u{1FDB}'),
+    ('\u{1FDD}', '\u{1FEF}'),
+    ('\u{1FF2}', '\u{1FF4}'),
+    ('\u{1FF6}', '\u{1FFE}'),
+    ('\u{2126}', '\u{2126}'),
+    ('\u{AB65}', '\u{AB65}'),
+    ('\u{10140}', '\u{1018E}'),
+    ('\u{101A0}', '\u{101A0}'),
+    ('\u{1D200}', '\u{1D245}'),
+];
+
+/// Gujarati, Gujr
+pub const GUJARATI: &[(char, char)] = &[
+    ('\u{A81}', '\u{A83}'),
+    ('\u{A85}', '\u{A8D}'),
+    ('\u{A8F}', '\u{A91}'),
+    ('\u{A93}', '\u{AA8}'),
+    ('\u{AAA}', '\u{AB0}'),
+    ('\u{AB2}', '\u{AB3}'),
+    ('\u{AB5}', '\u{AB9}'),
+    ('\u{ABC}', '\u{AC5}'),
+    ('\u{AC7}', '\u{AC9}'),
+    ('\u{ACB}', '\u{ACD}'),
+    ('\u{AD0}', '\u{AD0}'),
+    ('\u{AE0}', '\u{AE3}'),
+    ('\u{AE6}', '\u{AF1}'),
+    ('\u{AF9}', '\u{AFF}'),
+];
+
+/// Gurmukhi, Guru
+pub const GURMUKHI: &[(char, char)] = &[
+    ('\u{A01}', '\u{A03}'),
+    ('\u{A05}', '\u{A0A}'),
+    ('\u{A0F}', '\u{A10}'),
+    ('\u{A13}', '\u{A28}'),
+    ('\u{A2A}', '\u{A30}'),
+    ('\u{A32}', '\u{A33}'),
+    ('\u{A35}', '\u{A36}'),
+    ('\u{A38}', '\u{A39}'),
+    ('\u{A3C}', '\u{A3C}'),
+    ('\u{A3E}', '\u{A42}'),
+    ('\u{A47}', '\u{A48}'),
+    ('\u{A4B}', '\u{A4D}'),
+    ('\u{A51}', '\u{A51}'),
+    ('\u{A59}', '\u{A5C}'),
+    ('\u{A5E}', '\u{A5E}'),
+    ('\u{A66}', '\u{A76}'),
+];
+
+/// Hangul, Hang
+pub const HANGUL: &[(char, char)] = &[
+    ('\u{1100}', '\u{11FF}'),
+    ('\u{302E}', '\u{302F}'),
+    ('\u{3131}', '\u{318E}'),
+    ('\u{3200}', '\u{321E}'),
+    ('\u{3260}', '\u{327E}'),
+    ('\u{A960}', '\u{A97C}'),
+    ('\u{AC00}', '\u{D7A3}'),
+    ('\u{D7B0}', '\u{D7C6}'),
+    ('\u{D7CB}', '\u{D7FB}'),
+    ('\u{FFA0}', '\u{FFBE}'),
+    ('\u{FFC2}', '\u{FFC7}'),
+    ('\u{FFCA}', '\u{FFCF}'),
+    ('\u{FFD2}', '\u{FFD7}'),
+    ('\u{FFDA}', '\u{FFDC}'),
+];
+
+/// Han, Hani
+pub const HAN: &[(char, char)] = &[
+    ('\u{2E80}', '\u{2E99}'),
+    ('\u{2E9B}', '\u{2EF3}'),
+    ('\u{2F00}', '\u{2FD5}'),
+    ('\u{3005}', '\u{3005}'),
+    ('\u{3007}', '\u{3007}'),
+    ('\u{3021}', '\u{3029}'),
+    ('\u{3038}', '\u{303B}'),
+    ('\u{3400}', '\u{4DBF}'),
+    ('\u{4E00}', '\u{9FFF}'),
+    ('\u{F900}', '\u{FA6D}'),
+    ('\u{FA70}', '\u{FAD9}'),
+    ('\u{16FE2}', '\u{16FE3}'),
+    ('\u{16FF0}', '\u{16FF1}'),
+    ('\u{20000}', '\u{2A6DF}'),
+    ('\u{2A700}', '\u{2B738}'),
+    ('\u{2B740}', '\u{2B81D}'),
+    ('\u{2B820}', '\u{2CEA1}'),
+    ('\u{2CEB0}', '\u{2EBE0}'),
+    ('\u{2F800}', '\u{2FA1D}'),
+    ('\u{30000}', '\u{3134A}'),
+];
+
+/// Hanunoo, Hano
+pub const HANUNOO: &[(char, char)] = &[('\u{1720}', '\u{1734}')];
+
+/// Hatran, Hatr
+pub const HATRAN: &[(char, char)] = &[
+    ('\u{108E0}', '\u{108F2}'),
+    ('\u{108F4}', '\u{108F5}'),
+    ('\u{108FB}', '\u{108FF}'),
+];
+
+/// Hebrew, Hebr
+pub const HEBREW: &[(char, char)] = &[
+    ('\u{591}', '\u{5C7}'),
+    ('\u{5D0}', '\u{5EA}'),
+    ('\u{5EF}', '\u{5F4}'),
+    ('\u{FB1D}', '\u{FB36}'),
+    ('\u{FB38}', '\u{FB3C}'),
+    ('\u{FB3E}', '\u{FB3E}'),
+    ('\u{FB40}', '\u{FB41}'),
+    ('\u{FB43}', '\u{FB44}'),
+    ('\u{FB46}', '\u{FB4F}'),
+];
+
+/// Hiragana, Hira
+pub const HIRAGANA: &[(char, char)] = &[
+    ('\u{3041}', '\u{3096}'),
+    ('\u{309D}', '\u{309F}'),
+    ('\u{1B001}', '\u{1B11F}'),
+    ('\u{1B150}', '\u{1B152}'),
+    ('\u{1F200}', '\u{1F200}'),
+];
+
+/// Anatolian_Hieroglyphs, Hluw
+pub const ANATOLIAN_HIEROGLYPHS: &[(char, char)] = &[('\u{14400}', '\u{14646}')];
+
+/// Pahawh_Hmong, Hmng
+pub const PAHAWH_HMONG: &[(char, char)] = &[
+    ('\u{16B00}', '\u{16B45}'),
+    ('\u{16B50}', '\u{16B59}'),
+    ('\u{16B5B}', '\u{16B61}'),
+    ('\u{16B63}', '\u{16B77}'),
+    ('\u{16B7D}', '\u{16B8F}'),
+];
+
+/// Nyiakeng_Puachue_Hmong, Hmnp
+pub const NYIAKENG_PUACHUE_HMONG: &[(char, char)] = &[
+    ('\u{1E100}', '\u{1E12C}'),
+    ('\u{1E130}', '\u{1E13D}'),
+    ('\u{1E140}', '\u{1E149}'),
+    ('\u{1E14E}', '\u{1E14F}'),
+];
+
+/// Old_Hungarian, Hung
+pub const OLD_HUNGARIAN: &[(char, char)] = &[
+    ('\u{10C80}', '\u{10CB2}'),
+    ('\u{10CC0}', '\u{10CF2}'),
+    ('\u{10CFA}', '\u{10CFF}'),
+];
+
+/// Old_Italic, Ital
+pub const OLD_ITALIC: &[(char, char)] = &[('\u{10300}', '\u{10323}'), ('\u{1032D}', '\u{1032F}')];
+
+/// Javanese, Java
+pub const JAVANESE: &[(char, char)] = &[
+    ('\u{A980}', '\u{A9CD}'),
+    ('\u{A9D0}', '\u{A9D9}'),
+    ('\u{A9DE}', '\u{A9DF}'),
+];
+
+/// Kayah_Li, Kali
+pub const KAYAH_LI: &[(char, char)] = &[('\u{A900}', '\u{A92D}'), ('\u{A92F}', '\u{A92F}')];
+
+/// Katakana, Kana
+pub const KATAKANA: &[(char, char)] = &[
+    ('\u{30A1}', '\u{30FA}'),
+    ('\u{30FD}', '\u{30FF}'),
+    ('\u{31F0}', '\u{31FF}'),
+    ('\u{32D0}', '\u{32FE}'),
+    ('\u{3300}', '\u{3357}'),
+    ('\u{FF66}', '\u{FF6F}'),
+    ('\u{FF71}', '\u{FF9D}'),
+    ('\u{1AFF0}', '\u{1AFF3}'),
+    ('\u{1AFF5}', '\u{1AFFB}'),
+    ('\u{1AFFD}', '\u{1AFFE}'),
+    ('\u{1B000}', '\u{1B000}'),
+    ('\u{1B120}', '\u{1B122}'),
+    ('\u{1B164}', '\u{1B167}'),
+];
+
+/// Kharoshthi, Khar
+pub const KHAROSHTHI: &[(char, char)] = &[
+    ('\u{10A00}', '\u{10A03}'),
+    ('\u{10A05}', '\u{10A06}'),
+    ('\u{10A0C}', '\u{10A13}'),
+    ('\u{10A15}', '\u{10A17}'),
+    ('\u{10A19}', '\u{10A35}'),
+    ('\u{10A38}', '\u{10A3A}'),
+    ('\u{10A3F}', '\u{10A48}'),
+    ('\u{10A50}', '\u{10A58}'),
+];
+
+/// Khmer, Khmr
+pub const KHMER: &[(char, char)] = &[
+    ('\u{1780}', '\u{17DD}'),
+    ('\u{17E0}', '\u{17E9}'),
+    ('\u{17F0}', '\u{17F9}'),
+    ('\u{19E0}', '\u{19FF}'),
+];
+
+/// Khojki, Khoj
+pub const KHOJKI: &[(char, char)] = &[('\u{11200}', '\u{11211}'), ('\u{11213}', '\u{1123E}')];
+
+/// Khitan_Small_Script, Kits
+pub const KHITAN_SMALL_SCRIPT: &[(char, char)] =
+    &[('\u{16FE4}', '\u{16FE4}'), ('\u{18B00}', '\u{18CD5}')];
+
+/// Kannada, Knda
+pub const KANNADA: &[(char, char)] = &[
+    ('\u{C80}', '\u{C8C}'),
+    ('\u{C8E}', '\u{C90}'),
+    ('\u{C92}', '\u{CA8}'),
+    ('\u{CAA}', '\u{CB3}'),
+    ('\u{CB5}', '\u{CB9}'),
+    ('\u{CBC}', '\u{CC4}'),
+    ('\u{CC6}', '\u{CC8}'),
+    ('\u{CCA}', '\u{CCD}'),
+    ('\u{CD5}', '\u{CD6}'),
+    ('\u{CDD}', '\u{CDE}'),
+    ('\u{CE0}', '\u{CE3}'),
+    ('\u{CE6}', '\u{CEF}'),
+    ('\u{CF1}', '\u{CF2}'),
+];
+
+/// Kaithi, Kthi
+pub const KAITHI: &[(char, char)] = &[('\u{11080}', '\u{110C2}'), ('\u{110CD}', '\u{110CD}')];
+
+/// Tai_Tham, Lana
+pub const TAI_THAM: &[(char, char)] = &[
+    ('\u{1A20}', '\u{1A5E}'),
+    ('\u{1A60}', '\u{1A7C}'),
+    ('\u{1A7F}', '\u{1A89}'),
+    ('\u{1A90}', '\u{1A99}'),
+    ('\u{1AA0}', '\u{1AAD}'),
+];
+
+/// Lao, Laoo
+pub const LAO: &[(char, char)] = &[
+    ('\u{E81}', '\u{E82}'),
+    ('\u{E84}', '\u{E84}'),
+    ('\u{E86}', '\u{E8A}'),
+    ('\u{E8C}', '\u{EA3}'),
+    ('\u{EA5}', '\u{EA5}'),
+    ('\u{EA7}', '\u{EBD}'),
+    ('\u{EC0}', '\u{EC4}'),
+    ('\u{EC6}', '\u{EC6}'),
+    ('\u{EC8}', '\u{ECD}'),
+    ('\u{ED0}', '\u{ED9}'),
+    ('\u{EDC}', '\u{EDF}'),
+];
+
+/// Latin, Latn
+pub const LATIN: &[(char, char)] = &[
+    ('\u{41}', '\u{5A}'),
+    ('\u{61}', '\u{7A}'),
+    ('\u{AA}', '\u{AA}'),
+    ('\u{BA}', '\u{BA}'),
+    ('\u{C0}', '\u{D6}'),
+    ('\u{D8}', '\u{F6}'),
+    ('\u{F8}', '\u{2B8}'),
+    ('\u{2E0}', '\u{2E4}'),
+    ('\u{1D00}', '\u{1D25}'),
+    ('\u{1D2C}', '\u{1D5C}'),
+    ('\u{1D62}', '\u{1D65}'),
+    ('\u{1D6B}', '\u{1D77}'),
+    ('\u{1D79}', '\u{1DBE}'),
+    ('\u{1E00}', '\u{1EFF}'),
+    ('\u{2071}', '\u{2071}'),
+    ('\u{207F}', '\u{207F}'),
+    ('\u{2090}', '\u{209C}'),
+    ('\u{212A}', '\u{212B}'),
+    ('\u{2132}', '\u{2132}'),
+    ('\u{214E}', '\u{214E}'),
+    ('\u{2160}', '\u{2188}'),
+    ('\u{2C60}', '\u{2C7F}'),
+    ('\u{A722}', '\u{A787}'),
+    ('\u{A78B}', '\u{A7CA}'),
+    ('\u{A7D0}', '\u{A7D1}'),
+    ('\u{A7D3}', '\u{A7D3}'),
+    ('\u{A7D5}', '\u{A7D9}'),
+    ('\u{A7F2}', '\u{A7FF}'),
+    ('\u{AB30}', '\u{AB5A}'),
+    ('\u{AB5C}', '\u{AB64}'),
+    ('\u{AB66}', '\u{AB69}'),
+    ('\u{FB00}', '\u{FB06}'),
+    ('\u{FF21}', '\u{FF3A}'),
+    ('\u{FF41}', '\u{FF5A}'),
+    ('\u{10780}', '\u{10785}'),
+    ('\u{10787}', '\u{107B0}'),
+    ('\u{107B2}', '\u{107BA}'),
+    ('\u{1DF00}', '\u{1DF1E}'),
+];
+
+/// Lepcha, Lepc
+pub const LEPCHA: &[(char, char)] = &[
+    ('\u{1C00}', '\u{1C37}'),
+    ('\u{1C3B}', '\u{1C49}'),
+    ('\u{1C4D}', '\u{1C4F}'),
+];
+
+/// Limbu, Limb
+pub const LIMBU: &[(char, char)] = &[
+    ('\u{1900}', '\u{191E}'),
+    ('\u{1920}', '\u{192B}'),
+    ('\u{1930}', '\u{193B}'),
+    ('\u{1940}', '\u{1940}'),
+    ('\u{1944}', '\u{194F}'),
+];
+
+/// Linear_A, Lina
+pub const LINEAR_A: &[(char, char)] = &[
+    ('\u{10600}', '\u{10736}'),
+    ('\u{10740}', '\u{10755}'),
+    ('\u{10760}', '\u{10767}'),
+];
+
+/// Linear_B, Linb
+pub const LINEAR_B: &[(char, char)] = &[
+    ('\u{10000}', '\u{1000B}'),
+    ('\u{1000D}', '\u{10026}'),
+    ('\u{10028}', '\u{1003A}'),
+    ('\u{1003C}', '\u{1003D}'),
+    ('\u{1003F}', '\u{1004D}'),
+    ('\u{10050}', '\u{1005D}'),
+    ('\u{10080}', '\u{100FA}'),
+];
+
+/// Lisu
+pub const LISU: &[(char, char)] = &[('\u{A4D0}', '\u{A4FF}'), ('\u{11FB0}', '\u{11FB0}')];
+
+/// Lycian, Lyci
+pub const LYCIAN: &[(char, char)] = &[('\u{10280}', '\u{1029C}')];
+
+/// Lydian, Lydi
+pub const LYDIAN: &[(char, char)] = &[('\u{10920}', '\u{10939}'), ('\u{1093F}', '\u{1093F}')];
+
+/// Mahajani, Mahj
+pub const MAHAJANI: &[(char, char)] = &[('\u{11150}', '\u{11176}')];
+
+/// Makasar, Maka
+pub const MAKASAR: &[(char, char)] = &[('\u{11EE0}', '\u{11EF8}')];
+
+/// Mandaic, Mand
+pub const MANDAIC: &[(char, char)] = &[('\u{840}', '\u{85B}'), ('\u{85E}', '\u{85E}')];
+
+/// Manichaean, Mani
+pub const MANICHAEAN: &[(char, char)] = &[('\u{10AC0}', '\u{10AE6}'), ('\u{10AEB}', '\u{10AF6}')];
+
+/// Marchen, Marc
+pub const MARCHEN: &[(char, char)] = &[
+    ('\u{11C70}', '\u{11C8F}'),
+    ('\u{11C92}', '\u{11CA7}'),
+    ('\u{11CA9}', '\u{11CB6}'),
+];
+
+/// Medefaidrin, Medf
+pub const MEDEFAIDRIN: &[(char, char)] = &[('\u{16E40}', '\u{16E9A}')];
+
+/// Mende_Kikakui, Mend
+pub const MENDE_KIKAKUI: &[(char, char)] =
+    &[('\u{1E800}', '\u{1E8C4}'), ('\u{1E8C7}', '\u{1E8D6}')];
+
+/// Meroitic_Cursive, Merc
+pub const MEROITIC_CURSIVE: &[(char, char)] = &[
+    ('\u{109A0}', '\u{109B7}'),
+    ('\u{109BC}', '\u{109CF}'),
+    ('\u{109D2}', '\u{109FF}'),
+];
+
+/// Meroitic_Hieroglyphs, Mero
+pub const MEROITIC_HIEROGLYPHS: &[(char, char)] = &[('\u{10980}', '\u{1099F}')];
+
+/// Malayalam, Mlym
+pub const MALAYALAM: &[(char, char)] = &[
+    ('\u{D00}', '\u{D0C}'),
+    ('\u{D0E}', '\u{D10}'),
+    ('\u{D12}', '\u{D44}'),
+    ('\u{D46}', '\u{D48}'),
+    ('\u{D4A}', '\u{D4F}'),
+    ('\u{D54}', '\u{D63}'),
+    ('\u{D66}', '\u{D7F}'),
+];
+
+/// Modi
+pub const MODI: &[(char, char)] = &[('\u{11600}', '\u{11644}'), ('\u{11650}', '\u{11659}')];
+
+/// Mongolian, Mong
+pub const MONGOLIAN: &[(char, char)] = &[
+    ('\u{1800}', '\u{1801}'),
+    ('\u{1804}', '\u{1804}'),
+    ('\u{1806}', '\u{1819}'),
+    ('\u{1820}', '\u{1878}'),
+    ('\u{1880}', '\u{18AA}'),
+    ('\u{11660}', '\u{1166C}'),
+];
+
+/// Mro, Mroo
+pub const MRO: &[(char, char)] = &[
+    ('\u{16A40}', '\u{16A5E}'),
+    ('\u{16A60}', '\u{16A69}'),
+    ('\u{16A6E}', '\u{16A6F}'),
+];
+
+/// Meetei_Mayek, Mtei
+pub const MEETEI_MAYEK: &[(char, char)] = &[
+    ('\u{AAE0}', '\u{AAF6}'),
+    ('\u{ABC0}', '\u{ABED}'),
+    ('\u{ABF0}', '\u{ABF9}'),
+];
+
+/// Multani, Mult
+pub const MULTANI: &[(char, char)] = &[
+    ('\u{11280}', '\u{11286}'),
+    ('\u{11288}', '\u{11288}'),
+    ('\u{1128A}', '\u{1128D}'),
+    ('\u{1128F}', '\u{1129D}'),
+    ('\u{1129F}', '\u{112A9}'),
+];
+
+/// Myanmar, Mymr
+pub const MYANMAR: &[(char, char)] = &[
+    ('\u{1000}', '\u{109F}'),
+    ('\u{A9E0}', '\u{A9FE}'),
+    ('\u{AA60}', '\u{AA7F}'),
+];
+
+/// Nandinagari, Nand
+pub const NANDINAGARI: &[(char, char)] = &[
+    ('\u{119A0}', '\u{119A7}'),
+    ('\u{119AA}', '\u{119D7}'),
+    ('\u{119DA}', '\u{119E4}'),
+];
+
+/// Old_North_Arabian, Narb
+pub const OLD_NORTH_ARABIAN: &[(char, char)] = &[('\u{10A80}', '\u{10A9F}')];
+
+/// Nabataean, Nbat
+pub const NABATAEAN: &[(char, char)] = &[('\u{10880}', '\u{1089E}'), ('\u{108A7}', '\u{108AF}')];
+
+/// Newa
+pub const NEWA: &[(char, char)] = &[('\u{11400}', '\u{1145B}'), ('\u{1145D}', '\u{11461}')];
+
+/// Nko, Nkoo
+pub const NKO: &[(char, char)] = &[('\u{7C0}', '\u{7FA}'), ('\u{7FD}', '\u{7FF}')];
+
+/// Nushu, Nshu
+pub const NUSHU: &[(char, char)] = &[('\u{16FE1}', '\u{16FE1}'), ('\u{1B170}', '\u{1B2FB}')];
+
+/// Ogham, Ogam
+pub const OGHAM: &[(char, char)] = &[('\u{1680}', '\u{169C}')];
+
+/// Ol_Chiki, Olck
+pub const OL_CHIKI: &[(char, char)] = &[('\u{1C50}', '\u{1C7F}')];
+
+/// Old_Turkic, Orkh
+pub const OLD_TURKIC: &[(char, char)] = &[('\u{10C00}', '\u{10C48}')];
+
+/// Oriya, Orya
+pub const ORIYA: &[(char, char)] = &[
+    ('\u{B01}', '\u{B03}'),
+    ('\u{B05}', '\u{B0C}'),
+    ('\u{B0F}', '\u{B10}'),
+    ('\u{B13}', '\u{B28}'),
+    ('\u{B2A}', '\u{B30}'),
+    ('\u{B32}', '\u{B33}'),
+    ('\u{B35}', '\u{B39}'),
+    ('\u{B3C}', '\u{B44}'),
+    ('\u{B47}', '\u{B48}'),
+    ('\u{B4B}', '\u{B4D}'),
+    ('\u{B55}', '\u{B57}'),
+    ('\u{B5C}', '\u{B5D}'),
+    ('\u{B5F}', '\u{B63}'),
+    ('\u{B66}', '\u{B77}'),
+];
+
+/// Osage, Osge
+pub const OSAGE: &[(char, char)] = &[('\u{104B0}', '\u{104D3}'), ('\u{104D8}', '\u{104FB}')];
+
+/// Osmanya, Osma
+pub const OSMANYA: &[(char, char)] = &[('\u{10480}', '\u{1049D}'), ('\u{104A0}', '\u{104A9}')];
+
+/// Old_Uyghur, Ougr
+pub const OLD_UYGHUR: &[(char, char)] = &[('\u{10F70}', '\u{10F89}')];
+
+/// Palmyrene, Palm
+pub const PALMYRENE: &[(char, char)] = &[('\u{10860}', '\u{1087F}')];
+
+/// Pau_Cin_Hau, Pauc
+pub const PAU_CIN_HAU: &[(char, char)] = &[('\u{11AC0}', '\u{11AF8}')];
+
+/// Old_Permic, Perm
+pub const OLD_PERMIC: &[(char, char)] = &[('\u{10350}', '\u{1037A}')];
+
+/// Phags_Pa, Phag
+pub const PHAGS_PA: &[(char, char)] = &[('\u{A840}', '\u{A877}')];
+
+/// Inscriptional_Pahlavi, Phli
+pub const INSCRIPTIONAL_PAHLAVI: &[(char, char)] =
+    &[('\u{10B60}', '\u{10B72}'), ('\u{10B78}', '\u{10B7F}')];
+
+/// Psalter_Pahlavi, Phlp
+pub const PSALTER_PAHLAVI: &[(char, char)] = &[
+    ('\u{10B80}', '\u{10B91}'),
+    ('\u{10B99}', '\u{10B9C}'),
+    ('\u{10BA9}', '\u{10BAF}'),
+];
+
+/// Phoenician, Phnx
+pub const PHOENICIAN: &[(char, char)] = &[('\u{10900}', '\u{1091B}'), ('\u{1091F}', '\u{1091F}')];
+
+/// Miao, Plrd
+pub const MIAO: &[(char, char)] = &[
+    ('\u{16F00}', '\u{16F4A}'),
+    ('\u{16F4F}', '\u{16F87}'),
+    ('\u{16F8F}', '\u{16F9F}'),
+];
+
+/// Inscriptional_Parthian, Prti
+pub const INSCRIPTIONAL_PARTHIAN: &[(char, char)] =
+    &[('\u{10B40}', '\u{10B55}'), ('\u{10B58}', '\u{10B5F}')];
+
+/// Rejang, Rjng
+pub const REJANG: &[(char, char)] = &[('\u{A930}', '\u{A953}'), ('\u{A95F}', '\u{A95F}')];
+
+/// Hanifi_Rohingya, Rohg
+pub const HANIFI_ROHINGYA: &[(char, char)] =
+    &[('\u{10D00}', '\u{10D27}'), ('\u{10D30}', '\u{10D39}')];
+
+/// Runic, Runr
+pub const RUNIC: &[(char, char)] = &[('\u{16A0}', '\u{16EA}'), ('\u{16EE}', '\u{16F8}')];
+
+/// Samaritan, Samr
+pub const SAMARITAN: &[(char, char)] = &[('\u{800}', '\u{82D}'), ('\u{830}', '\u{83E}')];
+
+/// Old_South_Arabian, Sarb
+pub const OLD_SOUTH_ARABIAN: &[(char, char)] = &[('\u{10A60}', '\u{10A7F}')];
+
+/// Saurashtra, Saur
+pub const SAURASHTRA: &[(char, char)] = &[('\u{A880}', '\u{A8C5}'), ('\u{A8CE}', '\u{A8D9}')];
+
+/// SignWriting, Sgnw
+pub const SIGNWRITING: &[(char, char)] = &[
+    ('\u{1D800}', '\u{1DA8B}'),
+    ('\u{1DA9B}', '\u{1DA9F}'),
+    ('\u{1DAA1}', '\u{1DAAF}'),
+];
+
+/// Shavian, Shaw
+pub const SHAVIAN: &[(char, char)] = &[('\u{10450}', '\u{1047F}')];
+
+/// Sharada, Shrd
+pub const SHARADA: &[(char, char)] = &[('\u{11180}', '\u{111DF}')];
+
+/// Siddham, Sidd
+pub const SIDDHAM: &[(char, char)] = &[('\u{11580}', '\u{115B5}'), ('\u{115B8}', '\u{115DD}')];
+
+/// Khudawadi, Sind
+pub const KHUDAWADI: &[(char, char)] = &[('\u{112B0}', '\u{112EA}'), ('\u{112F0}', '\u{112F9}')];
+
+/// Sinhala, Sinh
+pub const SINHALA: &[(char, char)] = &[
+    ('\u{D81}', '\u{D83}'),
+    ('\u{D85}', '\u{D96}'),
+    ('\u{D9A}', '\u{DB1}'),
+    ('\u{DB3}', '\u{DBB}'),
+    ('\u{DBD}', '\u{DBD}'),
+    ('\u{DC0}', '\u{DC6}'),
+    ('\u{DCA}', '\u{DCA}'),
+    ('\u{DCF}', '\u{DD4}'),
+    ('\u{DD6}', '\u{DD6}'),
+    ('\u{DD8}', '\u{DDF}'),
+    ('\u{DE6}', '\u{DEF}'),
+    ('\u{DF2}', '\u{DF4}'),
+    ('\u{111E1}', '\u{111F4}'),
+];
+
+/// Sogdian, Sogd
+pub const SOGDIAN: &[(char, char)] = &[('\u{10F30}', '\u{10F59}')];
+
+/// Old_Sogdian, Sogo
+pub const OLD_SOGDIAN: &[(char, char)] = &[('\u{10F00}', '\u{10F27}')];
+
+/// Sora_Sompeng, Sora
+pub const SORA_SOMPENG: &[(char, char)] = &[('\u{110D0}', '\u{110E8}'), ('\u{110F0}', '\u{110F9}')];
+
+/// Soyombo, Soyo
+pub const SOYOMBO: &[(char, char)] = &[('\u{11A50}', '\u{11AA2}')];
+
+/// Sundanese, Sund
+pub const SUNDANESE: &[(char, char)] = &[('\u{1B80}', '\u{1BBF}'), ('\u{1CC0}', '\u{1CC7}')];
+
+/// Syloti_Nagri, Sylo
+pub const SYLOTI_NAGRI: &[(char, char)] = &[('\u{A800}', '\u{A82C}')];
+
+/// Syriac, Syrc
+pub const SYRIAC: &[(char, char)] = &[
+    ('\u{700}', '\u{70D}'),
+    ('\u{70F}', '\u{74A}'),
+    ('\u{74D}', '\u{74F}'),
+    ('\u{860}', '\u{86A}'),
+];
+
+/// Tagbanwa, Tagb
+pub const TAGBANWA: &[(char, char)] = &[
+    ('\u{1760}', '\u{176C}'),
+    ('\u{176E}', '\u{1770}'),
+    ('\u{1772}', '\u{1773}'),
+];
+
+/// Takri, Takr
+pub const TAKRI: &[(char, char)] = &[('\u{11680}', '\u{116B9}'), ('\u{116C0}', '\u{116C9}')];
+
+/// Tai_Le, Tale
+pub const TAI_LE: &[(char, char)] = &[('\u{1950}', '\u{196D}'), ('\u{1970}', '\u{1974}')];
+
+/// New_Tai_Lue, Talu
+pub const NEW_TAI_LUE: &[(char, char)] = &[
+    ('\u{1980}', '\u{19AB}'),
+    ('\u{19B0}', '\u{19C9}'),
+    ('\u{19D0}', '\u{19DA}'),
+    ('\u{19DE}', '\u{19DF}'),
+];
+
+/// Tamil, Taml
+pub const TAMIL: &[(char, char)] = &[
+    ('\u{B82}', '\u{B83}'),
+    ('\u{B85}', '\u{B8A}'),
+    ('\u{B8E}', '\u{B90}'),
+    ('\u{B92}', '\u{B95}'),
+    ('\u{B99}', '\u{B9A}'),
+    ('\u{B9C}', '\u{B9C}'),
+    ('\u{B9E}', '\u{B9F}'),
+    ('\u{BA3}', '\u{BA4}'),
+    ('\u{BA8}', '\u{BAA}'),
+    ('\u{BAE}', '\u{BB9}'),
+    ('\u{BBE}', '\u{BC2}'),
+    ('\u{BC6}', '\u{BC8}'),
+    ('\u{BCA}', '\u{BCD}'),
+    ('\u{BD0}', '\u{BD0}'),
+    ('\u{BD7}', '\u{BD7}'),
+    ('\u{BE6}', '\u{BFA}'),
+    ('\u{11FC0}', '\u{11FF1}'),
+    ('\u{11FFF}', '\u{11FFF}'),
+];
+
+/// Tangut, Tang
+pub const TANGUT: &[(char, char)] = &[
+    ('\u{16FE0}', '\u{16FE0}'),
+    ('\u{17000}', '\u{187F7}'),
+    ('\u{18800}', '\u{18AFF}'),
+    ('\u{18D00}', '\u{18D08}'),
+];
+
+/// Tai_Viet, Tavt
+pub const TAI_VIET: &[(char, char)] = &[('\u{AA80}', '\u{AAC2}'), ('\u{AADB}', '\u{AADF}')];
+
+/// Telugu, Telu
+pub const TELUGU: &[(char, char)] = &[
+    ('\u{C00}', '\u{C0C}'),
+    ('\u{C0E}', '\u{C10}'),
+    ('\u{C12}', '\u{C28}'),
+    ('\u{C2A}', '\u{C39}'),
+    ('\u{C3C}', '\u{C44}'),
+    ('\u{C46}', '\u{C48}'),
+    ('\u{C4A}', '\u{C4D}'),
+    ('\u{C55}', '\u{C56}'),
+    ('\u{C58}', '\u{C5A}'),
+    ('\u{C5D}', '\u{C5D}'),
+    ('\u{C60}', '\u{C63}'),
+    ('\u{C66}', '\u{C6F}'),
+    ('\u{C77}', '\u{C7F}'),
+];
+
+/// Tifinagh, Tfng
+pub const TIFINAGH: &[(char, char)] = &[
+    ('\u{2D30}', '\u{2D67}'),
+    ('\u{2D6F}', '\u{2D70}'),
+    ('\u{2D7F}', '\u{2D7F}'),
+];
+
+/// Tagalog, Tglg
+pub const TAGALOG: &[(char, char)] = &[('\u{1700}', '\u{1715}'), ('\u{171F}', '\u{171F}')];
+
+/// Thaana, Thaa
+pub const THAANA: &[(char, char)] = &[('\u{780}', '\u{7B1}')];
+
+/// Thai
+pub const THAI: &[(char, char)] = &[('\u{E01}', '\u{E3A}'), ('\u{E40}', '\u{E5B}')];
+
+/// Tibetan, Tibt
+pub const TIBETAN: &[(char, char)] = &[
+    ('\u{F00}', '\u{F47}'),
+    ('\u{F49}', '\u{F6C}'),
+    ('\u{F71}', '\u{F97}'),
+    ('\u{F99}', '\u{FBC}'),
+    ('\u{FBE}', '\u{FCC}'),
+    ('\u{FCE}', '\u{FD4}'),
+    ('\u{FD9}', '\u{FDA}'),
+];
+
+/// Tirhuta, Tirh
+pub const TIRHUTA: &[(char, char)] = &[('\u{11480}', '\u{114C7}'), ('\u{114D0}', '\u{114D9}')];
+
+/// Tangsa, Tnsa
+pub const TANGSA: &[(char, char)] = &[('\u{16A70}', '\u{16ABE}'), ('\u{16AC0}', '\u{16AC9}')];
+
+/// Toto
+pub const TOTO: &[(char, char)] = &[('\u{1E290}', '\u{1E2AE}')];
+
+/// Ugaritic, Ugar
+pub const UGARITIC: &[(char, char)] = &[('\u{10380}', '\u{1039D}'), ('\u{1039F}', '\u{1039F}')];
+
+/// Vai, Vaii
+pub const VAI: &[(char, char)] = &[('\u{A500}', '\u{A62B}')];
+
+/// Vithkuqi, Vith
+pub const VITHKUQI: &[(char, char)] = &[
+    ('\u{10570}', '\u{1057A}'),
+    ('\u{1057C}', '\u{1058A}'),
+    ('\u{1058C}', '\u{10592}'),
+    ('\u{10594}', '\u{10595}'),
+    ('\u{10597}', '\u{105A1}'),
+    ('\u{105A3}', '\u{105B1}'),
+    ('\u{105B3}', '\u{105B9}'),
+    ('\u{105BB}', '\u{105BC}'),
+];
+
+/// Warang_Citi, Wara
+pub const WARANG_CITI: &[(char, char)] = &[('\u{118A0}', '\u{118F2}'), ('\u{118FF}', '\u{118FF}')];
+
+/// Wancho, Wcho
+pub const WANCHO: &[(char, char)] = &[('\u{1E2C0}', '\u{1E2F9}'), ('\u{1E2FF}', '\u{1E2FF}')];
+
+/// Old_Persian, Xpeo
+pub const OLD_PERSIAN: &[(char, char)] = &[('\u{103A0}', '\u{103C3}'), ('\u{103C8}', '\u{103D5}')];
+
+/// Cuneiform, Xsux
+pub const CUNEIFORM: &[(char, char)] = &[
+    ('\u{12000}', '\u{12399}'),
+    ('\u{12400}', '\u{1246E}'),
+    ('\u{12470}', '\u{12474}'),
+    ('\u{12480}', '\u{12543}'),
+];
+
+/// Yezidi, Yezi
+pub const YEZIDI: &[(char, char)] = &[
+    ('\u{10E80}', '\u{10EA9}'),
+    ('\u{10EAB}', '\u{10EAD}'),
+    ('\u{10EB0}', '\u{10EB1}'),
+];
+
+/// Yi, Yiii
+pub const YI: &[(char, char)] = &[('\u{A000}', '\u{A48C}'), ('\u{A490}', '\u{A4C6}')];
+
+/// Zanabazar_Square, Zanb
+pub const ZANABAZAR_SQUARE: &[(char, char)] = &[('\u{11A00}', '\u{11A47}')];
+
+/// Inherited, Zinh, Qaai
+pub const INHERITED: &[(char, char)] = &[
+    ('\u{300}', '\u{36F}'),
+    ('\u{485}', '\u{486}'),
+    ('\u{64B}', '\u{655}'),
+    ('\u{670}', '\u{670}'),
+    ('\u{951}', '\u{954}'),
+    ('\u{1AB0}', '\u{1ACE}'),
+    ('\u{1CD0}', '\u{1CD2}'),
+    ('\u{1CD4}', '\u{1CE0}'),
+    ('\u{1CE2}', '\u{1CE8}'),
+    ('\u{1CED}', '\u{1CED}'),
+    ('\u{1CF4}', '\u{1CF4}'),
+    ('\u{1CF8}', '\u{1CF9}'),
+    ('\u{1DC0}', '\u{1DFF}'),
+    ('\u{200C}', '\u{200D}'),
+    ('\u{20D0}', '\u{20F0}'),
+    ('\u{302A}', '\u{302D}'),
+    ('\u{3099}', '\u{309A}'),
+    ('\u{FE00}', '\u{FE0F}'),
+    ('\u{FE20}', '\u{FE2D}'),
+    ('\u{101FD}', '\u{101FD}'),
+    ('\u{102E0}', '\u{102E0}'),
+    ('\u{1133B}', '\u{1133B}'),
+    ('\u{1CF00}', '\u{1CF2D}'),
+    ('\u{1CF30}', '\u{1CF46}'),
+    ('\u{1D167}', '\u{1D169}'),
+    ('\u{1D17B}', '\u{1D182}'),
+    ('\u{1D185}', '\u{1D18B}'),
+    ('\u{1D1AA}', '\u{1D1AD}'),
+    ('\u{E0100}', '\u{E01EF}'),
+];
+
+/// Common, Zyyy
+pub const COMMON: &[(char, char)] = &[
+    ('\u{0}', '\u{40}'),
+    ('\u{5B}', '\u{60}'),
+    ('\u{7B}', '\u{A9}'),
+    ('\u{AB}', '\u{B9}'),
+    ('\u{BB}', '\u{BF}'),
+    ('\u{D7}', '\u{D7}'),
+    ('\u{F7}', '\u{F7}'),
+    ('\u{2B9}', '\u{2DF}'),
+    ('\u{2E5}', '\u{2E9}'),
+    ('\u{2EC}', '\u{2FF}'),
+    ('\u{374}', '\u{374}'),
+    ('\u{37E}', '\u{37E}'),
+    ('\u{385}', '\u{385}'),
+    ('\u{387}', '\u{387}'),
+    ('\u{605}', '\u{605}'),
+    ('\u{60C}', '\u{60C}'),
+    ('\u{61B}', '\u{61B}'),
+    ('\u{61F}', '\u{61F}'),
+    ('\u{640}', '\u{640}'),
+    ('\u{6DD}', '\u{6DD}'),
+    ('\u{8E2}', '\u{8E2}'),
+    ('\u{964}', '\u{965}'),
+    ('\u{E3F}', '\u{E3F}'),
+    ('\u{FD5}', '\u{FD8}'),
+    ('\u{10FB}', '\u{10FB}'),
+    ('\u{16EB}', '\u{16ED}'),
+    ('\u{1735}', '\u{1736}'),
+    ('\u{1802}', '\u{1803}'),
+    ('\u{1805}', '\u{1805}'),
+    ('\u{1CD3}', '\u{1CD3}'),
+    ('\u{1CE1}', '\u{1CE1}'),
+    ('\u{1CE9}', '\u{1CEC}'),
+    ('\u{1CEE}', '\u{1CF3}'),
+    ('\u{1CF5}', '\u{1CF7}'),
+    ('\u{1CFA}', '\u{1CFA}'),
+    ('\u{2000}', '\u{200B}'),
+    ('\u{200E}', '\u{2064}'),
+    ('\u{2066}', '\u{2070}'),
+    ('\u{2074}', '\u{207E}'),
+    ('\u{2080}', '\u{208E}'),
+    ('\u{20A0}', '\u{20C0}'),
+    ('\u{2100}', '\u{2125}'),
+    ('\u{2127}', '\u{2129}'),
+    ('\u{212C}', '\u{2131}'),
+    ('\u{2133}', '\u{214D}'),
+    ('\u{214F}', '\u{215F}'),
+    ('\u{2189}', '\u{218B}'),
+    ('\u{2190}', '\u{2426}'),
+    ('\u{2440}', '\u{244A}'),
+    ('\u{2460}', '\u{27FF}'),
+    ('\u{2900}', '\u{2B73}'),
+    ('\u{2B76}', '\u{2B95}'),
+    ('\u{2B97}', '\u{2BFF}'),
+    ('\u{2E00}', '\u{2E5D}'),
+    ('\u{2FF0}', '\u{2FFB}'),
+    ('\u{3000}', '\u{3004}'),
+    ('\u{3006}', '\u{3006}'),
+    ('\u{3008}', '\u{3020}'),
+    ('\u{3030}', '\u{3037}'),
+    ('\u{303C}', '\u{303F}'),
+    ('\u{309B}', '\u{309C}'),
+    ('\u{30A0}', '\u{30A0}'),
+    ('\u{30FB}', '\u{30FC}'),
+    ('\u{3190}', '\u{319F}'),
+    ('\u{31C0}', '\u{31E3}'),
+    ('\u{3220}', '\u{325F}'),
+    ('\u{327F}', '\u{32CF}'),
+    ('\u{32FF}', '\u{32FF}'),
+    ('\u{3358}', '\u{33FF}'),
+    ('\u{4DC0}', '\u{4DFF}'),
+    ('\u{A700}', '\u{A721}'),
+    ('\u{A788}', '\u{A78A}'),
+    ('\u{A830}', '\u{A839}'),
+    ('\u{A92E}', '\u{A92E}'),
+    ('\u{A9CF}', '\u{A9CF}'),
+    ('\u{AB5B}', '\u{AB5B}'),
+    ('\u{AB6A}', '\u{AB6B}'),
+    ('\u{FD3E}', '\u{FD3F}'),
+    ('\u{FE10}', '\u{FE19}'),
+    ('\u{FE30}', '\u{FE52}'),
+    ('\u{FE54}', '\u{FE66}'),
+    ('\u{FE68}', '\u{FE6B}'),
+    ('\u{FEFF}', '\u{FEFF}'),
+    ('\u{FF01}', '\u{FF20}'),
+    ('\u{FF3B}', '\u{FF40}'),
+    ('\u{FF5B}', '\u{FF65}'),
+    ('\u{FF70}', '\u{FF70}'),
+    ('\u{FF9E}', '\u{FF9F}'),
+    ('\u{FFE0}', '\u{FFE6}'),
+    ('\u{FFE8}', '\u{FFEE}'),
+    ('\u{FFF9}', '\u{FFFD}'),
+    ('\u{10100}', '\u{10102}'),
+    ('\u{10107}', '\u{10133}'),
+    ('\u{10137}', '\u{1013F}'),
+    ('\u{10190}', '\u{1019C}'),
+    ('\u{101D0}', '\u{101FC}'),
+    ('\u{102E1}', '\u{102FB}'),
+    ('\u{1BCA0}', '\u{1BCA3}'),
+    ('\u{1CF50}', '\u{1CFC3}'),
+    ('\u{1D000}', '\u{1D0F5}'),
+    ('\u{1D100}', '\u{1D126}'),
+    ('\u{1D129}', '\u{1D166}'),
+    ('\u{1D16A}', '\u{1D17A}'),
+    ('\u{1D183}', '\u{1D184}'),
+    ('\u{1D18C}', '\u{1D1A9}'),
+    ('\u{1D1AE}', '\u{1D1EA}'),
+    ('\u{1D2E0}', '\u{1D2F3}'),
+    ('\u{1D300}', '\u{1D356}'),
+    ('\u{1D360}', '\u{1D378}'),
+    ('\u{1D400}', '\u{1D454}'),
+    ('\u{1D456}', '\u{1D49C}'),
+    ('\u{1D49E}', '\u{1D49F}'),
+    ('\u{1D4A2}', '\u{1D4A2}'),
+    ('\u{1D4A5}', '\u{1D4A6}'),
+    ('\u{1D4A9}', '\u{1D4AC}'),
+    ('\u{1D4AE}', '\u{1D4B9}'),
+    ('\u{1D4BB}', '\u{1D4BB}'),
+    ('\u{1D4BD}', '\u{1D4C3}'),
+    ('\u{1D4C5}', '\u{1D505}'),
+    ('\u{1D507}', '\u{1D50A}'),
+    ('\u{1D50D}', '\u{1D514}'),
+    ('\u{1D516}', '\u{1D51C}'),
+    ('\u{1D51E}', '\u{1D539}'),
+    ('\u{1D53B}', '\u{1D53E}'),
+    ('\u{1D540}', '\u{1D544}'),
+    ('\u{1D546}', '\u{1D546}'),
+    ('\u{1D54A}', '\u{1D550}'),
+    ('\u{1D552}', '\u{1D6A5}'),
+    ('\u{1D6A8}', '\u{1D7CB}'),
+    ('\u{1D7CE}', '\u{1D7FF}'),
+    ('\u{1EC71}', '\u{1ECB4}'),
+    ('\u{1ED01}', '\u{1ED3D}'),
+    ('\u{1F000}', '\u{1F02B}'),
+    ('\u{1F030}', '\u{1F093}'),
+    ('\u{1F0A0}', '\u{1F0AE}'),
+    ('\u{1F0B1}', '\u{1F0BF}'),
+    ('\u{1F0C1}', '\u{1F0CF}'),
+    ('\u{1F0D1}', '\u{1F0F5}'),
+    ('\u{1F100}', '\u{1F1AD}'),
+    ('\u{1F1E6}', '\u{1F1FF}'),
+    ('\u{1F201}', '\u{1F202}'),
+    ('\u{1F210}', '\u{1F23B}'),
+    ('\u{1F240}', '\u{1F248}'),
+    ('\u{1F250}', '\u{1F251}'),
+    ('\u{1F260}', '\u{1F265}'),
+    ('\u{1F300}', '\u{1F6D7}'),
+    ('\u{1F6DD}', '\u{1F6EC}'),
+    ('\u{1F6F0}', '\u{1F6FC}'),
+    ('\u{1F700}', '\u{1F773}'),
+    ('\u{1F780}', '\u{1F7D8}'),
+    ('\u{1F7E0}', '\u{1F7EB}'),
+    ('\u{1F7F0}', '\u{1F7F0}'),
+    ('\u{1F800}', '\u{1F80B}'),
+    ('\u{1F810}', '\u{1F847}'),
+    ('\u{1F850}', '\u{1F859}'),
+    ('\u{1F860}', '\u{1F887}'),
+    ('\u{1F890}', '\u{1F8AD}'),
+    ('\u{1F8B0}', '\u{1F8B1}'),
+    ('\u{1F900}', '\u{1FA53}'),
+    ('\u{1FA60}', '\u{1FA6D}'),
+    ('\u{1FA70}', '\u{1FA74}'),
+    ('\u{1FA78}', '\u{1FA7C}'),
+    ('\u{1FA80}', '\u{1FA86}'),
+    ('\u{1FA90}', '\u{1FAAC}'),
+    ('\u{1FAB0}', '\u{1FABA}'),
+    ('\u{1FAC0}', '\u{1FAC5}'),
+    ('\u{1FAD0}', '\u{1FAD9}'),
+    ('\u{1FAE0}', '\u{1FAE7}'),
+    ('\u{1FAF0}', '\u{1FAF6}'),
+    ('\u{1FB00}', '\u{1FB92}'),
+    ('\u{1FB94}', '\u{1FBCA}'),
+    ('\u{1FBF0}', '\u{1FBF9}'),
+    ('\u{E0001}', '\u{E0001}'),
+    ('\u{E0020}', '\u{E007F}'),
+];
+
+/// Unknown, Zzzz
+pub const UNKNOWN: &[(char, char)] = &[
+    ('\u{378}', '\u{379}'),
+    ('\u{380}', '\u{383}'),
+    ('\u{38B}', '\u{38B}'),
+    ('\u{38D}', '\u{38D}'),
+    ('\u{3A2}', '\u{3A2}'),
+    ('\u{530}', '\u{530}'),
+    ('\u{557}', '\u{558}'),
+    ('\u{58B}', '\u{58C}'),
+    ('\u{590}', '\u{590}'),
+    ('\u{5C8}', '\u{5CF}'),
+    ('\u{5EB}', '\u{5EE}'),
+    ('\u{5F5}', '\u{5FF}'),
+    ('\u{70E}', '\u{70E}'),
+    ('\u{74B}', '\u{74C}'),
+    ('\u{7B2}', '\u{7BF}'),
+    ('\u{7FB}', '\u{7FC}'),
+    ('\u{82E}', '\u{82F}'),
+    ('\u{83F}', '\u{83F}'),
+    ('\u{85C}', '\u{85D}'),
+    ('\u{85F}', '\u{85F}'),
+    ('\u{86B}', '\u{86F}'),
+    ('\u{88F}', '\u{88F}'),
+    ('\u{892}', '\u{897}'),
+    ('\u{984}', '\u{984}'),
+    ('\u{98D}', '\u{98E}'),
+    ('\u{991}', '\u{992}'),
+    ('\u{9A9}', '\u{9A9}'),
+    ('\u{9B1}', '\u{9B1}'),
+    ('\u{9B3}', '\u{9B5}'),
+    ('\u{9BA}', '\u{9BB}'),
+    ('\u{9C5}', '\u{9C6}'),
+    ('\u{9C9}', '\u{9CA}'),
+    ('\u{9CF}', '\u{9D6}'),
+    ('\u{9D8}', '\u{9DB}'),
+    ('\u{9DE}', '\u{9DE}'),
+    ('\u{9E4}', '\u{9E5}'),
+    ('\u{9FF}', '\u{A00}'),
+    ('\u{A04}', '\u{A04}'),
+    ('\u{A0B}', '\u{A0E}'),
+    ('\u{A11}', '\u{A12}'),
+    ('\u{A29}', '\u{A29}'),
+    ('\u{A31}', '\u{A31}'),
+    ('\u{A34}', '\u{A34}'),
+    ('\u{A37}', '\u{A37}'),
+    ('\u{A3A}', '\u{A3B}'),
+    ('\u{A3D}', '\u{A3D}'),
+    ('\u{A43}', '\u{A46}'),
+    ('\u{A49}', '\u{A4A}'),
+    ('\u{A4E}', '\u{A50}'),
+    ('\u{A52}', '\u{A58}'),
+    ('\u{A5D}', '\u{A5D}'),
+    ('\u{A5F}', '\u{A65}'),
+    ('\u{A77}', '\u{A80}'),
+    ('\u{A84}', '\u{A84}'),
+    ('\u{A8E}', '\u{A8E}'),
+    ('\u{A92}', '\u{A92}'),
+    ('\u{AA9}', '\u{AA9}'),
+    ('\u{AB1}', '\u{AB1}'),
+    ('\u{AB4}', '\u{AB4}'),
+    ('\u{ABA}', '\u{ABB}'),
+    ('\u{AC6}', '\u{AC6}'),
+    ('\u{ACA}', '\u{ACA}'),
+    ('\u{ACE}', '\u{ACF}'),
+    ('\u{AD1}', '\u{ADF}'),
+    ('\u{AE4}', '\u{AE5}'),
+    ('\u{AF2}', '\u{AF8}'),
+    ('\u{B00}', '\u{B00}'),
+    ('\u{B04}', '\u{B04}'),
+    ('\u{B0D}', '\u{B0E}'),
+    ('\u{B11}', '\u{B12}'),
+    ('\u{B29}', '\u{B29}'),
+    ('\u{B31}', '\u{B31}'),
+    ('\u{B34}', '\u{B34}'),
+    ('\u{B3A}', '\u{B3B}'),
+    ('\u{B45}', '\u{B46}'),
+    ('\u{B49}', '\u{B4A}'),
+    ('\u{B4E}', '\u{B54}'),
+    ('\u{B58}', '\u{B5B}'),
+    ('\u{B5E}', '\u{B5E}'),
+    ('\u{B64}', '\u{B65}'),
+    ('\u{B78}', '\u{B81}'),
+    ('\u{B84}', '\u{B84}'),
+    ('\u{B8B}', '\u{B8D}'),
+    ('\u{B91}', '\u{B91}'),
+    ('\u{B96}', '\u{B98}'),
+    ('\u{B9B}', '\u{B9B}'),
+    ('\u{B9D}', '\u{B9D}'),
+    ('\u{BA0}', '\u{BA2}'),
+    ('\u{BA5}', '\u{BA7}'),
+    ('\u{BAB}', '\u{BAD}'),
+    ('\u{BBA}', '\u{BBD}'),
+    ('\u{BC3}', '\u{BC5}'),
+    ('\u{BC9}', '\u{BC9}'),
+    ('\u{BCE}', '\u{BCF}'),
+    ('\u{BD1}', '\u{BD6}'),
+    ('\u{BD8}', '\u{BE5}'),
+    ('\u{BFB}', '\u{BFF}'),
+    ('\u{C0D}', '\u{C0D}'),
+    ('\u{C11}', '\u{C11}'),
+    ('\u{C29}', '\u{C29}'),
+    ('\u{C3A}', '\u{C3B}'),
+    ('\u{C45}', '\u{C45}'),
+    ('\u{C49}', '\u{C49}'),
+    ('\u{C4E}', '\u{C54}'),
+    ('\u{C57}', '\u{C57}'),
+    ('\u{C5B}', '\u{C5C}'),
+    ('\u{C5E}', '\u{C5F}'),
+    ('\u{C64}', '\u{C65}'),
+    ('\u{C70}', '\u{C76}'),
+    ('\u{C8D}', '\u{C8D}'),
+    ('\u{C91}', '\u{C91}'),
+    ('\u{CA9}', '\u{CA9}'),
+    ('\u{CB4}', '\u{CB4}'),
+    ('\u{CBA}', '\u{CBB}'),
+    ('\u{CC5}', '\u{CC5}'),
+    ('\u{CC9}', '\u{CC9}'),
+    ('\u{CCE}', '\u{CD4}'),
+    ('\u{CD7}', '\u{CDC}'),
+    ('\u{CDF}', '\u{CDF}'),
+    ('\u{CE4}', '\u{CE5}'),
+    ('\u{CF0}', '\u{CF0}'),
+    ('\u{CF3}', '\u{CFF}'),
+    ('\u{D0D}', '\u{D0D}'),
+    ('\u{D11}', '\u{D11}'),
+    ('\u{D45}', '\u{D45}'),
+    ('\u{D49}', '\u{D49}'),
+    ('\u{D50}', '\u{D53}'),
+    ('\u{D64}', '\u{D65}'),
+    ('\u{D80}', '\u{D80}'),
+    ('\u{D84}', '\u{D84}'),
+    ('\u{D97}', '\u{D99}'),
+    ('\u{DB2}', '\u{DB2}'),
+    ('\u{DBC}', '\u{DBC}'),
+    ('\u{DBE}', '\u{DBF}'),
+    ('\u{DC7}', '\u{DC9}'),
+    ('\u{DCB}', '\u{DCE}'),
+    ('\u{DD5}', '\u{DD5}'),
+    ('\u{DD7}', '\u{DD7}'),
+    ('\u{DE0}', '\u{DE5}'),
+    ('\u{DF0}', '\u{DF1}'),
+    ('\u{DF5}', '\u{E00}'),
+    ('\u{E3B}', '\u{E3E}'),
+    ('\u{E5C}', '\u{E80}'),
+    ('\u{E83}', '\u{E83}'),
+    ('\u{E85}', '\u{E85}'),
+    ('\u{E8B}', '\u{E8B}'),
+    ('\u{EA4}', '\u{EA4}'),
+    ('\u{EA6}', '\u{EA6}'),
+    ('\u{EBE}', '\u{EBF}'),
+    ('\u{EC5}', '\u{EC5}'),
+    ('\u{EC7}', '\u{EC7}'),
+    ('\u{ECE}', '\u{ECF}'),
+    ('\u{EDA}', '\u{EDB}'),
+    ('\u{EE0}', '\u{EFF}'),
+    ('\u{F48}', '\u{F48}'),
+    ('\u{F6D}', '\u{F70}'),
+    ('\u{F98}', '\u{F98}'),
+    ('\u{FBD}', '\u{FBD}'),
+    ('\u{FCD}', '\u{FCD}'),
+    ('\u{FDB}', '\u{FFF}'),
+    ('\u{10C6}', '\u{10C6}'),
+    ('\u{10C8}', '\u{10CC}'),
+    ('\u{10CE}', '\u{10CF}'),
+    ('\u{1249}', '\u{1249}'),
+    ('\u{124E}', '\u{124F}'),
+    ('\u{1257}', '\u{1257}'),
+    ('\u{1259}', '\u{1259}'),
+    ('\u{125E}', '\u{125F}'),
+    ('\u{1289}', '\u{1289}'),
+    ('\u{128E}', '\u{128F}'),
+    ('\u{12B1}', '\u{12B1}'),
+    ('\u{12B6}', '\u{12B7}'),
+    ('\u{12BF}', '\u{12BF}'),
+    ('\u{12C1}', '\u{12C1}'),
+    ('\u{12C6}', '\u{12C7}'),
+    ('\u{12D7}', '\u{12D7}'),
+    ('\u{1311}', '\u{1311}'),
+    ('\u{1316}', '\u{1317}'),
+    ('\u{135B}', '\u{135C}'),
+    ('\u{137D}', '\u{137F}'),
+    ('\u{139A}', '\u{139F}'),
+    ('\u{13F6}', '\u{13F7}'),
+    ('\u{13FE}', '\u{13FF}'),
+    ('\u{169D}', '\u{169F}'),
+    ('\u{16F9}', '\u{16FF}'),
+    ('\u{1716}', '\u{171E}'),
+    ('\u{1737}', '\u{173F}'),
+    ('\u{1754}', '\u{175F}'),
+    ('\u{176D}', '\u{176D}'),
+    ('\u{1771}', '\u{1771}'),
+    ('\u{1774}', '\u{177F}'),
+    ('\u{17DE}', '\u{17DF}'),
+    ('\u{17EA}', '\u{17EF}'),
+    ('\u{17FA}', '\u{17FF}'),
+    ('\u{181A}', '\u{181F}'),
+    ('\u{1879}', '\u{187F}'),
+    ('\u{18AB}', '\u{18AF}'),
+    ('\u{18F6}', '\u{18FF}'),
+    ('\u{191F}', '\u{191F}'),
+    ('\u{192C}', '\u{192F}'),
+    ('\u{193C}', '\u{193F}'),
+    ('\u{1941}', '\u{1943}'),
+    ('\u{196E}', '\u{196F}'),
+    ('\u{1975}', '\u{197F}'),
+    ('\u{19AC}', '\u{19AF}'),
+    ('\u{19CA}', '\u{19CF}'),
+    ('\u{19DB}', '\u{19DD}'),
+    ('\u{1A1C}', '\u{1A1D}'),
+    ('\u{1A5F}', '\u{1A5F}'),
+    ('\u{1A7D}', '\u{1A7E}'),
+    ('\u{1A8A}', '\u{1A8F}'),
+    ('\u{1A9A}', '\u{1A9F}'),
+    ('\u{1AAE}', '\u{1AAF}'),
+    ('\u{1ACF}', '\u{1AFF}'),
+    ('\u{1B4D}', '\u{1B4F}'),
+    ('\u{1B7F}', '\u{1B7F}'),
+    ('\u{1BF4}', '\u{1BFB}'),
+    ('\u{1C38}', '\u{1C3A}'),
+    ('\u{1C4A}', '\u{1C4C}'),
+    ('\u{1C89}', '\u{1C8F}'),
+    ('\u{1CBB}', '\u{1CBC}'),
+    ('\u{1CC8}', '\u{1CCF}'),
+    ('\u{1CFB}', '\u{1CFF}'),
+    ('\u{1F16}', '\u{1F17}'),
+    ('\u{1F1E}', '\u{1F1F}'),
+    ('\u{1F46}', '\u{1F47}'),
+    ('\u{1F4E}', '\u{1F4F}'),
+    ('\u{1F58}', '\u{1F58}'),
+    ('\u{1F5A}', '\u{1F5A}'),
+    ('\u{1F5C}', '\u{1F5C}'),
+    ('\u{1F5E}', '\u{1F5E}'),
+    ('\u{1F7E}', '\u{1F7F}'),
+    ('\u{1FB5}', '\u{1FB5}'),
+    ('\u{1FC5}', '\u{1FC5}'),
+    ('\u{1FD4}', '\u{1FD5}'),
+    ('\u{1FDC}', '\u{1FDC}'),
+    ('\u{1FF0}', '\u{1FF1}'),
+    ('\u{1FF5}', '\u{1FF5}'),
+    ('\u{1FFF}', '\u{1FFF}'),
+    ('\u{2065}', '\u{2065}'),
+    ('\u{2072}', '\u{2073}'),
+    ('\u{208F}', '\u{208F}'),
+    ('\u{209D}', '\u{209F}'),
+    ('\u{20C1}', '\u{20CF}'),
+    ('\u{20F1}', '\u{20FF}'),
+    ('\u{218C}', '\u{218F}'),
+    ('\u{2427}', '\u{243F}'),
+    ('\u{244B}', '\u{245F}'),
+    ('\u{2B74}', '\u{2B75}'),
+    ('\u{2B96}', '\u{2B96}'),
+    ('\u{2CF4}', '\u{2CF8}'),
+    ('\u{2D26}', '\u{2D26}'),
+    ('\u{2D28}', '\u{2D2C}'),
+    ('\u{2D2E}', '\u{2D2F}'),
+    ('\u{2D68}', '\u{2D6E}'),
+    ('\u{2D71}', '\u{2D7E}'),
+    ('\u{2D97}', '\u{2D9F}'),
+    ('\u{2DA7}', '\u{2DA7}'),
+    ('\u{2DAF}', '\u{2DAF}'),
+    ('\u{2DB7}', '\u{2DB7}'),
+    ('\u{2DBF}', '\u{2DBF}'),
+    ('\u{2DC7}', '\u{2DC7}'),
+    ('\u{2DCF}', '\u{2DCF}'),
+    ('\u{2DD7}', '\u{2DD7}'),
+    ('\u{2DDF}', '\u{2DDF}'),
+    ('\u{2E5E}', '\u{2E7F}'),
+    ('\u{2E9A}', '\u{2E9A}'),
+    ('\u{2EF4}', '\u{2EFF}'),
+    ('\u{2FD6}', '\u{2FEF}'),
+    ('\u{2FFC}', '\u{2FFF}'),
+    ('\u{3040}', '\u{3040}'),
+    ('\u{3097}', '\u{3098}'),
+    ('\u{3100}', '\u{3104}'),
+    ('\u{3130}', '\u{3130}'),
+    ('\u{318F}', '\u{318F}'),
+    ('\u{31E4}', '\u{31EF}'),
+    ('\u{321F}', '\u{321F}'),
+    ('\u{A48D}', '\u{A48F}'),
+    ('\u{A4C7}', '\u{A4CF}'),
+    ('\u{A62C}', '\u{A63F}'),
+    ('\u{A6F8}', '\u{A6FF}'),
+    ('\u{A7CB}', '\u{A7CF}'),
+    ('\u{A7D2}', '\u{A7D2}'),
+    ('\u{A7D4}', '\u{A7D4}'),
+    ('\u{A7DA}', '\u{A7F1}'),
+    ('\u{A82D}', '\u{A82F}'),
+    ('\u{A83A}', '\u{A83F}'),
+    ('\u{A878}', '\u{A87F}'),
+    ('\u{A8C6}', '\u{A8CD}'),
+    ('\u{A8DA}', '\u{A8DF}'),
+    ('\u{A954}', '\u{A95E}'),
+    ('\u{A97D}', '\u{A97F}'),
+    ('\u{A9CE}', '\u{A9CE}'),
+    ('\u{A9DA}', '\u{A9DD}'),
+    ('\u{A9FF}', '\u{A9FF}'),
+    ('\u{AA37}', '\u{AA3F}'),
+    ('\u{AA4E}', '\u{AA4F}'),
+    ('\u{AA5A}', '\u{AA5B}'),
+    ('\u{AAC3}', '\u{AADA}'),
+    ('\u{AAF7}', '\u{AB00}'),
+    ('\u{AB07}', '\u{AB08}'),
+    ('\u{AB0F}', '\u{AB10}'),
+    ('\u{AB17}', '\u{AB1F}'),
+    ('\u{AB27}', '\u{AB27}'),
+    ('\u{AB2F}', '\u{AB2F}'),
+    ('\u{AB6C}', '\u{AB6F}'),
+    ('\u{ABEE}', '\u{ABEF}'),
+    ('\u{ABFA}', '\u{ABFF}'),
+    ('\u{D7A4}', '\u{D7AF}'),
+    ('\u{D7C7}', '\u{D7CA}'),
+    ('\u{D7FC}', '\u{D7FF}'),
+    ('\u{E000}', '\u{F8FF}'),
+    ('\u{FA6E}', '\u{FA6F}'),
+    ('\u{FADA}', '\u{FAFF}'),
+    ('\u{FB07}', '\u{FB12}'),
+    ('\u{FB18}', '\u{FB1C}'),
+    ('\u{FB37}', '\u{FB37}'),
+    ('\u{FB3D}', '\u{FB3D}'),
+    ('\u{FB3F}', '\u{FB3F}'),
+    ('\u{FB42}', '\u{FB42}'),
+    ('\u{FB45}', '\u{FB45}'),
+    ('\u{FBC3}', '\u{FBD2}'),
+    ('\u{FD90}', '\u{FD91}'),
+    ('\u{FDC8}', '\u{FDCE}'),
+    ('\u{FDD0}', '\u{FDEF}'),
+    ('\u{FE1A}', '\u{FE1F}'),
+    ('\u{FE53}', '\u{FE53}'),
+    ('\u{FE67}', '\u{FE67}'),
+    ('\u{FE6C}', '\u{FE6F}'),
+    ('\u{FE75}', '\u{FE75}'),
+    ('\u{FEFD}', '\u{FEFE}'),
+    ('\u{FF00}', '\u{FF00}'),
+    ('\u{FFBF}', '\u{FFC1}'),
+    ('\u{FFC8}', '\u{FFC9}'),
+    ('\u{FFD0}', '\u{FFD1}'),
+    ('\u{FFD8}', '\u{FFD9}'),
+    ('\u{FFDD}', '\u{FFDF}'),
+    ('\u{FFE7}', '\u{FFE7}'),
+    ('\u{FFEF}', '\u{FFF8}'),
+    ('\u{FFFE}', '\u{FFFF}'),
+    ('\u{1000C}', '\u{1000C}'),
+    ('\u{10027}', '\u{10027}'),
+    ('\u{1003B}', '\u{1003B}'),
+    ('\u{1003E}', '\u{1003E}'),
+    ('\u{1004E}', '\u{1004F}'),
+    ('\u{1005E}', '\u{1007F}'),
+    ('\u{100FB}', '\u{100FF}'),
+    ('\u{10103}', '\u{10106}'),
+    ('\u{10134}', '\u{10136}'),
+    ('\u{1018F}', '\u{1018F}'),
+    ('\u{1019D}', '\u{1019F}'),
+    ('\u{101A1}', '\u{101CF}'),
+    ('\u{101FE}', '\u{1027F}'),
+    ('\u{1029D}', '\u{1029F}'),
+    ('\u{102D1}', '\u{102DF}'),
+    ('\u{102FC}', '\u{102FF}'),
+    ('\u{10324}', '\u{1032C}'),
+    ('\u{1034B}', '\u{1034F}'),
+    ('\u{1037B}', '\u{1037F}'),
+    ('\u{1039E}', '\u{1039E}'),
+    ('\u{103C4}', '\u{103C7}'),
+    ('\u{103D6}', '\u{103FF}'),
+    ('\u{1049E}', '\u{1049F}'),
+    ('\u{104AA}', '\u{104AF}'),
+    ('\u{104D4}', '\u{104D7}'),
+    ('\u{104FC}', '\u{104FF}'),
+    ('\u{10528}', '\u{1052F}'),
+    ('\u{10564}', '\u{1056E}'),
+    ('\u{1057B}', '\u{1057B}'),
+    ('\u{1058B}', '\u{1058B}'),
+    ('\u{10593}', '\u{10593}'),
+    ('\u{10596}', '\u{10596}'),
+    ('\u{105A2}', '\u{105A2}'),
+    ('\u{105B2}', '\u{105B2}'),
+    ('\u{105BA}', '\u{105BA}'),
+    ('\u{105BD}', '\u{105FF}'),
+    ('\u{10737}', '\u{1073F}'),
+    ('\u{10756}', '\u{1075F}'),
+    ('\u{10768}', '\u{1077F}'),
+    ('\u{10786}', '\u{10786}'),
+    ('\u{107B1}', '\u{107B1}'),
+    ('\u{107BB}', '\u{107FF}'),
+    ('\u{10806}', '\u{10807}'),
+    ('\u{10809}', '\u{10809}'),
+    ('\u{10836}', '\u{10836}'),
+    ('\u{10839}', '\u{1083B}'),
+    ('\u{1083D}', '\u{1083E}'),
+    ('\u{10856}', '\u{10856}'),
+    ('\u{1089F}', '\u{108A6}'),
+    ('\u{108B0}', '\u{108DF}'),
+    ('\u{108F3}', '\u{108F3}'),
+    ('\u{108F6}', '\u{108FA}'),
+    ('\u{1091C}', '\u{1091E}'),
+    ('\u{1093A}', '\u{1093E}'),
+    ('\u{10940}', '\u{1097F}'),
+    ('\u{109B8}', '\u{109BB}'),
+    ('\u{109D0}', '\u{109D1}'),
+    ('\u{10A04}', '\u{10A04}'),
+    ('\u{10A07}', '\u{10A0B}'),
+    ('\u{10A14}', '\u{10A14}'),
+    ('\u{10A18}', '\u{10A18}'),
+    ('\u{10A36}', '\u{10A37}'),
+    ('\u{10A3B}', '\u{10A3E}'),
+    ('\u{10A49}', '\u{10A4F}'),
+    ('\u{10A59}', '\u{10A5F}'),
+    ('\u{10AA0}', '\u{10ABF}'),
+    ('\u{10AE7}', '\u{10AEA}'),
+    ('\u{10AF7}', '\u{10AFF}'),
+    ('\u{10B36}', '\u{10B38}'),
+    ('\u{10B56}', '\u{10B57}'),
+    ('\u{10B73}', '\u{10B77}'),
+    ('\u{10B92}', '\u{10B98}'),
+    ('\u{10B9D}', '\u{10BA8}'),
+    ('\u{10BB0}', '\u{10BFF}'),
+    ('\u{10C49}', '\u{10C7F}'),
+    ('\u{10CB3}', '\u{10CBF}'),
+    ('\u{10CF3}', '\u{10CF9}'),
+    ('\u{10D28}', '\u{10D2F}'),
+    ('\u{10D3A}', '\u{10E5F}'),
+    ('\u{10E7F}', '\u{10E7F}'),
+    ('\u{10EAA}', '\u{10EAA}'),
+    ('\u{10EAE}', '\u{10EAF}'),
+    ('\u{10EB2}', '\u{10EFF}'),
+    ('\u{10F28}', '\u{10F2F}'),
+    ('\u{10F5A}', '\u{10F6F}'),
+    ('\u{10F8A}', '\u{10FAF}'),
+    ('\u{10FCC}', '\u{10FDF}'),
+    ('\u{10FF7}', '\u{10FFF}'),
+    ('\u{1104E}', '\u{11051}'),
+    ('\u{11076}', '\u{1107E}'),
+    ('\u{110C3}', '\u{110CC}'),
+    ('\u{110CE}', '\u{110CF}'),
+    ('\u{110E9}', '\u{110EF}'),
+    ('\u{110FA}', '\u{110FF}'),
+    ('\u{11135}', '\u{11135}'),
+    ('\u{11148}', '\u{1114F}'),
+    ('\u{11177}', '\u{1117F}'),
+    ('\u{111E0}', '\u{111E0}'),
+    ('\u{111F5}', '\u{111FF}'),
+    ('\u{11212}', '\u{11212}'),
+    ('\u{1123F}', '\u{1127F}'),
+    ('\u{11287}', '\u{11287}'),
+    ('\u{11289}', '\u{11289}'),
+    ('\u{1128E}', '\u{1128E}'),
+    ('\u{1129E}', '\u{1129E}'),
+    ('\u{112AA}', '\u{112AF}'),
+    ('\u{112EB}', '\u{112EF}'),
+    ('\u{112FA}', '\u{112FF}'),
+    ('\u{11304}', '\u{11304}'),
+    ('\u{1130D}', '\u{1130E}'),
+    ('\u{11311}', '\u{11312}'),
+    ('\u{11329}', '\u{11329}'),
+    ('\u{11331}', '\u{11331}'),
+    ('\u{11334}', '\u{11334}'),
+    ('\u{1133A}', '\u{1133A}'),
+    ('\u{11345}', '\u{11346}'),
+    ('\u{11349}', '\u{1134A}'),
+    ('\u{1134E}', '\u{1134F}'),
+    ('\u{11351}', '\u{11356}'),
+    ('\u{11358}', '\u{1135C}'),
+    ('\u{11364}', '\u{11365}'),
+    ('\u{1136D}', '\u{1136F}'),
+    ('\u{11375}', '\u{113FF}'),
+    ('\u{1145C}', '\u{1145C}'),
+    ('\u{11462}', '\u{1147F}'),
+    ('\u{114C8}', '\u{114CF}'),
+    ('\u{114DA}', '\u{1157F}'),
+    ('\u{115B6}', '\u{115B7}'),
+    ('\u{115DE}', '\u{115FF}'),
+    ('\u{11645}', '\u{1164F}'),
+    ('\u{1165A}', '\u{1165F}'),
+    ('\u{1166D}', '\u{1167F}'),
+    ('\u{116BA}', '\u{116BF}'),
+    ('\u{116CA}', '\u{116FF}'),
+    ('\u{1171B}', '\u{1171C}'),
+    ('\u{1172C}', '\u{1172F}'),
+    ('\u{11747}', '\u{117FF}'),
+    ('\u{1183C}', '\u{1189F}'),
+    ('\u{118F3}', '\u{118FE}'),
+    ('\u{11907}', '\u{11908}'),
+    ('\u{1190A}', '\u{1190B}'),
+    ('\u{11914}', '\u{11914}'),
+    ('\u{11917}', '\u{11917}'),
+    ('\u{11936}', '\u{11936}'),
+    ('\u{11939}', '\u{1193A}'),
+    ('\u{11947}', '\u{1194F}'),
+    ('\u{1195A}', '\u{1199F}'),
+    ('\u{119A8}', '\u{119A9}'),
+    ('\u{119D8}', '\u{119D9}'),
+    ('\u{119E5}', '\u{119FF}'),
+    ('\u{11A48}', '\u{11A4F}'),
+    ('\u{11AA3}', '\u{11AAF}'),
+    ('\u{11AF9}', '\u{11BFF}'),
+    ('\u{11C09}', '\u{11C09}'),
+    ('\u{11C37}', '\u{11C37}'),
+    ('\u{11C46}', '\u{11C4F}'),
+    ('\u{11C6D}', '\u{11C6F}'),
+    ('\u{11C90}', '\u{11C91}'),
+    ('\u{11CA8}', '\u{11CA8}'),
+    ('\u{11CB7}', '\u{11CFF}'),
+    ('\u{11D07}', '\u{11D07}'),
+    ('\u{11D0A}', '\u{11D0A}'),
+    ('\u{11D37}', '\u{11D39}'),
+    ('\u{11D3B}', '\u{11D3B}'),
+    ('\u{11D3E}', '\u{11D3E}'),
+    ('\u{11D48}', '\u{11D4F}'),
+    ('\u{11D5A}', '\u{11D5F}'),
+    ('\u{11D66}', '\u{11D66}'),
+    ('\u{11D69}', '\u{11D69}'),
+    ('\u{11D8F}', '\u{11D8F}'),
+    ('\u{11D92}', '\u{11D92}'),
+    ('\u{11D99}', '\u{11D9F}'),
+    ('\u{11DAA}', '\u{11EDF}'),
+    ('\u{11EF9}', '\u{11FAF}'),
+    ('\u{11FB1}', '\u{11FBF}'),
+    ('\u{11FF2}', '\u{11FFE}'),
+    ('\u{1239A}', '\u{123FF}'),
+    ('\u{1246F}', '\u{1246F}'),
+    ('\u{12475}', '\u{1247F}'),
+    ('\u{12544}', '\u{12F8F}'),
+    ('\u{12FF3}', '\u{12FFF}'),
+    ('\u{1342F}', '\u{1342F}'),
+    ('\u{13439}', '\u{143FF}'),
+    ('\u{14647}', '\u{167FF}'),
+    ('\u{16A39}', '\u{16A3F}'),
+    ('\u{16A5F}', '\u{16A5F}'),
+    ('\u{16A6A}', '\u{16A6D}'),
+    ('\u{16ABF}', '\u{16ABF}'),
+    ('\u{16ACA}', '\u{16ACF}'),
+    ('\u{16AEE}', '\u{16AEF}'),
+    ('\u{16AF6}', '\u{16AFF}'),
+    ('\u{16B46}', '\u{16B4F}'),
+    ('\u{16B5A}', '\u{16B5A}'),
+    ('\u{16B62}', '\u{16B62}'),
+    ('\u{16B78}', '\u{16B7C}'),
+    ('\u{16B90}', '\u{16E3F}'),
+    ('\u{16E9B}', '\u{16EFF}'),
+    ('\u{16F4B}', '\u{16F4E}'),
+    ('\u{16F88}', '\u{16F8E}'),
+    ('\u{16FA0}', '\u{16FDF}'),
+    ('\u{16FE5}', '\u{16FEF}'),
+    ('\u{16FF2}', '\u{16FFF}'),
+    ('\u{187F8}', '\u{187FF}'),
+    ('\u{18CD6}', '\u{18CFF}'),
+    ('\u{18D09}', '\u{1AFEF}'),
+    ('\u{1AFF4}', '\u{1AFF4}'),
+    ('\u{1AFFC}', '\u{1AFFC}'),
+    ('\u{1AFFF}', '\u{1AFFF}'),
+    ('\u{1B123}', '\u{1B14F}'),
+    ('\u{1B153}', '\u{1B163}'),
+    ('\u{1B168}', '\u{1B16F}'),
+    ('\u{1B2FC}', '\u{1BBFF}'),
+    ('\u{1BC6B}', '\u{1BC6F}'),
+    ('\u{1BC7D}', '\u{1BC7F}'),
+    ('\u{1BC89}', '\u{1BC8F}'),
+    ('\u{1BC9A}', '\u{1BC9B}'),
+    ('\u{1BCA4}', '\u{1CEFF}'),
+    ('\u{1CF2E}', '\u{1CF2F}'),
+    ('\u{1CF47}', '\u{1CF4F}'),
+    ('\u{1CFC4}', '\u{1CFFF}'),
+    ('\u{1D0F6}', '\u{1D0FF}'),
+    ('\u{1D127}', '\u{1D128}'),
+    ('\u{1D1EB}', '\u{1D1FF}'),
+    ('\u{1D246}', '\u{1D2DF}'),
+    ('\u{1D2F4}', '\u{1D2FF}'),
+    ('\u{1D357}', '\u{1D35F}'),
+    ('\u{1D379}', '\u{1D3FF}'),
+    ('\u{1D455}', '\u{1D455}'),
+    ('\u{1D49D}', '\u{1D49D}'),
+    ('\u{1D4A0}', '\u{1D4A1}'),
+    ('\u{1D4A3}', '\u{1D4A4}'),
+    ('\u{1D4A7}', '\u{1D4A8}'),
+    ('\u{1D4AD}', '\u{1D4AD}'),
+    ('\u{1D4BA}', '\u{1D4BA}'),
+    ('\u{1D4BC}', '\u{1D4BC}'),
+    ('\u{1D4C4}', '\u{1D4C4}'),
+    ('\u{1D506}', '\u{1D506}'),
+    ('\u{1D50B}', '\u{1D50C}'),
+    ('\u{1D515}', '\u{1D515}'),
+    ('\u{1D51D}', '\u{1D51D}'),
+    ('\u{1D53A}', '\u{1D53A}'),
+    ('\u{1D53F}', '\u{1D53F}'),
+    ('\u{1D545}', '\u{1D545}'),
+    ('\u{1D547}', '\u{1D549}'),
+    ('\u{1D551}', '\u{1D551}'),
+    ('\u{1D6A6}', '\u{1D6A7}'),
+    ('\u{1D7CC}', '\u{1D7CD}'),
+    ('\u{1DA8C}', '\u{1DA9A}'),
+    ('\u{1DAA0}', '\u{1DAA0}'),
+    ('\u{1DAB0}', '\u{1DEFF}'),
+    ('\u{1DF1F}', '\u{1DFFF}'),
+    ('\u{1E007}', '\u{1E007}'),
+    ('\u{1E019}', '\u{1E01A}'),
+    ('\u{1E022}', '\u{1E022}'),
+    ('\u{1E025}', '\u{1E025}'),
+    ('\u{1E02B}', '\u{1E0FF}'),
+    ('\u{1E12D}', '\u{1E12F}'),
+    ('\u{1E13E}', '\u{1E13F}'),
+    ('\u{1E14A}', '\u{1E14D}'),
+    ('\u{1E150}', '\u{1E28F}'),
+    ('\u{1E2AF}', '\u{1E2BF}'),
+    ('\u{1E2FA}', '\u{1E2FE}'),
+    ('\u{1E300}', '\u{1E7DF}'),
+    ('\u{1E7E7}', '\u{1E7E7}'),
+    ('\u{1E7EC}', '\u{1E7EC}'),
+    ('\u{1E7EF}', '\u{1E7EF}'),
+    ('\u{1E7FF}', '\u{1E7FF}'),
+    ('\u{1E8C5}', '\u{1E8C6}'),
+    ('\u{1E8D7}', '\u{1E8FF}'),
+    ('\u{1E94C}', '\u{1E94F}'),
+    ('\u{1E95A}', '\u{1E95D}'),
+    ('\u{1E960}', '\u{1EC70}'),
+    ('\u{1ECB5}', '\u{1ED00}'),
+    ('\u{1ED3E}', '\u{1EDFF}'),
+    ('\u{1EE04}', '\u{1EE04}'),
+    ('\u{1EE20}', '\u{1EE20}'),
+    ('\u{1EE23}', '\u{1EE23}'),
+    ('\u{1EE25}', '\u{1EE26}'),
+    ('\u{1EE28}', '\u{1EE28}'),
+    ('\u{1EE33}', '\u{1EE33}'),
+    ('\u{1EE38}', '\u{1EE38}'),
+    ('\u{1EE3A}', '\u{1EE3A}'),
+    ('\u{1EE3C}', '\u{1EE41}'),
+    ('\u{1EE43}', '\u{1EE46}'),
+    ('\u{1EE48}', '\u{1EE48}'),
+    ('\u{1EE4A}', '\u{1EE4A}'),
+    ('\u{1EE4C}', '\u{1EE4C}'),
+    ('\u{1EE50}', '\u{1EE50}'),
+    ('\u{1EE53}', '\u{1EE53}'),
+    ('\u{1EE55}', '\u{1EE56}'),
+    ('\u{1EE58}', '\u{1EE58}'),
+    ('\u{1EE5A}', '\u{1EE5A}'),
+    ('\u{1EE5C}', '\u{1EE5C}'),
+    ('\u{1EE5E}', '\u{1EE5E}'),
+    ('\u{1EE60}', '\u{1EE60}'),
+    ('\u{1EE63}', '\u{1EE63}'),
+    ('\u{1EE65}', '\u{1EE66}'),
+    ('\u{1EE6B}', '\u{1EE6B}'),
+    ('\u{1EE73}', '\u{1EE73}'),
+    ('\u{1EE78}', '\u{1EE78}'),
+    ('\u{1EE7D}', '\u{1EE7D}'),
+    ('\u{1EE7F}', '\u{1EE7F}'),
+    ('\u{1EE8A}', '\u{1EE8A}'),
+    ('\u{1EE9C}', '\u{1EEA0}'),
+    ('\u{1EEA4}', '\u{1EEA4}'),
+    ('\u{1EEAA}', '\u{1EEAA}'),
+    ('\u{1EEBC}', '\u{1EEEF}'),
+    ('\u{1EEF2}', '\u{1EFFF}'),
+    ('\u{1F02C}', '\u{1F02F}'),
+    ('\u{1F094}', '\u{1F09F}'),
+    ('\u{1F0AF}', '\u{1F0B0}'),
+    ('\u{1F0C0}', '\u{1F0C0}'),
+    ('\u{1F0D0}', '\u{1F0D0}'),
+    ('\u{1F0F6}', '\u{1F0FF}'),
+    ('\u{1F1AE}', '\u{1F1E5}'),
+    ('\u{1F203}', '\u{1F20F}'),
+    ('\u{1F23C}', '\u{1F23F}'),
+    ('\u{1F249}', '\u{1F24F}'),
+    ('\u{1F252}', '\u{1F25F}'),
+    ('\u{1F266}', '\u{1F2FF}'),
+    ('\u{1F6D8}', '\u{1F6DC}'),
+    ('\u{1F6ED}', '\u{1F6EF}'),
+    ('\u{1F6FD}', '\u{1F6FF}'),
+    ('\u{1F774}', '\u{1F77F}'),
+    ('\u{1F7D9}', '\u{1F7DF}'),
+    ('\u{1F7EC}', '\u{1F7EF}'),
+    ('\u{1F7F1}', '\u{1F7FF}'),
+    ('\u{1F80C}', '\u{1F80F}'),
+    ('\u{1F848}', '\u{1F84F}'),
+    ('\u{1F85A}', '\u{1F85F}'),
+    ('\u{1F888}', '\u{1F88F}'),
+    ('\u{1F8AE}', '\u{1F8AF}'),
+    ('\u{1F8B2}', '\u{1F8FF}'),
+    ('\u{1FA54}', '\u{1FA5F}'),
+    ('\u{1FA6E}', '\u{1FA6F}'),
+    ('\u{1FA75}', '\u{1FA77}'),
+    ('\u{1FA7D}', '\u{1FA7F}'),
+    ('\u{1FA87}', '\u{1FA8F}'),
+    ('\u{1FAAD}', '\u{1FAAF}'),
+    ('\u{1FABB}', '\u{1FABF}'),
+    ('\u{1FAC6}', '\u{1FACF}'),
+    ('\u{1FADA}', '\u{1FADF}'),
+    ('\u{1FAE8}', '\u{1FAEF}'),
+    ('\u{1FAF7}', '\u{1FAFF}'),
+    ('\u{1FB93}', '\u{1FB93}'),
+    ('\u{1FBCB}', '\u{1FBEF}'),
+    ('\u{1FBFA}', '\u{1FFFF}'),
+    ('\u{2A6E0}', '\u{2A6FF}'),
+    ('\u{2B739}', '\u{2B73F}'),
+    ('\u{2B81E}', '\u{2B81F}'),
+    ('\u{2CEA2}', '\u{2CEAF}'),
+    ('\u{2EBE1}', '\u{2F7FF}'),
+    ('\u{2FA1E}', '\u{2FFFF}'),
+    ('\u{3134B}', '\u{E0000}'),
+    ('\u{E0002}', '\u{E001F}'),
+    ('\u{E0080}', '\u{E00FF}'),
+    ('\u{E01F0}', '\u{10FFFF}'),
+];